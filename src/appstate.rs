@@ -1,104 +1,316 @@
 use actix::Addr;
-use surrealdb::Surreal;
-use surrealdb::engine::remote::ws::Client;
-use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+use actix_web::cookie::Key;
+use actix_session::Session;
+use actix_web::web;
+use chrono::Utc;
+use futures_util::lock::Mutex as AsyncMutex;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::Duration;
+use uuid::Uuid;
 use validator::Validate;
-use crate::structs::{Room, UserData, LoginForm};
+use crate::config::Config;
+use crate::email::EmailSender;
+use crate::login_guard::LoginGuard;
+use crate::idempotency::SentMessages;
+use crate::resume::ResumeLog;
+use crate::rate_limit::RateLimiter;
+use crate::structs::{AttachmentMeta, LoginForm, LoginLockout, UserData};
 use crate::message_structs::*;
-use crate::websocket::{WsActor, WsMessage};
+use crate::presence::PresenceTracker;
+use crate::store::{ChatStore, HistoryCursor};
+use crate::uploads::{StoredBlob, UploadStore};
+use crate::websocket::{Disconnect, WsActor, WsMessage};
+
+// How stale a session's last_seen may get before a request updates it
+const SESSION_TOUCH_INTERVAL_SECS: u64 = 60;
+// Checked against when no account matches a login, so the attempt takes as long as a real one
+static DUMMY_PASSWORD_HASH: LazyLock<String> = LazyLock::new(|| bcrypt::hash("not a real password", bcrypt::DEFAULT_COST).unwrap());
 
 pub type WsActorMap = HashMap<String, Addr<WsActor>>;
 pub struct AppState {
-    pub db: Arc<Surreal<Client>>,
-    pub channels: Arc<Mutex<HashMap<String, Room>>>,
+    pub store: Arc<dyn ChatStore>,
     pub actor_registry: Arc<Mutex<HashMap<String, WsActorMap>>>,
     pub main_room_id: String,
+    pub presence: PresenceTracker,
+    pub uploads: UploadStore,
+    // Held while attachments are added or deleted, so a blob is not removed as an
+    // upload of the same content starts using it, and concurrent uploads cannot
+    // both fit in the same remaining quota
+    pub attachment_lock: AsyncMutex<()>,
+    pub email: Arc<dyn EmailSender>,
+    pub config: Config,
+    // Signs links that are checked without a stored token
+    pub signing_key: Key,
+    // When each user was last sent a verification email
+    pub verification_sent: Mutex<HashMap<String, u64>>,
+    pub login_guard: LoginGuard,
+    pub rate_limiter: RateLimiter,
+    pub sent_messages: SentMessages,
+    pub resume: ResumeLog,
 }
 
 impl AppState {
     pub async fn broadcast_message(&self, message: String, room_id: String, user_id: String) {
-        let query = "SELECT * FROM rooms WHERE room_id = $room_id;";
-        let mut response = match self.db.query(query)
-            .bind(("room_id", room_id))
-            .await {
-                Ok(retrieved) => retrieved,
-                Err(e) => {log::error!("Failed to get users in requested room: fn broadcast_message, error: {:?}", e);
-                return}
-            };
-        let rooms: Vec<Room> = match response.take(0) {
-            Ok(user) => user,
-            Err(e) => {log::error!("Failed to get user data: fn broadcast_message, error: {:?}", e);
+        self.send_to_room(message, &room_id, Some(&user_id), false, true).await;
+    }
+
+    // Chat messages are replayed from the store by seq when a connection resumes, so unlike
+    // broadcast_message this does not record the frame for resuming
+    pub async fn broadcast_chat_message(&self, message: String, room_id: String, user_id: String) {
+        self.send_to_room(message, &room_id, Some(&user_id), false, false).await;
+    }
+
+    // Like broadcast_message, but skips every connection of user_id and is not replayed on resume
+    pub async fn broadcast_to_others(&self, message: String, room_id: String, user_id: String) {
+        self.send_to_room(message, &room_id, Some(&user_id), true, false).await;
+    }
+
+    // For events raised by the server itself, so there is no sender whose membership to check
+    pub async fn broadcast_to_room(&self, message: String, room_id: String) {
+        self.send_to_room(message, &room_id, None, false, true).await;
+    }
+
+    // Sends an event about room_id to every connection of one user, e.g. one no longer in the room
+    pub fn send_room_event(&self, message: &str, user_id: &str, room_id: &str) {
+        let actor_registry = self.actor_registry.lock().unwrap();
+        self.resume.record(user_id, room_id, message);
+        if let Some(client) = actor_registry.get(user_id) {
+            for instance in client.values() {
+                instance.do_send(WsMessage(message.to_string()));
+            }
+        }
+    }
+
+    // Sends to every user sharing at least one room with user_id, including user_id's own connections
+    pub async fn broadcast_to_contacts(&self, message: String, user_id: &str) {
+        let user = match self.store.get_user(user_id).await {
+            Ok(Some(user)) => user,
+            Ok(None) => return,
+            Err(e) => {log::error!("Failed to get user data: fn broadcast_to_contacts, error: {:?}", e);
             return}
         };
+        let mut contacts = HashSet::new();
+        contacts.insert(user.user_id);
+        for room_id in &user.rooms {
+            match self.store.get_room(room_id).await {
+                Ok(Some(room)) => contacts.extend(room.users),
+                Ok(None) => {}
+                Err(e) => log::error!("Failed to get room: fn broadcast_to_contacts, error: {:?}", e),
+            }
+        }
         let actor_registry = self.actor_registry.lock().unwrap();
+        for contact in &contacts {
+            if let Some(client) = actor_registry.get(contact) {
+                for instance in client.values() {
+                    instance.do_send(WsMessage(message.clone()));
+                }
+            }
+        }
+    }
 
-        for room in rooms {
-            if room.users.get(&user_id).is_some() {
-                for user in &room.users {
-                    if let Some(client) = actor_registry.get(user) {
-                        for instance in client.values() {
-                            instance.do_send(WsMessage(message.clone()));
-                        }
-                    }
+    async fn send_to_room(&self, message: String, room_id: &str, sender: Option<&str>, skip_sender: bool, record: bool) {
+        let room = match self.store.get_room(room_id).await {
+            Ok(Some(room)) => room,
+            Ok(None) => return,
+            Err(e) => {log::error!("Failed to get users in requested room: fn send_to_room, error: {:?}", e);
+            return}
+        };
+        if sender.is_some_and(|sender| !room.users.contains(sender)) {
+            return;
+        }
+        let actor_registry = self.actor_registry.lock().unwrap();
+        for user in room.users.iter().filter(|user| !(skip_sender && sender == Some(user.as_str()))) {
+            if record {
+                self.resume.record(user, room_id, &message);
+            }
+            if let Some(client) = actor_registry.get(user) {
+                for instance in client.values() {
+                    instance.do_send(WsMessage(message.clone()));
                 }
-            } else {
-                return;
             }
         }
     }
 
-    pub async fn catch_up(&self, room_id: &str) -> Option<Vec<UserMessage>> {
-        let query = "SELECT * FROM messages WHERE room_id = $room_id ORDER BY timestamp ASC;";
-        let mut response = match self.db.query(query).bind(("room_id", room_id))
-            .await {
-                Ok(queried) => queried,
-                Err(e) => {log::error!("Failed to query messages: fn catch_up, error: {:?}", e);
-                return None}
-            };
-        let basic_messages: Vec<BasicMessage> = match response.take(0)
-            {
-                Ok(retrieved) => retrieved,
-                Err(e) => {log::error!("Failed to get messages from query: fn catch_up, error: {:?}", e);
-                return None}
-            };
-        let user_messages: Vec<UserMessage> = basic_messages.into_iter().map(UserMessage::Basic).collect();
-        Some(user_messages)
-    }
-
-    pub async fn authenticate_user(&self, login_data: &LoginForm) -> Option<String> {
-        let query = "SELECT * FROM users WHERE login_username = $login_username;";
-        let mut response = match self.db
-            .query(query)
-            .bind(("login_username", login_data.username.clone()))
-            .await {
-                Ok(queried) => queried,
-                Err(e) => {log::error!("Failed to query for user: fn authenticate_user, error: {:?}", e);
-                return None}
-            };
-        let result: Option<UserData> = match response.take(0)
-            {
-                Ok(user) => user,
-                Err(e) => {log::error!("Failed to get user data: fn authenticate_user, error: {:?}", e);
-                return None}
+    // The user a session is logged in as. Sessions that were revoked, went
+    // unused for session.ttl_secs, or started before the user's session_epoch
+    // last changed are purged.
+    pub async fn session_user(&self, session: &Session) -> Option<UserData> {
+        let user_id = session.get::<String>("key").ok()??;
+        let epoch = session.get::<u64>("epoch").ok().flatten().unwrap_or(0);
+        let session_id = session.get::<String>("session_id").ok().flatten().unwrap_or_default();
+        let now = Utc::now().timestamp() as u64;
+        let record = match self.store.get_session(&session_id).await {
+            Ok(Some(record)) if record.user_id == user_id && record.last_seen + self.config.session.ttl_secs > now => record,
+            Ok(_) => {
+                session.purge();
+                return None;
+            }
+            Err(e) => {log::error!("Failed to get session: fn session_user, error: {:?}", e);
+            return None}
+        };
+        let user = match self.store.get_user(&user_id).await {
+            Ok(Some(user)) if user.session_epoch == epoch => user,
+            Ok(_) => {
+                session.purge();
+                return None;
+            }
+            Err(e) => {log::error!("Failed to get user data: fn session_user, error: {:?}", e);
+            return None}
+        };
+        if record.last_seen + SESSION_TOUCH_INTERVAL_SECS <= now {
+            if let Err(e) = self.store.touch_session(&session_id, now).await {
+                log::error!("Failed to update session: fn session_user, error: {:?}", e);
+            }
+            // Changing the cookie session also pushes back its expiry in Redis
+            let _ = session.insert("last_seen", now);
+        }
+        Some(user)
+    }
+
+    // Logs out one session of user_id, closing its websockets
+    pub async fn revoke_session(&self, user_id: &str, session_id: &str) -> anyhow::Result<()> {
+        self.store.delete_session(session_id).await?;
+        self.disconnect_user(user_id, Disconnect::Session(session_id.to_string()));
+        Ok(())
+    }
+
+    // Logs out every session of user_id except keep
+    pub async fn revoke_other_sessions(&self, user_id: &str, keep: &str) -> anyhow::Result<()> {
+        self.store.delete_user_sessions(user_id, Some(keep)).await?;
+        self.disconnect_user(user_id, Disconnect::AllExcept(keep.to_string()));
+        Ok(())
+    }
+
+    pub async fn expire_sessions(&self) {
+        let last_seen_before = (Utc::now().timestamp() as u64).saturating_sub(self.config.session.ttl_secs);
+        if let Err(e) = self.store.delete_idle_sessions(last_seen_before).await {
+            log::error!("Failed to delete idle sessions: fn expire_sessions, error: {:?}", e);
+        }
+    }
+
+    // Unverified users stay in the main room while verification is required
+    pub fn can_join_rooms(&self, user: &UserData) -> bool {
+        user.email_verified || !self.config.auth.require_email_verification
+    }
+
+    pub fn is_admin(&self, user: &UserData) -> bool {
+        self.config.auth.admins.iter().any(|admin| admin.eq_ignore_ascii_case(&user.login_username))
+    }
+
+    // Counts a failed login, recording any lockout it starts
+    pub async fn record_login_failure(&self, login: &str, ip: &str) {
+        let now = Utc::now().timestamp() as u64;
+        for lockout in self.login_guard.record_failure(login, ip, now) {
+            log::warn!("Locked out {:?} {} after {} failed logins", lockout.kind, lockout.target, lockout.failures);
+            let record = LoginLockout {
+                lockout_id: Uuid::new_v4().to_string().replace('-', ""),
+                kind: lockout.kind,
+                target: lockout.target,
+                failures: lockout.failures,
+                locked_at: now,
+                expires_at: lockout.expires_at,
+                lifted_by: None,
             };
+            if let Err(e) = self.store.create_lockout(record).await {
+                log::error!("Failed to record lockout: fn record_login_failure, error: {:?}", e);
+            }
+        }
+    }
+
+    // Closes the live websockets of user_id that which applies to
+    pub fn disconnect_user(&self, user_id: &str, which: Disconnect) {
+        let actor_registry = self.actor_registry.lock().unwrap();
+        if let Some(client) = actor_registry.get(user_id) {
+            for instance in client.values() {
+                instance.do_send(which.clone());
+            }
+        }
+    }
+
+    pub async fn history_page(&self, room_id: &str, cursor: HistoryCursor, limit: usize) -> Option<HistoryMessage> {
+        let page = match self.store.message_page(room_id, cursor, limit).await {
+            Ok(retrieved) => retrieved,
+            Err(e) => {log::error!("Failed to get messages: fn history_page, error: {:?}", e);
+            return None}
+        };
+        let next_cursor = page.next_cursor.map(|cursor| cursor.to_string());
+        Some(HistoryMessage::new(room_id.to_string(), page.messages, next_cursor))
+    }
+
+    // Bytes user_id may still upload before reaching their own quota, and before room_id reaches its quota
+    pub async fn upload_quota_left(&self, user_id: &str, room_id: &str) -> anyhow::Result<(u64, u64)> {
+        let user_usage = self.store.user_attachment_usage(user_id).await?;
+        let room_usage = self.store.room_attachment_usage(room_id).await?;
+        Ok((self.uploads.user_quota.saturating_sub(user_usage), self.uploads.room_quota.saturating_sub(room_usage)))
+    }
+
+    // Keeps the uploaded blob and stores its attachment, unless that takes the
+    // uploader or the room past its quota, in which case false is returned
+    pub async fn add_attachment(&self, attachment: &AttachmentMeta, blob: StoredBlob) -> anyhow::Result<bool> {
+        let _guard = self.attachment_lock.lock().await;
+        let (user_left, room_left) = self.upload_quota_left(&attachment.uploader_id, &attachment.room_id).await?;
+        if attachment.size > user_left.min(room_left) {
+            // Deletes the uploaded file
+            web::block(move || drop(blob)).await?;
+            return Ok(false);
+        }
+        web::block(move || blob.keep()).await??;
+        self.store.create_attachment(attachment.clone()).await?;
+        Ok(true)
+    }
+
+    // Removes the attachment, and its blob on disk once no other upload shares the content
+    pub async fn delete_attachment(&self, attachment: &AttachmentMeta) -> anyhow::Result<()> {
+        let _guard = self.attachment_lock.lock().await;
+        self.store.delete_attachment(&attachment.attachment_id).await?;
+        if !self.store.blob_in_use(&attachment.sha256).await? {
+            let uploads = self.uploads.clone();
+            let sha256 = attachment.sha256.clone();
+            web::block(move || uploads.remove_blob(&sha256)).await??;
+        }
+        Ok(())
+    }
+
+    // Deletes attachments older than grace that no message refers to
+    pub async fn sweep_attachments(&self, grace: Duration) {
+        let created_before = (Utc::now().timestamp() as u64).saturating_sub(grace.as_secs());
+        let attachments = match self.store.unreferenced_attachments(created_before).await {
+            Ok(found) => found,
+            Err(e) => {log::error!("Failed to find unreferenced attachments: fn sweep_attachments, error: {:?}", e);
+            return}
+        };
+        for attachment in &attachments {
+            if let Err(e) = self.delete_attachment(attachment).await {
+                log::error!("Failed to delete attachment: fn sweep_attachments, error: {:?}", e);
+            }
+        }
+        if !attachments.is_empty() {
+            log::info!("Swept {} unreferenced attachments", attachments.len());
+        }
+    }
+
+    pub async fn authenticate_user(&self, login_data: &LoginForm) -> Option<UserData> {
+        let result = match self.store.get_user_by_login(&login_data.username).await {
+            Ok(user) => user,
+            Err(e) => {log::error!("Failed to get user data: fn authenticate_user, error: {:?}", e);
+            return None}
+        };
 
         match result {
-            Some(user_data) => {
-                if bcrypt::verify(login_data.password.clone(), &user_data.hashed_password).unwrap_or(false) {
-                    Some(user_data.user_id)
-                } else {
-                    None
-                }
+            Some(user_data) if bcrypt::verify(login_data.password.clone(), &user_data.hashed_password).unwrap_or(false) => {
+                Some(user_data)
             },
+            Some(_) => None,
             None => {
+                let _ = bcrypt::verify(login_data.password.clone(), &DUMMY_PASSWORD_HASH);
                 None
             }
         }
     }
 
     pub async fn valid_user_credentials(&self, signup_data: &LoginForm) -> bool {
-        let result: Option<UserData> = match self.db.select(("logins", &signup_data.username))            .await {
+        let result = match self.store.get_user_by_login(&signup_data.username).await {
             Ok(retrieved) => retrieved,
             Err(e) => {log::error!("Failed to get user : fn valid_user_credentials, error: {:?}", e);
             return false}
@@ -113,4 +325,29 @@ impl AppState {
             }
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+impl AppState {
+    // Default config over store, sending its emails to email
+    pub fn for_tests(store: Arc<dyn ChatStore>, email: Arc<dyn EmailSender>) -> AppState {
+        let mut config = Config::default();
+        config.uploads.dir = std::env::temp_dir().join("blacksignal-tests");
+        AppState {
+            store,
+            actor_registry: Arc::new(Mutex::new(HashMap::new())),
+            main_room_id: "main".to_string(),
+            presence: PresenceTracker::new(),
+            uploads: UploadStore::new(&config.uploads).unwrap(),
+            attachment_lock: AsyncMutex::new(()),
+            email,
+            signing_key: Key::generate(),
+            verification_sent: Mutex::new(HashMap::new()),
+            login_guard: LoginGuard::new(&config.auth),
+            rate_limiter: RateLimiter::new(&config.rate_limit),
+            sent_messages: SentMessages::new(&config.messages),
+            resume: ResumeLog::new(&config.session),
+            config,
+        }
+    }
+}
@@ -0,0 +1,519 @@
+use actix_session::{Session, SessionInsertError};
+use actix_web::HttpRequest;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use bcrypt::{hash, DEFAULT_COST};
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use totp_rs::{Algorithm, Secret, TOTP};
+use uuid::Uuid;
+use crate::appstate::AppState;
+use crate::email::Email;
+use crate::structs::{PasswordResetToken, SessionRecord, TwoFactor, UserData};
+use crate::websocket::Disconnect;
+
+// Random bytes in every emailed token
+const TOKEN_LEN: usize = 32;
+const TOTP_ISSUER: &str = "Black Signal";
+// 160 bits, as RFC 4226 recommends
+const TOTP_SECRET_LEN: usize = 20;
+const TOTP_STEP_SECS: u64 = 30;
+const RECOVERY_CODE_COUNT: usize = 10;
+// Session entry of a login waiting for its second step
+const PENDING_LOGIN_KEY: &str = "pending_login";
+const PENDING_LOGIN_TTL_SECS: u64 = 5 * 60;
+// Wrong codes a pending login survives before the password must be entered again
+const PENDING_LOGIN_ATTEMPTS: u32 = 5;
+
+// An unguessable token for links sent by email
+pub fn generate_token() -> String {
+    let mut bytes = [0u8; TOKEN_LEN];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+pub fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+// Where a request comes from, recorded on the sessions it starts
+pub struct ClientInfo {
+    pub ip: String,
+    pub user_agent: Option<String>,
+}
+
+impl ClientInfo {
+    // The peer address, or with auth.trust_proxy_headers the client address reported by the proxy
+    pub fn from_request(req: &HttpRequest, trust_proxy_headers: bool) -> Self {
+        let connection_info = req.connection_info();
+        let ip = match trust_proxy_headers {
+            true => connection_info.realip_remote_addr(),
+            false => connection_info.peer_addr(),
+        };
+        let user_agent = req.headers()
+            .get(actix_web::http::header::USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .map(String::from);
+        ClientInfo { ip: ip.unwrap_or("unknown").to_string(), user_agent }
+    }
+}
+
+// Logs user into session, remembering the session_epoch it was started under,
+// and records it so it can be listed and revoked
+pub async fn start_session(state: &AppState, session: &Session, user: &UserData, client: &ClientInfo) -> anyhow::Result<()> {
+    let now = Utc::now().timestamp() as u64;
+    let record = SessionRecord {
+        session_id: Uuid::new_v4().to_string().replace('-', ""),
+        user_id: user.user_id.clone(),
+        created_at: now,
+        last_seen: now,
+        user_agent: client.user_agent.clone(),
+        ip: client.ip.clone(),
+    };
+    state.store.create_session(record.clone()).await?;
+    session.renew();
+    session.insert("key", &user.user_id)?;
+    session.insert("epoch", user.session_epoch)?;
+    session.insert("session_id", record.session_id)?;
+    Ok(())
+}
+
+pub fn current_session_id(session: &Session) -> Option<String> {
+    session.get::<String>("session_id").ok().flatten()
+}
+
+// Emails a reset link if email belongs to an account, and quietly does nothing otherwise
+pub async fn send_password_reset(state: &AppState, email: &str) {
+    let user = match state.store.get_user_by_login(email).await {
+        Ok(Some(user)) => user,
+        Ok(None) => return,
+        Err(e) => {log::error!("Failed to get user data: fn send_password_reset, error: {:?}", e);
+        return}
+    };
+    let token = generate_token();
+    let reset_token = PasswordResetToken {
+        token_hash: hash_token(&token),
+        user_id: user.user_id,
+        expires_at: Utc::now().timestamp() as u64 + state.config.auth.reset_token_ttl_secs,
+    };
+    if let Err(e) = state.store.create_reset_token(reset_token).await {
+        log::error!("Failed to create reset token: fn send_password_reset, error: {:?}", e);
+        return;
+    }
+    let link = format!("{}/password/reset?token={}", state.config.server.public_url(), token);
+    let email = Email {
+        to: user.login_username,
+        subject: "Reset your Black Signal password".to_string(),
+        body: format!(
+            "Someone asked to reset the password of your Black Signal account.\n\n\
+            Open this link to choose a new one:\n{}\n\n\
+            The link works once and expires in {} minutes. If you did not ask for this, ignore this email.",
+            link,
+            state.config.auth.reset_token_ttl_secs / 60,
+        ),
+    };
+    if let Err(e) = state.email.send(email).await {
+        log::error!("Failed to send reset email: fn send_password_reset, error: {:?}", e);
+    }
+}
+
+// Why a token from an emailed link was not accepted
+pub enum TokenError {
+    InvalidToken,
+    Internal(anyhow::Error),
+}
+
+impl From<anyhow::Error> for TokenError {
+    fn from(e: anyhow::Error) -> Self {
+        TokenError::Internal(e)
+    }
+}
+
+// Uses up token to set a new password, then ends every session of the account
+pub async fn reset_password(state: &AppState, token: &str, password: &str) -> Result<(), TokenError> {
+    let reset_token = match state.store.take_reset_token(&hash_token(token)).await? {
+        Some(reset_token) if reset_token.expires_at > Utc::now().timestamp() as u64 => reset_token,
+        _ => return Err(TokenError::InvalidToken),
+    };
+    let hashed_password = hash(password, DEFAULT_COST).map_err(anyhow::Error::from)?;
+    state.store.set_password(&reset_token.user_id, &hashed_password).await?;
+    state.store.delete_reset_tokens(&reset_token.user_id).await?;
+    state.store.delete_user_sessions(&reset_token.user_id, None).await?;
+    state.disconnect_user(&reset_token.user_id, Disconnect::All);
+    Ok(())
+}
+
+// Signature over everything a verification link vouches for. Including the
+// address means a link stops working if the account's email ever changes.
+fn verification_mac(state: &AppState, user_id: &str, email: &str, expires_at: u64) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(state.signing_key.signing()).expect("HMAC accepts keys of any length");
+    mac.update(format!("verify-email\n{}\n{}\n{}", user_id, email, expires_at).as_bytes());
+    mac
+}
+
+// Token of the form <user_id>.<expires_at>.<signature>
+fn verification_token(state: &AppState, user: &UserData) -> String {
+    let expires_at = Utc::now().timestamp() as u64 + state.config.auth.verification_token_ttl_secs;
+    let signature = verification_mac(state, &user.user_id, &user.login_username, expires_at).finalize().into_bytes();
+    format!("{}.{}.{}", user.user_id, expires_at, URL_SAFE_NO_PAD.encode(signature))
+}
+
+// Emails user a link that marks their address as verified
+pub async fn send_verification(state: &AppState, user: &UserData) {
+    state.verification_sent.lock().unwrap().insert(user.user_id.clone(), Utc::now().timestamp() as u64);
+    let link = format!("{}/verify_email?token={}", state.config.server.public_url(), verification_token(state, user));
+    let email = Email {
+        to: user.login_username.clone(),
+        subject: "Verify your Black Signal email".to_string(),
+        body: format!(
+            "Welcome to Black Signal!\n\n\
+            Open this link to confirm this is your email address:\n{}\n\n\
+            The link expires in {} hours. If you did not sign up, ignore this email.",
+            link,
+            state.config.auth.verification_token_ttl_secs / 3600,
+        ),
+    };
+    if let Err(e) = state.email.send(email).await {
+        log::error!("Failed to send verification email: fn send_verification, error: {:?}", e);
+    }
+}
+
+pub enum ResendError {
+    AlreadyVerified,
+    // Seconds until another email may be sent
+    TooSoon(u64),
+}
+
+// Sends a fresh verification link, at most once per verification_resend_interval_secs
+pub async fn resend_verification(state: &AppState, user: &UserData) -> Result<(), ResendError> {
+    if user.email_verified {
+        return Err(ResendError::AlreadyVerified);
+    }
+    let now = Utc::now().timestamp() as u64;
+    let interval = state.config.auth.verification_resend_interval_secs;
+    {
+        let mut verification_sent = state.verification_sent.lock().unwrap();
+        verification_sent.retain(|_, sent_at| *sent_at + interval > now);
+        if let Some(sent_at) = verification_sent.get(&user.user_id) {
+            return Err(ResendError::TooSoon(*sent_at + interval - now));
+        }
+    }
+    send_verification(state, user).await;
+    Ok(())
+}
+
+// Checks the signature and expiry of token, then marks the user it names as verified
+pub async fn verify_email(state: &AppState, token: &str) -> Result<(), TokenError> {
+    let mut parts = token.splitn(3, '.');
+    let (Some(user_id), Some(expires_at), Some(signature)) = (parts.next(), parts.next(), parts.next()) else {
+        return Err(TokenError::InvalidToken);
+    };
+    let (Ok(expires_at), Ok(signature)) = (expires_at.parse::<u64>(), URL_SAFE_NO_PAD.decode(signature)) else {
+        return Err(TokenError::InvalidToken);
+    };
+    if expires_at <= Utc::now().timestamp() as u64 {
+        return Err(TokenError::InvalidToken);
+    }
+    let Some(user) = state.store.get_user(user_id).await? else {
+        return Err(TokenError::InvalidToken);
+    };
+    if verification_mac(state, &user.user_id, &user.login_username, expires_at).verify_slice(&signature).is_err() {
+        return Err(TokenError::InvalidToken);
+    }
+    state.store.set_email_verified(&user.user_id).await?;
+    Ok(())
+}
+
+pub enum TwoFactorError {
+    AlreadyEnabled,
+    NotEnabled,
+    // Confirming without an enrollment in progress
+    NotEnrolling,
+    // The pending login is missing, expired or out of attempts
+    NoPendingLogin,
+    InvalidCode,
+    Internal(anyhow::Error),
+}
+
+impl From<anyhow::Error> for TwoFactorError {
+    fn from(e: anyhow::Error) -> Self {
+        TwoFactorError::Internal(e)
+    }
+}
+
+pub struct TotpEnrollment {
+    pub secret: String,
+    // For authenticator apps, usually shown as a QR code
+    pub otpauth_uri: String,
+}
+
+// A login whose password was right, waiting for a code from an authenticator
+// app. The session only gets "key" once the code is accepted.
+#[derive(Serialize, Deserialize)]
+struct PendingLogin {
+    user_id: String,
+    // The login guard key the first step was made with
+    login_key: String,
+    session_epoch: u64,
+    started_at: u64,
+    attempts: u32,
+}
+
+fn totp(secret: &str, user: &UserData) -> anyhow::Result<TOTP> {
+    let secret = Secret::Encoded(secret.to_string()).to_bytes()?;
+    let account_name = user.login_username.replace(':', "");
+    // No skew here, matching_step tries the neighbouring steps itself
+    Ok(TOTP::new(Algorithm::SHA1, 6, 0, TOTP_STEP_SECS, secret, Some(TOTP_ISSUER.to_string()), account_name)?)
+}
+
+// The time step code was generated for, allowing one step of clock drift either way
+fn matching_step(totp: &TOTP, code: &str) -> Option<u64> {
+    let current = Utc::now().timestamp() as u64 / TOTP_STEP_SECS;
+    (current - 1..=current + 1).find(|step| totp.check(code, step * TOTP_STEP_SECS))
+}
+
+fn normalize_recovery_code(code: &str) -> String {
+    code.chars().filter(|c| c.is_ascii_alphanumeric()).collect::<String>().to_ascii_lowercase()
+}
+
+// Recovery codes to show the user once, and the hashes to store
+fn generate_recovery_codes() -> (Vec<String>, Vec<String>) {
+    let codes: Vec<String> = (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let mut bytes = [0u8; 5];
+            rand::thread_rng().fill_bytes(&mut bytes);
+            let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+            format!("{}-{}", &hex[..5], &hex[5..])
+        })
+        .collect();
+    let hashes = codes.iter().map(|code| hash_token(&normalize_recovery_code(code))).collect();
+    (codes, hashes)
+}
+
+// Accepts a current TOTP code not used before, or an unused recovery code
+async fn check_second_factor(state: &AppState, user: &UserData, code: &str) -> anyhow::Result<bool> {
+    let Some(two_factor) = &user.two_factor else {
+        return Ok(false);
+    };
+    let code = code.trim();
+    if code.len() == 6 && code.bytes().all(|b| b.is_ascii_digit()) {
+        return match matching_step(&totp(&two_factor.secret, user)?, code) {
+            Some(step) => state.store.advance_totp_step(&user.user_id, step).await,
+            None => Ok(false),
+        };
+    }
+    state.store.take_recovery_code(&user.user_id, &hash_token(&normalize_recovery_code(code))).await
+}
+
+// Starts enrollment with a fresh secret, replacing any enrollment left unconfirmed
+pub async fn enroll_totp(state: &AppState, user: &UserData) -> Result<TotpEnrollment, TwoFactorError> {
+    if user.two_factor.is_some() {
+        return Err(TwoFactorError::AlreadyEnabled);
+    }
+    let mut bytes = [0u8; TOTP_SECRET_LEN];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    let Secret::Encoded(secret) = Secret::Raw(bytes.to_vec()).to_encoded() else {
+        unreachable!("to_encoded always returns Secret::Encoded");
+    };
+    let otpauth_uri = totp(&secret, user)?.get_url();
+    state.store.set_pending_totp_secret(&user.user_id, Some(secret.clone())).await?;
+    Ok(TotpEnrollment { secret, otpauth_uri })
+}
+
+// Turns on two-factor authentication once code shows the authenticator app
+// has the pending secret, returning the recovery codes
+pub async fn confirm_totp(state: &AppState, user: &UserData, code: &str) -> Result<Vec<String>, TwoFactorError> {
+    if user.two_factor.is_some() {
+        return Err(TwoFactorError::AlreadyEnabled);
+    }
+    let Some(secret) = &user.pending_totp_secret else {
+        return Err(TwoFactorError::NotEnrolling);
+    };
+    let Some(step) = matching_step(&totp(secret, user)?, code.trim()) else {
+        return Err(TwoFactorError::InvalidCode);
+    };
+    let (codes, hashes) = generate_recovery_codes();
+    let two_factor = TwoFactor { secret: secret.clone(), recovery_codes: hashes, last_step: step };
+    state.store.set_two_factor(&user.user_id, Some(two_factor)).await?;
+    Ok(codes)
+}
+
+pub async fn disable_totp(state: &AppState, user: &UserData, code: &str) -> Result<(), TwoFactorError> {
+    if user.two_factor.is_none() {
+        return Err(TwoFactorError::NotEnabled);
+    }
+    if !check_second_factor(state, user, code).await? {
+        return Err(TwoFactorError::InvalidCode);
+    }
+    state.store.set_two_factor(&user.user_id, None).await?;
+    Ok(())
+}
+
+// First step of logging in a user with two-factor authentication
+pub fn start_pending_login(session: &Session, user: &UserData, login_key: &str) -> Result<(), SessionInsertError> {
+    session.renew();
+    session.insert(PENDING_LOGIN_KEY, PendingLogin {
+        user_id: user.user_id.clone(),
+        login_key: login_key.to_string(),
+        session_epoch: user.session_epoch,
+        started_at: Utc::now().timestamp() as u64,
+        attempts: 0,
+    })
+}
+
+// Second step: logs the session in if code is right for its pending login
+pub async fn complete_pending_login(state: &AppState, session: &Session, code: &str, client: &ClientInfo) -> Result<UserData, TwoFactorError> {
+    let Some(mut pending) = session.get::<PendingLogin>(PENDING_LOGIN_KEY).ok().flatten() else {
+        return Err(TwoFactorError::NoPendingLogin);
+    };
+    if pending.started_at + PENDING_LOGIN_TTL_SECS <= Utc::now().timestamp() as u64 {
+        session.remove(PENDING_LOGIN_KEY);
+        return Err(TwoFactorError::NoPendingLogin);
+    }
+    let user = match state.store.get_user(&pending.user_id).await? {
+        Some(user) if user.session_epoch == pending.session_epoch => user,
+        _ => {
+            session.remove(PENDING_LOGIN_KEY);
+            return Err(TwoFactorError::NoPendingLogin);
+        }
+    };
+    if !check_second_factor(state, &user, code).await? {
+        state.record_login_failure(&pending.login_key, &client.ip).await;
+        pending.attempts += 1;
+        if pending.attempts >= PENDING_LOGIN_ATTEMPTS {
+            session.remove(PENDING_LOGIN_KEY);
+        } else {
+            session.insert(PENDING_LOGIN_KEY, pending).map_err(anyhow::Error::from)?;
+        }
+        return Err(TwoFactorError::InvalidCode);
+    }
+    session.remove(PENDING_LOGIN_KEY);
+    start_session(state, session, &user, client).await?;
+    state.login_guard.record_success(&pending.login_key);
+    Ok(user)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_session::SessionExt;
+    use std::sync::Arc;
+    use crate::email::LogEmailSender;
+    use crate::store::memory::MemoryStore;
+    use crate::structs::ConnectionState;
+
+    const SECRET: &str = "JBSWY3DPEHPK3PXPJBSWY3DPEHPK3PXP";
+
+    fn user(two_factor: Option<TwoFactor>) -> UserData {
+        UserData {
+            user_id: "alice".to_string(),
+            login_username: "alice@example.com".to_string(),
+            username: "alice".to_string(),
+            hashed_password: String::new(),
+            status: ConnectionState::Offline,
+            rooms: Vec::new(),
+            last_seen: None,
+            session_epoch: 0,
+            email_verified: true,
+            two_factor,
+            pending_totp_secret: None,
+        }
+    }
+
+    // State with alice enrolled in two-factor authentication, and her recovery codes
+    async fn state_with_two_factor() -> (AppState, UserData, Vec<String>) {
+        let (codes, hashes) = generate_recovery_codes();
+        let user = user(Some(TwoFactor { secret: SECRET.to_string(), recovery_codes: hashes, last_step: 0 }));
+        let state = AppState::for_tests(Arc::new(MemoryStore::new()), Arc::new(LogEmailSender));
+        state.store.create_user(user.clone()).await.unwrap();
+        (state, user, codes)
+    }
+
+    fn current_code(user: &UserData) -> String {
+        totp(SECRET, user).unwrap().generate_current().unwrap()
+    }
+
+    fn client() -> ClientInfo {
+        ClientInfo { ip: "127.0.0.1".to_string(), user_agent: None }
+    }
+
+    #[actix_web::test]
+    async fn totp_code_is_accepted_once() {
+        let (state, user, _) = state_with_two_factor().await;
+        let code = current_code(&user);
+        assert!(check_second_factor(&state, &user, &code).await.unwrap());
+        assert!(!check_second_factor(&state, &user, &code).await.unwrap());
+    }
+
+    #[actix_web::test]
+    async fn totp_step_never_goes_back() {
+        let (state, _, _) = state_with_two_factor().await;
+        assert!(state.store.advance_totp_step("alice", 10).await.unwrap());
+        assert!(!state.store.advance_totp_step("alice", 10).await.unwrap());
+        assert!(!state.store.advance_totp_step("alice", 9).await.unwrap());
+        assert!(state.store.advance_totp_step("alice", 11).await.unwrap());
+        assert!(!state.store.advance_totp_step("bob", 12).await.unwrap());
+    }
+
+    #[actix_web::test]
+    async fn recovery_code_is_single_use() {
+        let (state, user, codes) = state_with_two_factor().await;
+        assert!(check_second_factor(&state, &user, &codes[0]).await.unwrap());
+        assert!(!check_second_factor(&state, &user, &codes[0]).await.unwrap());
+        // Spelled without the dash and in capitals, it is the same code
+        let other = codes[1].replace('-', "").to_ascii_uppercase();
+        assert!(check_second_factor(&state, &user, &other).await.unwrap());
+        assert!(!check_second_factor(&state, &user, &codes[1]).await.unwrap());
+        let left = state.store.get_user("alice").await.unwrap().unwrap().two_factor.unwrap().recovery_codes;
+        assert_eq!(left.len(), RECOVERY_CODE_COUNT - 2);
+    }
+
+    #[actix_web::test]
+    async fn pending_login_is_completed_by_a_right_code() {
+        let (state, user, _) = state_with_two_factor().await;
+        let session = actix_web::test::TestRequest::default().to_srv_request().get_session();
+        start_pending_login(&session, &user, "alice@example.com").unwrap();
+        assert!(session.get::<String>("key").unwrap().is_none());
+        let logged_in = complete_pending_login(&state, &session, &current_code(&user), &client()).await.ok().unwrap();
+        assert_eq!(logged_in.user_id, "alice");
+        assert_eq!(session.get::<String>("key").unwrap().as_deref(), Some("alice"));
+        assert!(session.get::<PendingLogin>(PENDING_LOGIN_KEY).unwrap().is_none());
+        assert_eq!(state.store.user_sessions("alice").await.unwrap().len(), 1);
+    }
+
+    #[actix_web::test]
+    async fn pending_login_is_dropped_after_too_many_wrong_codes() {
+        let (state, user, _) = state_with_two_factor().await;
+        let session = actix_web::test::TestRequest::default().to_srv_request().get_session();
+        start_pending_login(&session, &user, "alice@example.com").unwrap();
+        for _ in 0..PENDING_LOGIN_ATTEMPTS {
+            let result = complete_pending_login(&state, &session, "not a code", &client()).await;
+            assert!(matches!(result, Err(TwoFactorError::InvalidCode)));
+        }
+        let result = complete_pending_login(&state, &session, &current_code(&user), &client()).await;
+        assert!(matches!(result, Err(TwoFactorError::NoPendingLogin)));
+        assert!(session.get::<String>("key").unwrap().is_none());
+    }
+
+    #[actix_web::test]
+    async fn pending_login_ends_when_the_session_epoch_moves_on() {
+        let (state, user, _) = state_with_two_factor().await;
+        let session = actix_web::test::TestRequest::default().to_srv_request().get_session();
+        start_pending_login(&session, &user, "alice@example.com").unwrap();
+        // Changing the password moves the epoch on
+        state.store.set_password("alice", "new hash").await.unwrap();
+        let result = complete_pending_login(&state, &session, &current_code(&user), &client()).await;
+        assert!(matches!(result, Err(TwoFactorError::NoPendingLogin)));
+    }
+
+    #[actix_web::test]
+    async fn pending_login_is_missing_without_a_first_step() {
+        let (state, user, _) = state_with_two_factor().await;
+        let session = actix_web::test::TestRequest::default().to_srv_request().get_session();
+        let result = complete_pending_login(&state, &session, &current_code(&user), &client()).await;
+        assert!(matches!(result, Err(TwoFactorError::NoPendingLogin)));
+    }
+}
@@ -0,0 +1,620 @@
+use actix_web::cookie::Key;
+use anyhow::{bail, Context};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde::Deserialize;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+const DEFAULT_CONFIG_PATH: &str = "blacksignal.toml";
+const ENV_PREFIX: &str = "BLACKSIGNAL_";
+// actix-web's cookie Key needs at least 64 bytes of key material
+const SESSION_KEY_LEN: usize = 64;
+
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub store: StoreConfig,
+    pub surreal: SurrealConfig,
+    pub redis: RedisConfig,
+    pub session: SessionConfig,
+    pub uploads: UploadsConfig,
+    pub email: EmailConfig,
+    pub auth: AuthConfig,
+    pub rate_limit: RateLimitConfig,
+    pub messages: MessagesConfig,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub host: String,
+    pub port: u16,
+    // Base of links sent out by email, defaults to http://host:port
+    pub public_url: Option<String>,
+    // How often expired sessions, rate limit buckets, idempotency keys and resume tokens are dropped
+    pub housekeeping_interval_secs: u64,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig { host: "127.0.0.1".to_string(), port: 8080, public_url: None, housekeeping_interval_secs: 60 }
+    }
+}
+
+impl ServerConfig {
+    pub fn public_url(&self) -> String {
+        match &self.public_url {
+            Some(url) => url.trim_end_matches('/').to_string(),
+            None => format!("http://{}:{}", self.host, self.port),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum StoreBackend {
+    #[default]
+    Surreal,
+    Memory,
+}
+
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct StoreConfig {
+    pub backend: StoreBackend,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct SurrealConfig {
+    pub address: String,
+    pub username: String,
+    pub password: String,
+    pub namespace: String,
+    pub database: String,
+}
+
+impl Default for SurrealConfig {
+    fn default() -> Self {
+        SurrealConfig {
+            address: "localhost:8000".to_string(),
+            username: "root".to_string(),
+            password: "root".to_string(),
+            namespace: "general".to_string(),
+            database: "all".to_string(),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct RedisConfig {
+    pub address: String,
+}
+
+impl Default for RedisConfig {
+    fn default() -> Self {
+        RedisConfig { address: "127.0.0.1:6379".to_string() }
+    }
+}
+
+// The signing key is taken from `key` (base64) when set, otherwise read from
+// `key_file`, which is created with a fresh key on first boot.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionConfig {
+    pub key: Option<String>,
+    pub key_file: PathBuf,
+    // Sessions not used for this long are logged out
+    pub ttl_secs: u64,
+    // How long a closed websocket connection can be resumed
+    pub resume_window_secs: u64,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        SessionConfig { key: None, key_file: PathBuf::from("session.key"), ttl_secs: 24 * 60 * 60, resume_window_secs: 300 }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct UploadsConfig {
+    pub dir: PathBuf,
+    // Largest accepted upload in bytes
+    pub max_size: u64,
+    // Total bytes one user may have uploaded, and that may be uploaded into one room
+    pub user_quota: u64,
+    pub room_quota: u64,
+    // How often attachments no message refers to are deleted, and how old they
+    // must be first so an upload can still be posted
+    pub sweep_interval_secs: u64,
+    pub unreferenced_grace_secs: u64,
+}
+
+impl Default for UploadsConfig {
+    fn default() -> Self {
+        UploadsConfig {
+            dir: PathBuf::from("uploads"),
+            max_size: 10 * 1024 * 1024,
+            user_quota: 500 * 1024 * 1024,
+            room_quota: 2 * 1024 * 1024 * 1024,
+            sweep_interval_secs: 60 * 60,
+            unreferenced_grace_secs: 24 * 60 * 60,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum EmailBackend {
+    // Writes emails to the log instead of sending them
+    #[default]
+    Log,
+    Smtp,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpSecurity {
+    #[default]
+    None,
+    Starttls,
+    Tls,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct EmailConfig {
+    pub backend: EmailBackend,
+    pub from: String,
+    pub smtp_host: String,
+    pub smtp_port: u16,
+    pub smtp_security: SmtpSecurity,
+    pub smtp_username: Option<String>,
+    pub smtp_password: Option<String>,
+}
+
+impl Default for EmailConfig {
+    fn default() -> Self {
+        EmailConfig {
+            backend: EmailBackend::Log,
+            from: "Black Signal <noreply@localhost>".to_string(),
+            smtp_host: "localhost".to_string(),
+            smtp_port: 25,
+            smtp_security: SmtpSecurity::None,
+            smtp_username: None,
+            smtp_password: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    // How long a password reset link stays usable
+    pub reset_token_ttl_secs: u64,
+    // Unverified users can only be in the main room
+    pub require_email_verification: bool,
+    pub verification_token_ttl_secs: u64,
+    // Shortest time between two verification emails to the same user
+    pub verification_resend_interval_secs: u64,
+    // Failed logins before an account name, or a client IP, is locked out
+    pub max_failed_logins: u64,
+    pub max_failed_logins_per_ip: u64,
+    // Wait after the first failed login, doubling with each further failure up to the max
+    pub login_backoff_base_secs: u64,
+    pub login_backoff_max_secs: u64,
+    pub lockout_secs: u64,
+    // Take the client IP from X-Forwarded-For / Forwarded, only safe behind a reverse proxy that sets them
+    pub trust_proxy_headers: bool,
+    // Login emails of users who may review and lift lockouts
+    pub admins: Vec<String>,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        AuthConfig {
+            reset_token_ttl_secs: 60 * 60,
+            require_email_verification: false,
+            verification_token_ttl_secs: 24 * 60 * 60,
+            verification_resend_interval_secs: 60,
+            max_failed_logins: 5,
+            max_failed_logins_per_ip: 20,
+            login_backoff_base_secs: 1,
+            login_backoff_max_secs: 60,
+            lockout_secs: 15 * 60,
+            trust_proxy_headers: false,
+            admins: Vec::new(),
+        }
+    }
+}
+
+// Every user, and every IP on HTTP routes, gets a bucket of capacity tokens
+// that refills at refill_per_sec. Actions take tokens by how costly they are.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    pub capacity: u64,
+    pub refill_per_sec: u64,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig { capacity: 60, refill_per_sec: 5 }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct MessagesConfig {
+    // How long a message's idempotency key is remembered, resends within it get the original back
+    pub idempotency_window_secs: u64,
+}
+
+impl Default for MessagesConfig {
+    fn default() -> Self {
+        MessagesConfig { idempotency_window_secs: 300 }
+    }
+}
+
+impl Config {
+    // Reads the TOML file named by BLACKSIGNAL_CONFIG (or blacksignal.toml if it exists),
+    // then applies BLACKSIGNAL_<SECTION>_<FIELD> environment overrides and validates.
+    pub fn load() -> anyhow::Result<Config> {
+        let mut config = match std::env::var(format!("{}CONFIG", ENV_PREFIX)) {
+            Ok(path) => Config::from_file(Path::new(&path))?,
+            Err(_) if Path::new(DEFAULT_CONFIG_PATH).exists() => Config::from_file(Path::new(DEFAULT_CONFIG_PATH))?,
+            Err(_) => Config::default(),
+        };
+        config.apply_env(|name| std::env::var(format!("{}{}", ENV_PREFIX, name)).ok())?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> anyhow::Result<Config> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read config file {}", path.display()))?;
+        toml::from_str(&content)
+            .with_context(|| format!("failed to parse config file {}", path.display()))
+    }
+
+    fn apply_env(&mut self, var: impl Fn(&str) -> Option<String>) -> anyhow::Result<()> {
+        if let Some(host) = var("SERVER_HOST") {
+            self.server.host = host;
+        }
+        if let Some(port) = var("SERVER_PORT") {
+            self.server.port = port.parse()
+                .with_context(|| format!("{}SERVER_PORT must be a port number, got {:?}", ENV_PREFIX, port))?;
+        }
+        if let Some(public_url) = var("SERVER_PUBLIC_URL") {
+            self.server.public_url = Some(public_url);
+        }
+        if let Some(backend) = var("STORE_BACKEND") {
+            self.store.backend = match backend.as_str() {
+                "surreal" => StoreBackend::Surreal,
+                "memory" => StoreBackend::Memory,
+                _ => bail!("{}STORE_BACKEND must be \"surreal\" or \"memory\", got {:?}", ENV_PREFIX, backend),
+            };
+        }
+        if let Some(address) = var("SURREAL_ADDRESS") {
+            self.surreal.address = address;
+        }
+        if let Some(username) = var("SURREAL_USERNAME") {
+            self.surreal.username = username;
+        }
+        if let Some(password) = var("SURREAL_PASSWORD") {
+            self.surreal.password = password;
+        }
+        if let Some(namespace) = var("SURREAL_NAMESPACE") {
+            self.surreal.namespace = namespace;
+        }
+        if let Some(database) = var("SURREAL_DATABASE") {
+            self.surreal.database = database;
+        }
+        if let Some(address) = var("REDIS_ADDRESS") {
+            self.redis.address = address;
+        }
+        if let Some(key) = var("SESSION_KEY") {
+            self.session.key = Some(key);
+        }
+        if let Some(key_file) = var("SESSION_KEY_FILE") {
+            self.session.key_file = PathBuf::from(key_file);
+        }
+        if let Some(dir) = var("UPLOADS_DIR") {
+            self.uploads.dir = PathBuf::from(dir);
+        }
+        if let Some(backend) = var("EMAIL_BACKEND") {
+            self.email.backend = match backend.as_str() {
+                "log" => EmailBackend::Log,
+                "smtp" => EmailBackend::Smtp,
+                _ => bail!("{}EMAIL_BACKEND must be \"log\" or \"smtp\", got {:?}", ENV_PREFIX, backend),
+            };
+        }
+        if let Some(from) = var("EMAIL_FROM") {
+            self.email.from = from;
+        }
+        if let Some(host) = var("EMAIL_SMTP_HOST") {
+            self.email.smtp_host = host;
+        }
+        if let Some(port) = var("EMAIL_SMTP_PORT") {
+            self.email.smtp_port = port.parse()
+                .with_context(|| format!("{}EMAIL_SMTP_PORT must be a port number, got {:?}", ENV_PREFIX, port))?;
+        }
+        if let Some(security) = var("EMAIL_SMTP_SECURITY") {
+            self.email.smtp_security = match security.as_str() {
+                "none" => SmtpSecurity::None,
+                "starttls" => SmtpSecurity::Starttls,
+                "tls" => SmtpSecurity::Tls,
+                _ => bail!("{}EMAIL_SMTP_SECURITY must be \"none\", \"starttls\" or \"tls\", got {:?}", ENV_PREFIX, security),
+            };
+        }
+        if let Some(username) = var("EMAIL_SMTP_USERNAME") {
+            self.email.smtp_username = Some(username);
+        }
+        if let Some(password) = var("EMAIL_SMTP_PASSWORD") {
+            self.email.smtp_password = Some(password);
+        }
+        if let Some(required) = var("AUTH_REQUIRE_EMAIL_VERIFICATION") {
+            self.auth.require_email_verification = required.parse()
+                .with_context(|| format!("{}AUTH_REQUIRE_EMAIL_VERIFICATION must be \"true\" or \"false\", got {:?}", ENV_PREFIX, required))?;
+        }
+        if let Some(trust) = var("AUTH_TRUST_PROXY_HEADERS") {
+            self.auth.trust_proxy_headers = trust.parse()
+                .with_context(|| format!("{}AUTH_TRUST_PROXY_HEADERS must be \"true\" or \"false\", got {:?}", ENV_PREFIX, trust))?;
+        }
+        if let Some(admins) = var("AUTH_ADMINS") {
+            self.auth.admins = admins.split(',').map(str::trim).filter(|admin| !admin.is_empty()).map(String::from).collect();
+        }
+        for (name, field) in [
+            ("SERVER_HOUSEKEEPING_INTERVAL_SECS", &mut self.server.housekeeping_interval_secs),
+            ("SESSION_TTL_SECS", &mut self.session.ttl_secs),
+            ("SESSION_RESUME_WINDOW_SECS", &mut self.session.resume_window_secs),
+            ("UPLOADS_MAX_SIZE", &mut self.uploads.max_size),
+            ("UPLOADS_USER_QUOTA", &mut self.uploads.user_quota),
+            ("UPLOADS_ROOM_QUOTA", &mut self.uploads.room_quota),
+            ("UPLOADS_SWEEP_INTERVAL_SECS", &mut self.uploads.sweep_interval_secs),
+            ("UPLOADS_UNREFERENCED_GRACE_SECS", &mut self.uploads.unreferenced_grace_secs),
+            ("AUTH_RESET_TOKEN_TTL_SECS", &mut self.auth.reset_token_ttl_secs),
+            ("AUTH_VERIFICATION_TOKEN_TTL_SECS", &mut self.auth.verification_token_ttl_secs),
+            ("AUTH_VERIFICATION_RESEND_INTERVAL_SECS", &mut self.auth.verification_resend_interval_secs),
+            ("AUTH_MAX_FAILED_LOGINS", &mut self.auth.max_failed_logins),
+            ("AUTH_MAX_FAILED_LOGINS_PER_IP", &mut self.auth.max_failed_logins_per_ip),
+            ("AUTH_LOGIN_BACKOFF_BASE_SECS", &mut self.auth.login_backoff_base_secs),
+            ("AUTH_LOGIN_BACKOFF_MAX_SECS", &mut self.auth.login_backoff_max_secs),
+            ("AUTH_LOCKOUT_SECS", &mut self.auth.lockout_secs),
+            ("RATE_LIMIT_CAPACITY", &mut self.rate_limit.capacity),
+            ("RATE_LIMIT_REFILL_PER_SEC", &mut self.rate_limit.refill_per_sec),
+            ("MESSAGES_IDEMPOTENCY_WINDOW_SECS", &mut self.messages.idempotency_window_secs),
+        ] {
+            if let Some(value) = var(name) {
+                *field = value.parse()
+                    .with_context(|| format!("{}{} must be a whole number, got {:?}", ENV_PREFIX, name, value))?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.server.host.trim().is_empty() {
+            bail!("server.host must not be empty");
+        }
+        if self.server.port == 0 {
+            bail!("server.port must not be 0");
+        }
+        if self.store.backend == StoreBackend::Surreal {
+            for (field, value) in [
+                ("surreal.address", &self.surreal.address),
+                ("surreal.username", &self.surreal.username),
+                ("surreal.namespace", &self.surreal.namespace),
+                ("surreal.database", &self.surreal.database),
+            ] {
+                if value.trim().is_empty() {
+                    bail!("{} must not be empty when store.backend is \"surreal\"", field);
+                }
+            }
+        }
+        if self.redis.address.trim().is_empty() {
+            bail!("redis.address must not be empty");
+        }
+        match &self.session.key {
+            Some(key) => {
+                decode_session_key(key).context("session.key is invalid")?;
+            }
+            None => {
+                if self.session.key_file.as_os_str().is_empty() {
+                    bail!("session.key_file must not be empty when session.key is not set");
+                }
+            }
+        }
+        if self.uploads.dir.as_os_str().is_empty() {
+            bail!("uploads.dir must not be empty");
+        }
+        for (field, value) in [
+            ("server.housekeeping_interval_secs", self.server.housekeeping_interval_secs),
+            ("session.ttl_secs", self.session.ttl_secs),
+            ("session.resume_window_secs", self.session.resume_window_secs),
+            ("uploads.max_size", self.uploads.max_size),
+            ("uploads.user_quota", self.uploads.user_quota),
+            ("uploads.room_quota", self.uploads.room_quota),
+            ("uploads.sweep_interval_secs", self.uploads.sweep_interval_secs),
+            ("auth.reset_token_ttl_secs", self.auth.reset_token_ttl_secs),
+            ("auth.verification_token_ttl_secs", self.auth.verification_token_ttl_secs),
+            ("auth.max_failed_logins", self.auth.max_failed_logins),
+            ("auth.max_failed_logins_per_ip", self.auth.max_failed_logins_per_ip),
+            ("auth.lockout_secs", self.auth.lockout_secs),
+            ("rate_limit.capacity", self.rate_limit.capacity),
+            ("rate_limit.refill_per_sec", self.rate_limit.refill_per_sec),
+            ("messages.idempotency_window_secs", self.messages.idempotency_window_secs),
+        ] {
+            if value == 0 {
+                bail!("{} must not be 0", field);
+            }
+        }
+        if self.auth.login_backoff_base_secs > self.auth.login_backoff_max_secs {
+            bail!("auth.login_backoff_base_secs must not be larger than auth.login_backoff_max_secs");
+        }
+        if self.email.from.parse::<lettre::message::Mailbox>().is_err() {
+            bail!("email.from must be an address like \"Name <user@example.com>\", got {:?}", self.email.from);
+        }
+        if self.email.backend == EmailBackend::Smtp {
+            if self.email.smtp_host.trim().is_empty() {
+                bail!("email.smtp_host must not be empty when email.backend is \"smtp\"");
+            }
+            if self.email.smtp_port == 0 {
+                bail!("email.smtp_port must not be 0");
+            }
+            if self.email.smtp_username.is_some() != self.email.smtp_password.is_some() {
+                bail!("email.smtp_username and email.smtp_password must be set together");
+            }
+        }
+        Ok(())
+    }
+
+    pub fn session_key(&self) -> anyhow::Result<Key> {
+        if let Some(key) = &self.session.key {
+            return decode_session_key(key);
+        }
+        let path = &self.session.key_file;
+        if path.exists() {
+            let content = std::fs::read_to_string(path)
+                .with_context(|| format!("failed to read session key file {}", path.display()))?;
+            return decode_session_key(content.trim())
+                .with_context(|| format!("session key file {} is invalid", path.display()));
+        }
+        let key = Key::generate();
+        // Readable by the server's user only, and never over a file created since the check above
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(path)
+            .with_context(|| format!("failed to create session key file {}", path.display()))?;
+        file.write_all(BASE64.encode(key.master()).as_bytes())
+            .with_context(|| format!("failed to write session key file {}", path.display()))?;
+        log::info!("Generated new session key at {}", path.display());
+        Ok(key)
+    }
+}
+
+fn decode_session_key(encoded: &str) -> anyhow::Result<Key> {
+    let bytes = BASE64.decode(encoded).context("session key must be base64")?;
+    if bytes.len() < SESSION_KEY_LEN {
+        bail!("session key must be at least {} bytes, got {}", SESSION_KEY_LEN, bytes.len());
+    }
+    Ok(Key::from(&bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    // Config::default() with vars applied as if they were set with the BLACKSIGNAL_ prefix
+    fn with_env(vars: &[(&str, &str)]) -> anyhow::Result<Config> {
+        let vars: HashMap<&str, &str> = vars.iter().copied().collect();
+        let mut config = Config::default();
+        config.apply_env(|name| vars.get(name).map(|value| value.to_string()))?;
+        Ok(config)
+    }
+
+    // Makes a default config invalid in one way
+    type Change = fn(&mut Config);
+
+    fn validation_error(change: Change) -> String {
+        let mut config = Config::default();
+        change(&mut config);
+        config.validate().unwrap_err().to_string()
+    }
+
+    #[test]
+    fn defaults_are_valid() {
+        Config::default().validate().unwrap();
+    }
+
+    #[test]
+    fn env_overrides_the_defaults() {
+        let config = with_env(&[
+            ("SERVER_HOST", "0.0.0.0"),
+            ("SERVER_PORT", "9000"),
+            ("STORE_BACKEND", "memory"),
+            ("EMAIL_SMTP_SECURITY", "starttls"),
+            ("AUTH_TRUST_PROXY_HEADERS", "true"),
+            ("AUTH_ADMINS", "alice, ,bob"),
+            ("UPLOADS_MAX_SIZE", "1024"),
+            ("MESSAGES_IDEMPOTENCY_WINDOW_SECS", "30"),
+        ]).unwrap();
+        assert_eq!(config.server.host, "0.0.0.0");
+        assert_eq!(config.server.port, 9000);
+        assert_eq!(config.store.backend, StoreBackend::Memory);
+        assert_eq!(config.email.smtp_security, SmtpSecurity::Starttls);
+        assert!(config.auth.trust_proxy_headers);
+        assert_eq!(config.auth.admins, ["alice", "bob"]);
+        assert_eq!(config.uploads.max_size, 1024);
+        assert_eq!(config.messages.idempotency_window_secs, 30);
+    }
+
+    #[test]
+    fn unset_env_leaves_the_defaults() {
+        let config = with_env(&[]).unwrap();
+        let defaults = Config::default();
+        assert_eq!(config.server.port, defaults.server.port);
+        assert_eq!(config.uploads.max_size, defaults.uploads.max_size);
+    }
+
+    #[test]
+    fn bad_env_values_are_rejected_by_name() {
+        for (name, value) in [
+            ("SERVER_PORT", "http"),
+            ("SERVER_PORT", "70000"),
+            ("STORE_BACKEND", "postgres"),
+            ("EMAIL_BACKEND", "carrier pigeon"),
+            ("EMAIL_SMTP_SECURITY", "ssl"),
+            ("AUTH_REQUIRE_EMAIL_VERIFICATION", "yes"),
+            ("SESSION_TTL_SECS", "-1"),
+            ("RATE_LIMIT_CAPACITY", "lots"),
+        ] {
+            let error = with_env(&[(name, value)]).unwrap_err().to_string();
+            assert!(error.starts_with(&format!("{}{}", ENV_PREFIX, name)), "{} = {:?}: {}", name, value, error);
+        }
+    }
+
+    #[test]
+    fn validate_names_the_bad_field() {
+        let cases: [(&str, Change); 8] = [
+            ("server.host", |config| config.server.host = " ".to_string()),
+            ("server.port", |config| config.server.port = 0),
+            ("surreal.address", |config| {
+                config.store.backend = StoreBackend::Surreal;
+                config.surreal.address = String::new();
+            }),
+            ("session.key", |config| config.session.key = Some("short".to_string())),
+            ("session.ttl_secs", |config| config.session.ttl_secs = 0),
+            ("auth.login_backoff_base_secs", |config| {
+                config.auth.login_backoff_base_secs = config.auth.login_backoff_max_secs + 1;
+            }),
+            ("email.from", |config| config.email.from = "not an address".to_string()),
+            ("email.smtp_username", |config| {
+                config.email.backend = EmailBackend::Smtp;
+                config.email.smtp_host = "smtp.example.com".to_string();
+                config.email.smtp_username = Some("user".to_string());
+            }),
+        ];
+        for (field, change) in cases {
+            let error = validation_error(change);
+            assert!(error.starts_with(field), "{}: {}", field, error);
+        }
+    }
+
+    #[test]
+    fn memory_store_needs_no_surreal_settings() {
+        let mut config = Config::default();
+        config.store.backend = StoreBackend::Memory;
+        config.surreal.address = String::new();
+        config.validate().unwrap();
+    }
+}
@@ -0,0 +1,179 @@
+use anyhow::Context;
+use async_trait::async_trait;
+use lettre::message::header::ContentType;
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use std::sync::Arc;
+use crate::config::{EmailBackend, EmailConfig, SmtpSecurity};
+
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+#[async_trait]
+pub trait EmailSender: Send + Sync {
+    async fn send(&self, email: Email) -> anyhow::Result<()>;
+}
+
+pub fn from_config(config: &EmailConfig) -> anyhow::Result<Arc<dyn EmailSender>> {
+    Ok(match config.backend {
+        EmailBackend::Log => Arc::new(LogEmailSender),
+        EmailBackend::Smtp => Arc::new(SmtpEmailSender::new(config)?),
+    })
+}
+
+// For development: the whole email, links included, ends up in the log
+pub struct LogEmailSender;
+
+#[async_trait]
+impl EmailSender for LogEmailSender {
+    async fn send(&self, email: Email) -> anyhow::Result<()> {
+        log::info!("Email to {}\nSubject: {}\n\n{}", email.to, email.subject, email.body);
+        Ok(())
+    }
+}
+
+pub struct SmtpEmailSender {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+}
+
+impl SmtpEmailSender {
+    pub fn new(config: &EmailConfig) -> anyhow::Result<Self> {
+        let builder = match config.smtp_security {
+            // Plain connections are for local relays and SMTP stand-ins in development
+            SmtpSecurity::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&config.smtp_host),
+            SmtpSecurity::Starttls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&config.smtp_host)?,
+            SmtpSecurity::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&config.smtp_host)?,
+        };
+        let mut builder = builder.port(config.smtp_port);
+        if let (Some(username), Some(password)) = (&config.smtp_username, &config.smtp_password) {
+            builder = builder.credentials(Credentials::new(username.clone(), password.clone()));
+        }
+        let from = config.from.parse().context("email.from is not a valid address")?;
+        Ok(SmtpEmailSender { transport: builder.build(), from })
+    }
+}
+
+#[async_trait]
+impl EmailSender for SmtpEmailSender {
+    async fn send(&self, email: Email) -> anyhow::Result<()> {
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(email.to.parse().with_context(|| format!("invalid recipient {:?}", email.to))?)
+            .subject(email.subject)
+            .header(ContentType::TEXT_PLAIN)
+            .body(email.body)?;
+        self.transport.send(message).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+    use std::sync::Mutex;
+    use crate::appstate::AppState;
+    use crate::auth::{hash_token, reset_password, send_password_reset, TokenError};
+    use crate::store::memory::MemoryStore;
+    use crate::structs::{ConnectionState, PasswordResetToken, SessionRecord, UserData};
+
+    // Keeps every email instead of sending it
+    #[derive(Default)]
+    struct MemoryEmailSender {
+        sent: Mutex<Vec<Email>>,
+    }
+
+    #[async_trait]
+    impl EmailSender for MemoryEmailSender {
+        async fn send(&self, email: Email) -> anyhow::Result<()> {
+            self.sent.lock().unwrap().push(email);
+            Ok(())
+        }
+    }
+
+    async fn state_with_user() -> (AppState, Arc<MemoryEmailSender>) {
+        let email = Arc::new(MemoryEmailSender::default());
+        let state = AppState::for_tests(Arc::new(MemoryStore::new()), email.clone());
+        state.store.create_user(UserData {
+            user_id: "alice".to_string(),
+            login_username: "alice@example.com".to_string(),
+            username: "alice".to_string(),
+            hashed_password: "old hash".to_string(),
+            status: ConnectionState::Offline,
+            rooms: Vec::new(),
+            last_seen: None,
+            session_epoch: 0,
+            email_verified: true,
+            two_factor: None,
+            pending_totp_secret: None,
+        }).await.unwrap();
+        (state, email)
+    }
+
+    // The token in the reset link of the one email sent
+    fn sent_token(email: &MemoryEmailSender) -> String {
+        let sent = email.sent.lock().unwrap();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].to, "alice@example.com");
+        let (_, rest) = sent[0].body.split_once("token=").unwrap();
+        rest.split_whitespace().next().unwrap().to_string()
+    }
+
+    #[actix_web::test]
+    async fn reset_email_is_only_sent_to_known_accounts() {
+        let (state, email) = state_with_user().await;
+        send_password_reset(&state, "bob@example.com").await;
+        assert!(email.sent.lock().unwrap().is_empty());
+    }
+
+    #[actix_web::test]
+    async fn reset_token_is_stored_hashed() {
+        let (state, email) = state_with_user().await;
+        send_password_reset(&state, "alice@example.com").await;
+        let token = sent_token(&email);
+        assert!(state.store.take_reset_token(&token).await.unwrap().is_none());
+        assert!(state.store.take_reset_token(&hash_token(&token)).await.unwrap().is_some());
+    }
+
+    #[actix_web::test]
+    async fn reset_sets_the_password_once_and_ends_every_session() {
+        let (state, email) = state_with_user().await;
+        state.store.create_session(SessionRecord {
+            session_id: "session".to_string(),
+            user_id: "alice".to_string(),
+            created_at: 0,
+            last_seen: 0,
+            user_agent: None,
+            ip: "127.0.0.1".to_string(),
+        }).await.unwrap();
+        send_password_reset(&state, "alice@example.com").await;
+        let token = sent_token(&email);
+
+        assert!(reset_password(&state, &token, "new password").await.is_ok());
+        let user = state.store.get_user("alice").await.unwrap().unwrap();
+        assert!(bcrypt::verify("new password", &user.hashed_password).unwrap());
+        assert!(user.session_epoch > 0);
+        assert!(state.store.user_sessions("alice").await.unwrap().is_empty());
+
+        let reused = reset_password(&state, &token, "another password").await;
+        assert!(matches!(reused, Err(TokenError::InvalidToken)));
+    }
+
+    #[actix_web::test]
+    async fn expired_reset_token_is_refused() {
+        let (state, _) = state_with_user().await;
+        state.store.create_reset_token(PasswordResetToken {
+            token_hash: hash_token("token"),
+            user_id: "alice".to_string(),
+            expires_at: Utc::now().timestamp() as u64,
+        }).await.unwrap();
+        let result = reset_password(&state, "token", "new password").await;
+        assert!(matches!(result, Err(TokenError::InvalidToken)));
+        assert_eq!(state.store.get_user("alice").await.unwrap().unwrap().hashed_password, "old hash");
+    }
+}
@@ -0,0 +1,141 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use crate::config::MessagesConfig;
+use crate::message_structs::BasicMessage;
+
+// Longest idempotency key a client may send
+pub const MAX_IDEMPOTENCY_KEY_LEN: usize = 128;
+
+struct Entry {
+    // None while the send that claimed the key has not finished
+    posted: Option<BasicMessage>,
+    claimed_at_secs: u64,
+}
+
+pub enum Claim {
+    // The key is new, the caller posts the message and then completes or releases it
+    New,
+    Pending,
+    Posted(Box<BasicMessage>),
+}
+
+// Remembers the idempotency keys each user sent messages with, so a resent
+// frame returns the first message instead of posting it again. Keys live in
+// memory for the configured window and are forgotten on restart.
+pub struct SentMessages {
+    keys: Mutex<HashMap<(String, String), Entry>>,
+    window_secs: u64,
+}
+
+impl SentMessages {
+    pub fn new(config: &MessagesConfig) -> Self {
+        SentMessages {
+            keys: Mutex::new(HashMap::new()),
+            window_secs: config.idempotency_window_secs,
+        }
+    }
+
+    pub fn claim(&self, user_id: &str, key: &str, now: u64) -> Claim {
+        let mut keys = self.keys.lock().unwrap();
+        let id = (user_id.to_string(), key.to_string());
+        match keys.get(&id) {
+            Some(entry) if entry.claimed_at_secs + self.window_secs > now => match &entry.posted {
+                None => Claim::Pending,
+                Some(message) => Claim::Posted(Box::new(message.clone())),
+            },
+            _ => {
+                keys.insert(id, Entry { posted: None, claimed_at_secs: now });
+                Claim::New
+            }
+        }
+    }
+
+    pub fn complete(&self, user_id: &str, key: &str, message: BasicMessage) {
+        let mut keys = self.keys.lock().unwrap();
+        if let Some(entry) = keys.get_mut(&(user_id.to_string(), key.to_string())) {
+            entry.posted = Some(message);
+        }
+    }
+
+    // A send that failed frees its key, so the client can retry with it
+    pub fn release(&self, user_id: &str, key: &str) {
+        self.keys.lock().unwrap().remove(&(user_id.to_string(), key.to_string()));
+    }
+
+    pub fn forget_expired(&self, now: u64) {
+        self.keys.lock().unwrap().retain(|_, entry| entry.claimed_at_secs + self.window_secs > now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sent_messages() -> SentMessages {
+        SentMessages::new(&MessagesConfig { idempotency_window_secs: 60 })
+    }
+
+    fn message(message_id: &str) -> BasicMessage {
+        BasicMessage {
+            content: "hello".to_string(),
+            sender_id: "alice".to_string(),
+            timestamp: 1000,
+            message_id: message_id.to_string(),
+            room_id: "room".to_string(),
+            ws_id: String::new(),
+            seq: 1,
+            edited_at: None,
+            deleted_at: None,
+            image: None,
+            attachments: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn resend_within_the_window_gets_the_first_message() {
+        let sent = sent_messages();
+        assert!(matches!(sent.claim("alice", "key", 1000), Claim::New));
+        assert!(matches!(sent.claim("alice", "key", 1001), Claim::Pending));
+        sent.complete("alice", "key", message("first"));
+        match sent.claim("alice", "key", 1059) {
+            Claim::Posted(message) => assert_eq!(message.message_id, "first"),
+            _ => panic!("resend was not answered with the posted message"),
+        }
+    }
+
+    #[test]
+    fn key_can_be_used_again_after_the_window() {
+        let sent = sent_messages();
+        assert!(matches!(sent.claim("alice", "key", 1000), Claim::New));
+        sent.complete("alice", "key", message("first"));
+        assert!(matches!(sent.claim("alice", "key", 1060), Claim::New));
+    }
+
+    #[test]
+    fn forget_expired_drops_only_keys_past_the_window() {
+        let sent = sent_messages();
+        sent.claim("alice", "old", 1000);
+        sent.claim("alice", "new", 1030);
+        sent.forget_expired(1060);
+        assert_eq!(sent.keys.lock().unwrap().len(), 1);
+        assert!(matches!(sent.claim("alice", "new", 1060), Claim::Pending));
+    }
+
+    #[test]
+    fn keys_are_kept_per_user() {
+        let sent = sent_messages();
+        assert!(matches!(sent.claim("alice", "key", 1000), Claim::New));
+        sent.complete("alice", "key", message("first"));
+        assert!(matches!(sent.claim("bob", "key", 1000), Claim::New));
+        sent.complete("bob", "other", message("never claimed"));
+        assert!(matches!(sent.claim("bob", "key", 1001), Claim::Pending));
+    }
+
+    #[test]
+    fn released_key_can_be_retried() {
+        let sent = sent_messages();
+        assert!(matches!(sent.claim("alice", "key", 1000), Claim::New));
+        sent.release("alice", "key");
+        assert!(matches!(sent.claim("alice", "key", 1001), Claim::New));
+    }
+}
@@ -0,0 +1,165 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use crate::config::AuthConfig;
+use crate::structs::LockoutKind;
+
+#[derive(Default)]
+struct Failures {
+    count: u64,
+    last_failure_secs: u64,
+    locked_until_secs: u64,
+}
+
+// A lockout that record_failure has just started
+pub struct NewLockout {
+    pub kind: LockoutKind,
+    pub target: String,
+    pub failures: u64,
+    pub expires_at: u64,
+}
+
+// Counts failed logins per account name and per client IP. Each failure makes
+// the next attempt wait twice as long, and reaching the limit locks the target
+// out. Account names are tracked whether or not an account uses them, so the
+// responses never tell the two apart. Counts live in memory and reset on restart.
+pub struct LoginGuard {
+    failures: Mutex<HashMap<(LockoutKind, String), Failures>>,
+    max_failed_logins: u64,
+    max_failed_logins_per_ip: u64,
+    backoff_base_secs: u64,
+    backoff_max_secs: u64,
+    lockout_secs: u64,
+}
+
+impl LoginGuard {
+    pub fn new(config: &AuthConfig) -> Self {
+        LoginGuard {
+            failures: Mutex::new(HashMap::new()),
+            max_failed_logins: config.max_failed_logins,
+            max_failed_logins_per_ip: config.max_failed_logins_per_ip,
+            backoff_base_secs: config.login_backoff_base_secs,
+            backoff_max_secs: config.login_backoff_max_secs,
+            lockout_secs: config.lockout_secs,
+        }
+    }
+
+    fn limit(&self, kind: LockoutKind) -> u64 {
+        match kind {
+            LockoutKind::Account => self.max_failed_logins,
+            LockoutKind::Ip => self.max_failed_logins_per_ip,
+        }
+    }
+
+    fn wait(&self, failures: &Failures, now: u64) -> u64 {
+        if failures.locked_until_secs > now {
+            return failures.locked_until_secs - now;
+        }
+        if failures.count == 0 {
+            return 0;
+        }
+        let backoff = self.backoff_base_secs
+            .saturating_mul(1u64 << (failures.count - 1).min(32))
+            .min(self.backoff_max_secs);
+        (failures.last_failure_secs + backoff).saturating_sub(now)
+    }
+
+    // Seconds until login may be tried for that account name from that ip, None when it may be tried now
+    pub fn check(&self, login: &str, ip: &str, now: u64) -> Option<u64> {
+        let failures = self.failures.lock().unwrap();
+        let wait = [(LockoutKind::Account, login), (LockoutKind::Ip, ip)]
+            .into_iter()
+            .filter_map(|(kind, target)| failures.get(&(kind, target.to_string())))
+            .map(|entry| self.wait(entry, now))
+            .max()
+            .unwrap_or(0);
+        (wait > 0).then_some(wait)
+    }
+
+    pub fn record_failure(&self, login: &str, ip: &str, now: u64) -> Vec<NewLockout> {
+        let mut failures = self.failures.lock().unwrap();
+        // Targets quiet for as long as a lockout lasts start over
+        failures.retain(|_, entry| entry.locked_until_secs > now || entry.last_failure_secs + self.lockout_secs > now);
+        let mut started = Vec::new();
+        for (kind, target) in [(LockoutKind::Account, login), (LockoutKind::Ip, ip)] {
+            let entry = failures.entry((kind, target.to_string())).or_default();
+            if entry.locked_until_secs != 0 && entry.locked_until_secs <= now {
+                *entry = Failures::default();
+            }
+            entry.count += 1;
+            entry.last_failure_secs = now;
+            if entry.count >= self.limit(kind) && entry.locked_until_secs <= now {
+                entry.locked_until_secs = now + self.lockout_secs;
+                started.push(NewLockout {
+                    kind,
+                    target: target.to_string(),
+                    failures: entry.count,
+                    expires_at: entry.locked_until_secs,
+                });
+            }
+        }
+        started
+    }
+
+    // The IP keeps its count, one good password says nothing about other attempts from it
+    pub fn record_success(&self, login: &str) {
+        self.failures.lock().unwrap().remove(&(LockoutKind::Account, login.to_string()));
+    }
+
+    pub fn lift(&self, kind: LockoutKind, target: &str) {
+        self.failures.lock().unwrap().remove(&(kind, target.to_string()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn guard() -> LoginGuard {
+        LoginGuard::new(&AuthConfig {
+            max_failed_logins: 3,
+            max_failed_logins_per_ip: 10,
+            login_backoff_base_secs: 2,
+            login_backoff_max_secs: 5,
+            lockout_secs: 100,
+            ..AuthConfig::default()
+        })
+    }
+
+    #[test]
+    fn each_failure_doubles_the_wait_up_to_the_max() {
+        let guard = guard();
+        assert_eq!(guard.check("alice", "ip", 1000), None);
+        guard.record_failure("alice", "ip", 1000);
+        assert_eq!(guard.check("alice", "ip", 1000), Some(2));
+        assert_eq!(guard.check("alice", "ip", 1002), None);
+        guard.record_failure("alice", "ip", 1002);
+        assert_eq!(guard.check("alice", "ip", 1002), Some(4));
+        guard.record_failure("bob", "ip", 1002);
+        guard.record_failure("bob", "ip", 1002);
+        guard.record_failure("bob", "ip", 1002);
+        // The ip has failed five times now, capped at 5 seconds
+        assert_eq!(guard.check("carol", "ip", 1002), Some(5));
+    }
+
+    #[test]
+    fn reaching_the_limit_locks_the_account_out() {
+        let guard = guard();
+        assert!(guard.record_failure("alice", "ip1", 1000).is_empty());
+        assert!(guard.record_failure("alice", "ip2", 1000).is_empty());
+        let started = guard.record_failure("alice", "ip3", 1000);
+        assert_eq!(started.len(), 1);
+        assert_eq!(started[0].kind, LockoutKind::Account);
+        assert_eq!(started[0].expires_at, 1100);
+        assert_eq!(guard.check("alice", "ip4", 1050), Some(50));
+        assert_eq!(guard.check("alice", "ip4", 1100), None);
+    }
+
+    #[test]
+    fn success_clears_the_account_but_not_the_ip() {
+        let guard = guard();
+        guard.record_failure("alice", "ip", 1000);
+        guard.record_success("alice");
+        assert_eq!(guard.check("alice", "other ip", 1000), None);
+        assert_eq!(guard.check("bob", "ip", 1000), Some(2));
+    }
+}
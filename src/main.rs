@@ -1,18 +1,20 @@
-use actix_web::{get, post, web, App, HttpServer, HttpResponse, Responder};
-use actix_session::{storage::RedisSessionStore, Session, SessionMiddleware};
+use actix_web::{delete, get, post, web, App, HttpRequest, HttpServer, HttpResponse, Responder};
+use actix_web::http::header::{self, Charset, ContentDisposition, DispositionParam, DispositionType, ExtendedValue};
+use actix_files::NamedFile;
+use actix_session::{Session, SessionMiddleware};
+use actix_session::config::BrowserSession;
 use actix_session::storage::RedisActorSessionStore;
-use actix_web::cookie::Key;
-use actix_files::Files;
-use surrealdb::engine::remote::ws::Ws;
-use surrealdb::opt::auth::Root;
-use surrealdb::Surreal;
 use std::collections::{HashMap, HashSet};
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use std::fs::read_to_string;
-use std::io::Write;
 
 use local_ip_address::local_ip;
-use serde::{Deserialize, Serialize};
+use futures_util::TryStreamExt;
+use actix_multipart::Multipart;
+use chrono::Utc;
+use serde::Deserialize;
+use validator::Validate;
 use bcrypt::{hash, DEFAULT_COST};
 use names::{Generator, Name};
 use serde_json::json;
@@ -23,14 +25,48 @@ mod websocket;
 mod appstate;
 mod structs;
 mod message_structs;
+mod store;
+mod config;
+mod presence;
+mod permissions;
+mod uploads;
+mod email;
+mod auth;
+mod login_guard;
+mod rate_limit;
+mod idempotency;
+mod resume;
 
-use structs::{Room, ConnectionState, LoginForm, UserData};
+use structs::{AttachmentMeta, Room, ConnectionState, LoginForm, UserData};
 use message_structs::*;
 use websocket::*;
 use appstate::AppState;
+use store::{ChatStore, MemoryStore, SurrealStore};
+use config::{Config, StoreBackend};
+use presence::PresenceTracker;
+use uploads::{sanitize_filename, ImageKind, UploadError, UploadStore};
+use login_guard::LoginGuard;
+use idempotency::SentMessages;
+use resume::ResumeLog;
+use rate_limit::{RateLimitKey, RateLimiter, ACCOUNT_CHANGE_COST, EMAIL_COST, LOGIN_COST, SIGNUP_COST, UPLOAD_COST};
+use auth::{
+    complete_pending_login, confirm_totp, current_session_id, disable_totp, enroll_totp, resend_verification, reset_password,
+    send_password_reset, send_verification, start_pending_login, start_session, verify_email, ClientInfo, ResendError, TokenError,
+    TwoFactorError,
+};
+
+// Fixed ids, so the rows made on first boot are found again after a restart
+const MAIN_ROOM_ID: &str = "main";
+const TEST_USER_ID: &str = "test";
+const TEST_LOGIN: &str = "test@gmail.com";
 
 #[get("/logout")]
-async fn logout(session: Session) -> impl Responder {
+async fn logout(session: Session, state: web::Data<AppState>) -> impl Responder {
+    if let (Some(user), Some(session_id)) = (state.session_user(&session).await, current_session_id(&session)) {
+        if let Err(e) = state.revoke_session(&user.user_id, &session_id).await {
+            log::error!("Failed to revoke session: fn logout, error: {:?}", e);
+        }
+    }
     session.purge();
     HttpResponse::Found().append_header(("LOCATION", "/login")).finish()
 }
@@ -47,8 +83,24 @@ async fn create_login_page() -> impl Responder {
     }
 }
 
+// Charges cost to the caller's IP, and to user_id on routes for logged in users
+fn rate_limit(req: &HttpRequest, state: &AppState, user_id: Option<&str>, cost: u64) -> Result<(), HttpResponse> {
+    let ip = ClientInfo::from_request(req, state.config.auth.trust_proxy_headers).ip;
+    let mut keys = vec![RateLimitKey::Ip(ip)];
+    keys.extend(user_id.map(|user_id| RateLimitKey::User(user_id.to_string())));
+    state.rate_limiter.acquire(&keys, cost).map_err(|retry_after| {
+        let retry_after_ms = retry_after.as_millis() as u64;
+        HttpResponse::TooManyRequests()
+            .append_header(("Retry-After", retry_after_ms.div_ceil(1000).to_string()))
+            .json(json!({"error": "Too many requests, please slow down", "retry_after_ms": retry_after_ms}))
+    })
+}
+
 #[post("/create_login")]
-async fn create_login_action(state: web::Data<AppState>, form: web::Json<LoginForm>, session: Session) -> impl Responder {
+async fn create_login_action(req: HttpRequest, state: web::Data<AppState>, form: web::Json<LoginForm>, session: Session) -> impl Responder {
+    if let Err(response) = rate_limit(&req, &state, None, SIGNUP_COST) {
+        return response;
+    }
     let login = form.into_inner();
     if state.valid_user_credentials(&login).await {
         let mut generator = Generator::with_naming(Name::Numbered);
@@ -58,36 +110,43 @@ async fn create_login_action(state: web::Data<AppState>, form: web::Json<LoginFo
             hashed_password: hash(login.password.clone(), DEFAULT_COST).unwrap(),
             login_username: login.username,
             username: generator.next().unwrap().replace('-', ""),
-            status: ConnectionState::Online,
-            rooms: vec![state.main_room_id.clone()],
-
-        };
-        let _: Vec<UserData> = match state.db.create("users").content(user_data.clone())
-            .await {
-            Ok(created) => created,
-            Err(e) => {log::error!("Failed to get user data: fn create_login_action, error: {:?}", e);
-            return HttpResponse::InternalServerError().body("Internal server error: Failed to create user data.")}
+            status: ConnectionState::Offline,
+            rooms: Vec::new(),
+            last_seen: None,
+            session_epoch: 0,
+            email_verified: false,
+            two_factor: None,
+            pending_totp_secret: None,
         };
+        if let Err(e) = state.store.create_user(user_data.clone()).await {
+            log::error!("Failed to create user data: fn create_login_action, error: {:?}", e);
+            return HttpResponse::InternalServerError().body("Internal server error: Failed to create user data.")
+        }
 
-        let query = "UPDATE rooms SET users += $user_id WHERE room_id = $room_id;";
-        if let Err(e) = state.db.query(query).bind(("user_id", user_data.user_id.clone())).bind(("room_id", state.main_room_id.clone())).await {
+        if let Err(e) = state.store.add_room_member(&state.main_room_id, &user_data.user_id).await {
             log::error!("Error adding to room: {:?}", e);
             return HttpResponse::InternalServerError().body("Internal server error: Failed to add user to room in db: fn create_login_action")
         }
 
-        let message = UserMessage::NewUser(NewUserMessage::new(user_data.user_id.clone(), user_data.username));
+        let message = UserMessage::NewUser(NewUserMessage::new(user_data.user_id.clone(), user_data.username.clone()));
         let serialized_message = serde_json::to_string(&message).unwrap();
 
         state.broadcast_message(serialized_message, state.main_room_id.clone(), user_data.user_id.clone()).await;
-        match session.insert("key", user_data.user_id){
+        if state.config.auth.require_email_verification {
+            let state = state.clone().into_inner();
+            let user_data = user_data.clone();
+            actix_web::rt::spawn(async move {
+                send_verification(&state, &user_data).await;
+            });
+        }
+        let client = ClientInfo::from_request(&req, state.config.auth.trust_proxy_headers);
+        match start_session(&state, &session, &user_data, &client).await {
             Ok(_) => HttpResponse::Found().append_header(("LOCATION", "/")).finish(),
             Err(e) => {
-                println!("Error: {:?}", e);
-                log::error!("Error: {:?}", e);
+                log::error!("Failed to start session: fn create_login_action, error: {:?}", e);
                 HttpResponse::InternalServerError().finish()
             }
-        };
-        HttpResponse::Found().append_header(("LOCATION", "/")).finish()
+        }
     } else {
         HttpResponse::Ok().json(json!(LoginErrorMessage::new("Invalid Please enter an email and a password".to_string())))
     }
@@ -106,12 +165,36 @@ async fn login_page() -> impl Responder {
 }
 
 #[post("/login")]
-async fn login_action(state: web::Data<AppState>, form: web::Json<LoginForm>, session: Session) -> impl Responder {
+async fn login_action(req: HttpRequest, state: web::Data<AppState>, form: web::Json<LoginForm>, session: Session) -> impl Responder {
+    if let Err(response) = rate_limit(&req, &state, None, LOGIN_COST) {
+        return response;
+    }
     let login = form.into_inner();
+    let login_key = login.username.trim().to_lowercase();
+    let client = ClientInfo::from_request(&req, state.config.auth.trust_proxy_headers);
+    // Checked before the password, so a locked out guess costs no bcrypt verify
+    if let Some(retry_after) = state.login_guard.check(&login_key, &client.ip, Utc::now().timestamp() as u64) {
+        return HttpResponse::TooManyRequests()
+            .append_header(("Retry-After", retry_after.to_string()))
+            .json(json!(LoginErrorMessage::new("Too many failed logins, please try again later".to_string())));
+    }
+    // A login with two-factor authentication only succeeds once its code is checked
     match state.authenticate_user(&login).await {
-        Some(username) => {
-            match session.insert("key", username){
-                Ok(_) => HttpResponse::Found().append_header(("LOCATION", "/")).finish(),
+        Some(user) if user.two_factor.is_some() => {
+            match start_pending_login(&session, &user, &login_key) {
+                Ok(_) => HttpResponse::Ok().json(json!({"two_factor_required": true})),
+                Err(e) => {
+                    log::error!("Failed to start pending login: fn login_action, error: {:?}", e);
+                    HttpResponse::InternalServerError().finish()
+                }
+            }
+        }
+        Some(user) => {
+            match start_session(&state, &session, &user, &client).await {
+                Ok(_) => {
+                    state.login_guard.record_success(&login_key);
+                    HttpResponse::Found().append_header(("LOCATION", "/")).finish()
+                }
                 Err(e) => {
                     println!("Error: {:?}", e);
                     log::error!("Error: {:?}", e);
@@ -120,68 +203,535 @@ async fn login_action(state: web::Data<AppState>, form: web::Json<LoginForm>, se
             }
         }
         None => {
+            state.record_login_failure(&login_key, &client.ip).await;
             HttpResponse::Ok().json(json!(LoginErrorMessage::new("Invalid Please enter an email and a password".to_string())))
         }
     }
 }
 
-#[derive(Serialize, Deserialize)]
-struct Image {
-    filename: String,
-    data: Vec<u8>,
+// The caller's logged in sessions, marking the one making the request
+#[get("/sessions")]
+async fn list_sessions(session: Session, state: web::Data<AppState>) -> impl Responder {
+    let user = match state.session_user(&session).await {
+        Some(user) => user,
+        None => return HttpResponse::Unauthorized().finish(),
+    };
+    let last_seen_after = (Utc::now().timestamp() as u64).saturating_sub(state.config.session.ttl_secs);
+    match state.store.user_sessions(&user.user_id).await {
+        Ok(sessions) => {
+            let sessions: Vec<_> = sessions.into_iter().filter(|record| record.last_seen > last_seen_after).collect();
+            HttpResponse::Ok().json(json!({"current_session_id": current_session_id(&session), "sessions": sessions}))
+        }
+        Err(e) => {
+            log::error!("Failed to get sessions: fn list_sessions, error: {:?}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[delete("/sessions/{session_id}")]
+async fn revoke_session(path: web::Path<String>, session: Session, state: web::Data<AppState>) -> impl Responder {
+    let user = match state.session_user(&session).await {
+        Some(user) => user,
+        None => return HttpResponse::Unauthorized().finish(),
+    };
+    let session_id = path.into_inner();
+    match state.store.get_session(&session_id).await {
+        Ok(Some(record)) if record.user_id == user.user_id => {}
+        Ok(_) => return HttpResponse::NotFound().finish(),
+        Err(e) => {
+            log::error!("Failed to get session: fn revoke_session, error: {:?}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+    if let Err(e) = state.revoke_session(&user.user_id, &session_id).await {
+        log::error!("Failed to revoke session: fn revoke_session, error: {:?}", e);
+        return HttpResponse::InternalServerError().finish();
+    }
+    if current_session_id(&session).as_deref() == Some(session_id.as_str()) {
+        session.purge();
+    }
+    HttpResponse::Ok().finish()
+}
+
+// Logs out everywhere but the session making the request
+#[post("/sessions/revoke_others")]
+async fn revoke_other_sessions(session: Session, state: web::Data<AppState>) -> impl Responder {
+    let (Some(user), Some(session_id)) = (state.session_user(&session).await, current_session_id(&session)) else {
+        return HttpResponse::Unauthorized().finish();
+    };
+    match state.revoke_other_sessions(&user.user_id, &session_id).await {
+        Ok(()) => HttpResponse::Ok().finish(),
+        Err(e) => {
+            log::error!("Failed to revoke sessions: fn revoke_other_sessions, error: {:?}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct LockoutsQuery {
+    // Unix timestamp, defaults to a week ago
+    since: Option<u64>,
+}
+
+async fn session_admin(session: &Session, state: &AppState) -> Result<UserData, HttpResponse> {
+    match state.session_user(session).await {
+        Some(user) if state.is_admin(&user) => Ok(user),
+        Some(_) => Err(HttpResponse::Forbidden().finish()),
+        None => Err(HttpResponse::Unauthorized().finish()),
+    }
+}
+
+#[get("/admin/lockouts")]
+async fn list_lockouts(query: web::Query<LockoutsQuery>, session: Session, state: web::Data<AppState>) -> impl Responder {
+    if let Err(response) = session_admin(&session, &state).await {
+        return response;
+    }
+    let since = query.since.unwrap_or_else(|| (Utc::now().timestamp() as u64).saturating_sub(7 * 24 * 60 * 60));
+    match state.store.recent_lockouts(since).await {
+        Ok(lockouts) => HttpResponse::Ok().json(lockouts),
+        Err(e) => {
+            log::error!("Failed to get lockouts: fn list_lockouts, error: {:?}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+// Ends a lockout early, also clearing the failed logins that led to it
+#[post("/admin/lockouts/{lockout_id}/lift")]
+async fn lift_lockout(path: web::Path<String>, session: Session, state: web::Data<AppState>) -> impl Responder {
+    let admin = match session_admin(&session, &state).await {
+        Ok(admin) => admin,
+        Err(response) => return response,
+    };
+    let lockout = match state.store.get_lockout(&path.into_inner()).await {
+        Ok(Some(lockout)) => lockout,
+        Ok(None) => return HttpResponse::NotFound().finish(),
+        Err(e) => {
+            log::error!("Failed to get lockout: fn lift_lockout, error: {:?}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+    if let Err(e) = state.store.lift_lockout(&lockout.lockout_id, &admin.user_id, Utc::now().timestamp() as u64).await {
+        log::error!("Failed to lift lockout: fn lift_lockout, error: {:?}", e);
+        return HttpResponse::InternalServerError().finish();
+    }
+    state.login_guard.lift(lockout.kind, &lockout.target);
+    log::info!("Lockout of {:?} {} lifted by {}", lockout.kind, lockout.target, admin.login_username);
+    HttpResponse::Ok().finish()
+}
+
+#[derive(Deserialize)]
+struct ForgotPasswordForm {
+    email: String,
+}
+
+#[derive(Deserialize, Validate)]
+struct ResetPasswordForm {
+    token: String,
+    #[validate(length(min = 1))]
+    password: String,
+}
+
+// Serves both the "forgot password" form and, when opened from an emailed link, the new password form
+#[get("/password/{step:forgot|reset}")]
+async fn password_reset_page() -> impl Responder {
+    let path = "static/HTML/password_reset_page.html";
+    match read_to_string(path) {
+        Ok(content) => HttpResponse::Ok().content_type("text/html").body(content),
+        Err(err) => {
+            eprintln!("Failed to read password_reset_page HTML: {:?}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+// Answers the same whether or not the account exists, and sends in the
+// background so the response time does not tell either
+#[post("/password/forgot")]
+async fn forgot_password(req: HttpRequest, form: web::Json<ForgotPasswordForm>, state: web::Data<AppState>) -> impl Responder {
+    if let Err(response) = rate_limit(&req, &state, None, EMAIL_COST) {
+        return response;
+    }
+    let email = form.into_inner().email;
+    let state = state.into_inner();
+    actix_web::rt::spawn(async move {
+        send_password_reset(&state, &email).await;
+    });
+    HttpResponse::Ok().json(json!({"message": "If an account uses that email, a reset link has been sent to it"}))
+}
+
+#[post("/password/reset")]
+async fn reset_password_action(req: HttpRequest, form: web::Json<ResetPasswordForm>, session: Session, state: web::Data<AppState>) -> impl Responder {
+    if let Err(response) = rate_limit(&req, &state, None, ACCOUNT_CHANGE_COST) {
+        return response;
+    }
+    let form = form.into_inner();
+    if form.validate().is_err() {
+        return HttpResponse::BadRequest().json(json!({"error": "Please enter a new password"}));
+    }
+    match reset_password(&state, &form.token, &form.password).await {
+        Ok(()) => {
+            session.purge();
+            HttpResponse::Ok().json(json!({"message": "Password changed, please log in again"}))
+        }
+        Err(TokenError::InvalidToken) => {
+            HttpResponse::BadRequest().json(json!({"error": "This reset link is invalid or has expired"}))
+        }
+        Err(TokenError::Internal(e)) => {
+            log::error!("Failed to reset password: fn reset_password_action, error: {:?}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct TwoFactorCodeForm {
+    code: String,
+}
+
+fn two_factor_error_response(error: TwoFactorError, fn_name: &str) -> HttpResponse {
+    let message = match error {
+        TwoFactorError::AlreadyEnabled => "Two-factor authentication is already enabled",
+        TwoFactorError::NotEnabled => "Two-factor authentication is not enabled",
+        TwoFactorError::NotEnrolling => "Start two-factor enrollment first",
+        TwoFactorError::NoPendingLogin => {
+            return HttpResponse::Unauthorized().json(json!({"error": "Your login has expired, please log in again"}));
+        }
+        TwoFactorError::InvalidCode => "Invalid code",
+        TwoFactorError::Internal(e) => {
+            log::error!("Failed two-factor request: fn {}, error: {:?}", fn_name, e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+    HttpResponse::BadRequest().json(json!({"error": message}))
+}
+
+// Second step of /login for users with two-factor authentication
+#[post("/login/2fa")]
+async fn login_two_factor(req: HttpRequest, form: web::Json<TwoFactorCodeForm>, session: Session, state: web::Data<AppState>) -> impl Responder {
+    if let Err(response) = rate_limit(&req, &state, None, LOGIN_COST) {
+        return response;
+    }
+    let client = ClientInfo::from_request(&req, state.config.auth.trust_proxy_headers);
+    match complete_pending_login(&state, &session, &form.code, &client).await {
+        Ok(_) => HttpResponse::Found().append_header(("LOCATION", "/")).finish(),
+        Err(e) => two_factor_error_response(e, "login_two_factor"),
+    }
+}
+
+#[post("/2fa/enroll")]
+async fn enroll_two_factor(session: Session, state: web::Data<AppState>) -> impl Responder {
+    let user = match state.session_user(&session).await {
+        Some(user) => user,
+        None => return HttpResponse::Unauthorized().finish(),
+    };
+    match enroll_totp(&state, &user).await {
+        Ok(enrollment) => HttpResponse::Ok().json(json!({"secret": enrollment.secret, "otpauth_uri": enrollment.otpauth_uri})),
+        Err(e) => two_factor_error_response(e, "enroll_two_factor"),
+    }
+}
+
+// Recovery codes are only ever shown in this response
+#[post("/2fa/confirm")]
+async fn confirm_two_factor(form: web::Json<TwoFactorCodeForm>, session: Session, state: web::Data<AppState>) -> impl Responder {
+    let user = match state.session_user(&session).await {
+        Some(user) => user,
+        None => return HttpResponse::Unauthorized().finish(),
+    };
+    match confirm_totp(&state, &user, &form.code).await {
+        Ok(recovery_codes) => HttpResponse::Ok().json(json!({"recovery_codes": recovery_codes})),
+        Err(e) => two_factor_error_response(e, "confirm_two_factor"),
+    }
+}
+
+#[post("/2fa/disable")]
+async fn disable_two_factor(form: web::Json<TwoFactorCodeForm>, session: Session, state: web::Data<AppState>) -> impl Responder {
+    let user = match state.session_user(&session).await {
+        Some(user) => user,
+        None => return HttpResponse::Unauthorized().finish(),
+    };
+    match disable_totp(&state, &user, &form.code).await {
+        Ok(()) => HttpResponse::Ok().json(json!({"message": "Two-factor authentication disabled"})),
+        Err(e) => two_factor_error_response(e, "disable_two_factor"),
+    }
+}
+
+#[derive(Deserialize)]
+struct VerifyEmailQuery {
+    token: String,
+}
+
+#[get("/verify_email")]
+async fn verify_email_link(req: HttpRequest, query: web::Query<VerifyEmailQuery>, state: web::Data<AppState>) -> impl Responder {
+    if let Err(response) = rate_limit(&req, &state, None, ACCOUNT_CHANGE_COST) {
+        return response;
+    }
+    match verify_email(&state, &query.token).await {
+        Ok(()) => HttpResponse::Found().append_header(("LOCATION", "/")).finish(),
+        Err(TokenError::InvalidToken) => {
+            HttpResponse::BadRequest().body("This verification link is invalid or has expired")
+        }
+        Err(TokenError::Internal(e)) => {
+            log::error!("Failed to verify email: fn verify_email_link, error: {:?}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[post("/verify_email/resend")]
+async fn resend_verification_action(req: HttpRequest, session: Session, state: web::Data<AppState>) -> impl Responder {
+    let user = match state.session_user(&session).await {
+        Some(user) => user,
+        None => return HttpResponse::Unauthorized().finish(),
+    };
+    if let Err(response) = rate_limit(&req, &state, Some(&user.user_id), EMAIL_COST) {
+        return response;
+    }
+    match resend_verification(&state, &user).await {
+        Ok(()) => HttpResponse::Ok().json(json!({"message": "Verification email sent"})),
+        Err(ResendError::AlreadyVerified) => {
+            HttpResponse::BadRequest().json(json!({"error": "Your email is already verified"}))
+        }
+        Err(ResendError::TooSoon(retry_after)) => HttpResponse::TooManyRequests()
+            .append_header(("Retry-After", retry_after.to_string()))
+            .json(json!({"error": "Please wait before asking for another verification email"})),
+    }
+}
+
+#[derive(Deserialize)]
+struct UploadQuery {
+    room_id: String,
 }
 
+// Accepts a multipart form with a "file" field of any type, uploaded into a room the user belongs to
 #[post("/upload")]
-async fn upload(upload: web::Json<Image>) -> impl Responder {
-    let image_data = upload.into_inner();
-    let file_name = Uuid::new_v4().to_string().replace('-', "");
-    let image_filename = format!("/Images/{}.jpg", file_name);
-    let image_path = std::path::Path::new(&image_filename);
-    let mut image_file = std::fs::File::create(image_path).expect("Failed to create image file");
-    image_file.write_all(&image_data.data).expect("Failed to write image data to file");
-    HttpResponse::Ok()
+async fn upload(req: HttpRequest, mut payload: Multipart, query: web::Query<UploadQuery>, session: Session, state: web::Data<AppState>) -> impl Responder {
+    let user_id = match state.session_user(&session).await {
+        Some(user) => user.user_id,
+        None => return HttpResponse::Unauthorized().finish(),
+    };
+    if let Err(response) = rate_limit(&req, &state, Some(&user_id), UPLOAD_COST) {
+        return response;
+    }
+    match state.store.get_room(&query.room_id).await {
+        Ok(Some(room)) if room.users.contains(&user_id) => {}
+        Ok(_) => return HttpResponse::Forbidden().json(json!({"error": "You are not a member of this room"})),
+        Err(e) => {
+            log::error!("Failed to get room: fn upload, error: {:?}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+    let quota_left = match state.upload_quota_left(&user_id, &query.room_id).await {
+        Ok((user_left, room_left)) => user_left.min(room_left),
+        Err(e) => {
+            log::error!("Failed to get storage usage: fn upload, error: {:?}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+    let field = loop {
+        match payload.try_next().await {
+            Ok(Some(field)) if field.name() == Some("file") => break field,
+            Ok(Some(_)) => continue,
+            Ok(None) => return HttpResponse::BadRequest().json(json!({"error": "Missing file field"})),
+            Err(e) => return HttpResponse::BadRequest().json(json!({"error": e.to_string()})),
+        }
+    };
+    let filename = sanitize_filename(field.content_disposition().and_then(|disposition| disposition.get_filename()));
+    let blob = match state.uploads.save(field, &filename, quota_left).await {
+        Ok(blob) => blob,
+        Err(UploadError::TooLarge(max_size)) => return HttpResponse::PayloadTooLarge()
+            .json(json!({"error": format!("File is larger than {} bytes", max_size)})),
+        Err(UploadError::OverQuota) => return HttpResponse::PayloadTooLarge()
+            .json(json!({"error": format!("Storage quota exceeded, only {} more bytes can be uploaded to this room", quota_left)})),
+        Err(UploadError::InvalidImage) => return HttpResponse::BadRequest()
+            .json(json!({"error": "File looks like an image but could not be read"})),
+        Err(UploadError::Multipart(e)) => return HttpResponse::BadRequest().json(json!({"error": e.to_string()})),
+        Err(UploadError::Io(e)) => {
+            log::error!("Failed to store upload: fn upload, error: {:?}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+    let attachment = AttachmentMeta {
+        attachment_id: Uuid::new_v4().to_string().replace('-', ""),
+        sha256: blob.sha256.clone(),
+        filename,
+        content_type: blob.content_type.clone(),
+        size: blob.size,
+        uploader_id: user_id,
+        room_id: query.into_inner().room_id,
+        created_at: Utc::now().timestamp() as u64,
+    };
+    match state.add_attachment(&attachment, blob).await {
+        Ok(true) => HttpResponse::Ok().json(AttachmentRef::new(&attachment)),
+        Ok(false) => HttpResponse::PayloadTooLarge().json(json!({"error": "Storage quota exceeded"})),
+        Err(e) => {
+            log::error!("Failed to create attachment: fn upload, error: {:?}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
 }
 
 #[post("/change_username")]
-async fn change_username(username_change: web::Json<UserMessage>, session: Session, state: web::Data<AppState>) -> impl Responder {
+async fn change_username(req: HttpRequest, username_change: web::Json<UserMessage>, session: Session, state: web::Data<AppState>) -> impl Responder {
     let arc_state: Arc<AppState> = state.clone().into_inner();
     if let UserMessage::UsernameChange(message) = username_change.into_inner() { 
-        let user_id = match session.get::<String>("key") {
-            Ok(Some(id)) => id,
-            _ => return HttpResponse::BadRequest().json(json!({"error": "Failed to get user_id from session"})),
+        let user_id = match state.session_user(&session).await {
+            Some(user) => user.user_id,
+            None => return HttpResponse::BadRequest().json(json!({"error": "Failed to get user_id from session"})),
         };
-        let query = "SELECT * FROM users WHERE user_id = $user_id;";
-        if let Ok(mut response) = state.db.query(query).bind(("user_id", user_id.clone())).await{
-            let user_query: Option<UserData> = match response.take(0) {
-                Ok(data) => data,
-                Err(e) => {
-                    log::error!("Failed to get user data: fn change_username, error: {:?}", e);
-                    return HttpResponse::BadRequest().json(json!({"error": "Failed to get user_id from session"}));
-                }
-            };
-            
-            let user_data = user_query.unwrap();
-            match check_and_update_username(user_id, user_data.username, message.new_username.clone(), arc_state, UserMessage::UsernameChange(message))
-                .await {
-                Ok(response) => response,
-                Err(e) => HttpResponse::InternalServerError().json(json!({"error": e.to_string()})),
-            }
-        } else {
-            HttpResponse::BadRequest().json(json!({"error": "Database Error"}))
+        if let Err(response) = rate_limit(&req, &state, Some(&user_id), ACCOUNT_CHANGE_COST) {
+            return response;
+        }
+
+        match check_and_update_username(user_id, message.new_username.clone(), arc_state, UserMessage::UsernameChange(message))
+            .await {
+            Ok(response) => response,
+            Err(e) => HttpResponse::InternalServerError().json(json!({"error": e.to_string()})),
         }
     } else {
         HttpResponse::BadRequest().json(json!({"error": "Invalid message format for username change."}))
     }
 }
 
-#[get("/get-image/{filename}")]
-async fn get_image(path: web::Path<(String,)>) -> impl Responder {
-    let filename = &path.0;
-    let image_path = format!("./Images/{}", filename);
-    match std::fs::read(image_path) {
-        Ok(data) => HttpResponse::Ok().content_type("image/jpeg").body(data),
-        Err(_) => HttpResponse::NotFound().finish(),
+// Lists the uploads of the logged in user and how much of their quota they use
+#[get("/attachments")]
+async fn list_attachments(session: Session, state: web::Data<AppState>) -> impl Responder {
+    let user_id = match state.session_user(&session).await {
+        Some(user) => user.user_id,
+        None => return HttpResponse::Unauthorized().finish(),
+    };
+    let attachments = match state.store.user_attachments(&user_id).await {
+        Ok(found) => found,
+        Err(e) => {
+            log::error!("Failed to get attachments: fn list_attachments, error: {:?}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+    let used: u64 = attachments.iter().map(|attachment| attachment.size).sum();
+    let listed: Vec<_> = attachments.iter().map(|attachment| json!({
+        "attachment_id": attachment.attachment_id,
+        "url": attachment.url(),
+        "filename": attachment.filename,
+        "content_type": attachment.content_type,
+        "size": attachment.size,
+        "room_id": attachment.room_id,
+        "created_at": attachment.created_at,
+    })).collect();
+    HttpResponse::Ok().json(json!({"attachments": listed, "used": used, "quota": state.uploads.user_quota}))
+}
+
+// Uploaders can delete their own attachments; messages referring to them keep a dead link
+#[delete("/attachments/{attachment_id}")]
+async fn delete_attachment(path: web::Path<String>, session: Session, state: web::Data<AppState>) -> impl Responder {
+    let user_id = match state.session_user(&session).await {
+        Some(user) => user.user_id,
+        None => return HttpResponse::Unauthorized().finish(),
+    };
+    let attachment = match state.store.get_attachment(&path.into_inner()).await {
+        Ok(Some(attachment)) if attachment.uploader_id == user_id => attachment,
+        Ok(_) => return HttpResponse::NotFound().finish(),
+        Err(e) => {
+            log::error!("Failed to get attachment: fn delete_attachment, error: {:?}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+    match state.delete_attachment(&attachment).await {
+        Ok(()) => HttpResponse::NoContent().finish(),
+        Err(e) => {
+            log::error!("Failed to delete attachment: fn delete_attachment, error: {:?}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+// Attachments are only visible to members of the room they were uploaded to
+async fn find_attachment(session: &Session, state: &AppState, attachment_id: &str) -> Result<AttachmentMeta, HttpResponse> {
+    let user_id = match state.session_user(session).await {
+        Some(user) => user.user_id,
+        None => return Err(HttpResponse::Unauthorized().finish()),
+    };
+    let attachment = match state.store.get_attachment(attachment_id).await {
+        Ok(Some(attachment)) => attachment,
+        Ok(None) => return Err(HttpResponse::NotFound().finish()),
+        Err(e) => {
+            log::error!("Failed to get attachment: fn find_attachment, error: {:?}", e);
+            return Err(HttpResponse::InternalServerError().finish());
+        }
+    };
+    match state.store.get_room(&attachment.room_id).await {
+        Ok(Some(room)) if room.users.contains(&user_id) => Ok(attachment),
+        Ok(_) => Err(HttpResponse::NotFound().finish()),
+        Err(e) => {
+            log::error!("Failed to get room: fn find_attachment, error: {:?}", e);
+            Err(HttpResponse::InternalServerError().finish())
+        }
+    }
+}
+
+// NamedFile answers Range, If-None-Match and If-Range requests. Blobs never
+// change after they are written, so its ETag stays valid.
+async fn serve_file(req: &HttpRequest, path: Option<std::path::PathBuf>, content_type: &str, disposition: ContentDisposition) -> HttpResponse {
+    let Some(path) = path else {
+        return HttpResponse::NotFound().finish();
+    };
+    let file = match NamedFile::open_async(path).await {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return HttpResponse::NotFound().finish(),
+        Err(e) => {
+            log::error!("Failed to open file: fn serve_file, error: {:?}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+    let mime = content_type.parse().unwrap_or(mime_guess::mime::APPLICATION_OCTET_STREAM);
+    let mut response = file
+        .set_content_type(mime)
+        .set_content_disposition(disposition)
+        .into_response(req);
+    response.headers_mut().insert(header::X_CONTENT_TYPE_OPTIONS, header::HeaderValue::from_static("nosniff"));
+    response
+}
+
+// Only sniffed images are shown inline; everything else downloads under its original name
+fn attachment_disposition(attachment: &AttachmentMeta) -> ContentDisposition {
+    let disposition = match ImageKind::from_content_type(&attachment.content_type) {
+        Some(_) => DispositionType::Inline,
+        None => DispositionType::Attachment,
+    };
+    let mut parameters = vec![DispositionParam::Filename(attachment.filename.clone())];
+    if !attachment.filename.is_ascii() {
+        parameters.push(DispositionParam::FilenameExt(ExtendedValue {
+            charset: Charset::Ext("UTF-8".to_string()),
+            language_tag: None,
+            value: attachment.filename.clone().into_bytes(),
+        }));
     }
+    ContentDisposition { disposition, parameters }
+}
+
+#[get("/attachments/{attachment_id}")]
+async fn get_attachment(req: HttpRequest, path: web::Path<String>, session: Session, state: web::Data<AppState>) -> impl Responder {
+    let attachment = match find_attachment(&session, &state, &path.into_inner()).await {
+        Ok(attachment) => attachment,
+        Err(response) => return response,
+    };
+    let blob_path = state.uploads.blob_path(&attachment.sha256);
+    serve_file(&req, blob_path, &attachment.content_type, attachment_disposition(&attachment)).await
+}
+
+#[get("/attachments/{attachment_id}/thumbnail")]
+async fn get_thumbnail(req: HttpRequest, path: web::Path<String>, session: Session, state: web::Data<AppState>) -> impl Responder {
+    let attachment = match find_attachment(&session, &state, &path.into_inner()).await {
+        Ok(attachment) => attachment,
+        Err(response) => return response,
+    };
+    let Some(kind) = ImageKind::from_content_type(&attachment.content_type) else {
+        return HttpResponse::NotFound().finish();
+    };
+    let thumbnail_path = state.uploads.thumbnail_path(&attachment.sha256);
+    let disposition = ContentDisposition { disposition: DispositionType::Inline, parameters: Vec::new() };
+    serve_file(&req, thumbnail_path, kind.thumbnail_kind().content_type(), disposition).await
 }
 
 #[get("/get-ip")]
@@ -193,9 +743,8 @@ async fn get_ip() -> impl Responder {
 }
 
 #[get("/")]
-async fn home_page(session: Session) -> impl Responder {
-    let val: Option<String> = session.get("key").unwrap();
-    match val {
+async fn home_page(session: Session, state: web::Data<AppState>) -> impl Responder {
+    match state.session_user(&session).await {
         Some(_) => {
             let path = "static/HTML/home_page.html";
             match read_to_string(path) {
@@ -214,69 +763,132 @@ async fn home_page(session: Session) -> impl Responder {
 async fn main() -> std::io::Result<()> {
     env_logger::init();
 
-    let db = match Surreal::new::<Ws>("localhost:8000").await {
-        Ok(connected) => connected,
-        Err(e) => {log::error!("Failed to connect to database: fn main, error: {:?}", e);
-        return Ok(())}
-    };
-    match db.signin(Root {
-        username: "root",
-        password: "root",
-    }).await {
-        Ok(connected) => connected,
-        Err(e) => {log::error!("Failed to login to database: fn main, error: {:?}", e);
-        return Ok(())}
-    };
-    match db.use_ns("general").use_db("all")
-        .await {
-        Ok(connected) => connected,
-        Err(e) => {log::error!("Failed use namespace of database: fn main, error: {:?}", e);
-        return Ok(())}
-    };
-
-    let hashed_password = match hash("password", DEFAULT_COST) {
-        Ok(hashed) => hashed,
-        Err(_) => return Ok(())
-    };
-
-    let main_room_id = Uuid::new_v4().to_string().replace('-', "");
-    let user_id = Uuid::new_v4().to_string().replace('-', "");
-
-    // Create test user
-    let _: Option<UserData> = match db.create(("users", "test"))
-        .content(UserData {
-            user_id: user_id.clone(),
-            login_username: "test@gmail.com".to_string(),
-            username: "test".to_string(),
-            hashed_password,
-            status: ConnectionState::Online,
-            rooms: vec![main_room_id.clone()],
-        }).await {
-            Ok(created) => created,
-            Err(e) => {log::error!("Failed to create test user data: fn main, error: {:?}", e);
-            return Ok(())}
-        };
+    let config = match Config::load() {
+        Ok(loaded) => loaded,
+        Err(e) => {log::error!("Invalid configuration: {:#}", e);
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("{:#}", e)))}
+    };
+    let secret_key = match config.session_key() {
+        Ok(key) => key,
+        Err(e) => {log::error!("Failed to load session key: {:#}", e);
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("{:#}", e)))}
+    };
 
-    let mut users = HashSet::new();
-    users.insert(user_id);
-
-    let _ : Vec<Room> = match db.create("rooms")
-        .content(Room {
-            name: "main".to_string(),
-            room_id: main_room_id.clone(),
-            users,
-        })
-        .await {
-            Ok(created) => created,
-            Err(e) => {log::error!("Failed to create room data: fn main, error: {:?}", e);
-            return Ok(())}
-        };
+    let store: Arc<dyn ChatStore> = match config.store.backend {
+        StoreBackend::Memory => Arc::new(MemoryStore::new()),
+        StoreBackend::Surreal => {
+            let surreal = &config.surreal;
+            match SurrealStore::connect(&surreal.address, &surreal.username, &surreal.password, &surreal.namespace, &surreal.database).await {
+                Ok(connected) => Arc::new(connected),
+                Err(e) => {log::error!("Failed to connect to database: fn main, error: {:?}", e);
+                return Ok(())}
+            }
+        }
+    };
+
+    let uploads = match UploadStore::new(&config.uploads) {
+        Ok(uploads) => uploads,
+        Err(e) => {log::error!("Failed to set up uploads: {:#}", e);
+        return Err(std::io::Error::other(format!("{:#}", e)))}
+    };
+
+    let email = match email::from_config(&config.email) {
+        Ok(sender) => sender,
+        Err(e) => {log::error!("Failed to set up email: {:#}", e);
+        return Err(std::io::Error::other(format!("{:#}", e)))}
+    };
+
+    // Created on first boot only, so restarts keep users and sessions in the same main room
+    let user_id = match store.get_user_by_login(TEST_LOGIN).await {
+        Ok(Some(user)) => user.user_id,
+        Ok(None) => {
+            let hashed_password = match hash("password", DEFAULT_COST) {
+                Ok(hashed) => hashed,
+                Err(_) => return Ok(())
+            };
+            if let Err(e) = store.create_user(UserData {
+                    user_id: TEST_USER_ID.to_string(),
+                    login_username: TEST_LOGIN.to_string(),
+                    username: "test".to_string(),
+                    hashed_password,
+                    status: ConnectionState::Offline,
+                    rooms: Vec::new(),
+                    last_seen: None,
+                    session_epoch: 0,
+                    email_verified: true,
+                    two_factor: None,
+                    pending_totp_secret: None,
+                }).await {
+                log::error!("Failed to create test user data: fn main, error: {:?}", e);
+                return Ok(())
+            }
+            TEST_USER_ID.to_string()
+        }
+        Err(e) => {
+            log::error!("Failed to get test user data: fn main, error: {:?}", e);
+            return Ok(())
+        }
+    };
+
+    match store.get_room(MAIN_ROOM_ID).await {
+        Ok(Some(_)) => {}
+        Ok(None) => {
+            if let Err(e) = store.create_room(Room {
+                    name: "main".to_string(),
+                    room_id: MAIN_ROOM_ID.to_string(),
+                    users: HashSet::from([user_id]),
+                    ..Room::default()
+                }).await {
+                log::error!("Failed to create room data: fn main, error: {:?}", e);
+                return Ok(())
+            }
+        }
+        Err(e) => {
+            log::error!("Failed to get room data: fn main, error: {:?}", e);
+            return Ok(())
+        }
+    }
 
     let app_state = web::Data::new(AppState {
-        db: Arc::new(db),
-        channels: Arc::new(Mutex::new(HashMap::new())),
-        main_room_id,
+        store,
+        main_room_id: MAIN_ROOM_ID.to_string(),
         actor_registry: Arc::new(Mutex::new(HashMap::new())),
+        presence: PresenceTracker::new(),
+        uploads,
+        attachment_lock: Default::default(),
+        email,
+        config: config.clone(),
+        signing_key: secret_key.clone(),
+        verification_sent: Mutex::new(HashMap::new()),
+        login_guard: LoginGuard::new(&config.auth),
+        rate_limiter: RateLimiter::new(&config.rate_limit),
+        sent_messages: SentMessages::new(&config.messages),
+        resume: ResumeLog::new(&config.session),
+    });
+
+    let sweeper_state = app_state.clone();
+    let sweep_interval = Duration::from_secs(config.uploads.sweep_interval_secs);
+    let unreferenced_grace = Duration::from_secs(config.uploads.unreferenced_grace_secs);
+    actix_web::rt::spawn(async move {
+        let mut interval = actix_web::rt::time::interval(sweep_interval);
+        loop {
+            interval.tick().await;
+            sweeper_state.sweep_attachments(unreferenced_grace).await;
+        }
+    });
+
+    let housekeeping_state = app_state.clone();
+    let housekeeping_interval = Duration::from_secs(config.server.housekeeping_interval_secs);
+    actix_web::rt::spawn(async move {
+        let mut interval = actix_web::rt::time::interval(housekeeping_interval);
+        loop {
+            interval.tick().await;
+            let now = Utc::now().timestamp() as u64;
+            housekeeping_state.expire_sessions().await;
+            housekeeping_state.rate_limiter.forget_full();
+            housekeeping_state.sent_messages.forget_expired(now);
+            housekeeping_state.resume.forget_expired(now);
+        }
     });
 
     let my_local_ip = local_ip();
@@ -284,19 +896,21 @@ async fn main() -> std::io::Result<()> {
 
     if let Ok(my_local_ip) = my_local_ip {
         address = my_local_ip;
-        println!("Go to {}:8080", address);
+        println!("Go to {}:{}", address, config.server.port);
     } else {
         return Ok(())
     }
 
-    let secret_key = Key::generate();
+    let redis_address = config.redis.address.clone();
+    let session_ttl = actix_web::cookie::time::Duration::seconds(config.session.ttl_secs as i64);
 
     HttpServer::new(move || {
         App::new()
-            .wrap(SessionMiddleware::new(
-                RedisActorSessionStore::new("127.0.0.1:6379"),
-                secret_key.clone(),
-            ))
+            .wrap(
+                SessionMiddleware::builder(RedisActorSessionStore::new(redis_address.clone()), secret_key.clone())
+                    .session_lifecycle(BrowserSession::default().state_ttl(session_ttl))
+                    .build(),
+            )
             .app_data(app_state.clone())
             .service(home_page)
             .service(login_page)
@@ -304,12 +918,31 @@ async fn main() -> std::io::Result<()> {
             .service(create_login_page)
             .service(create_login_action)
             .service(logout)
+            .service(password_reset_page)
+            .service(forgot_password)
+            .service(reset_password_action)
+            .service(verify_email_link)
+            .service(resend_verification_action)
+            .service(login_two_factor)
+            .service(enroll_two_factor)
+            .service(confirm_two_factor)
+            .service(disable_two_factor)
+            .service(list_lockouts)
+            .service(lift_lockout)
+            .service(list_sessions)
+            .service(revoke_session)
+            .service(revoke_other_sessions)
             .service(change_username)
             .service(get_ip)
+            .service(upload)
+            .service(list_attachments)
+            .service(get_attachment)
+            .service(delete_attachment)
+            .service(get_thumbnail)
             .route("/ws/", web::get().to(ws_index))
             .service(actix_files::Files::new("/static", "static").show_files_listing())
     })
-    .bind(("127.0.0.1", 8080))?
+    .bind((config.server.host.as_str(), config.server.port))?
     .run()
     .await
 }
\ No newline at end of file
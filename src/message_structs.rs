@@ -1,5 +1,6 @@
 use std::collections::HashMap;
 use serde::{Serialize, Deserialize};
+use crate::structs::{AttachmentMeta, ConnectionState, RoomRole};
 
 // UserInfo Struct
 #[derive(Serialize, Deserialize, Clone)]
@@ -7,11 +8,12 @@ pub struct UserInfo {
     pub user_id: String,
     pub ws_id: String,
     pub username: String,
+    pub resume_token: String,
 }
 
 impl UserInfo {
-    pub fn new(user_id: String, ws_id: String, username: String) -> Self {
-        UserInfo { user_id, ws_id, username }
+    pub fn new(user_id: String, ws_id: String, username: String, resume_token: String) -> Self {
+        UserInfo { user_id, ws_id, username, resume_token }
     }
 }
 
@@ -22,11 +24,24 @@ pub struct InitMessage {
     pub ws_id: String,
     pub username: String,
     pub user_map: HashMap<String, String>,
+    pub presence: HashMap<String, ConnectionState>,
+    // room_id -> name, direct conversations excluded
+    pub rooms: HashMap<String, String>,
+    pub direct_rooms: Vec<DirectRoomMessage>,
+    // Reconnect to /ws/?resume_token=..&last_seqs=room_id:seq,.. to only get what was missed
+    pub resume_token: String,
 }
 
 impl InitMessage {
-    pub fn new(user_id: String, ws_id: String, username: String, user_map: HashMap<String, String>) -> Self {
-        InitMessage { user_id, ws_id, username, user_map }
+    pub fn new(
+        user_info: UserInfo,
+        user_map: HashMap<String, String>,
+        presence: HashMap<String, ConnectionState>,
+        rooms: HashMap<String, String>,
+        direct_rooms: Vec<DirectRoomMessage>,
+    ) -> Self {
+        let UserInfo { user_id, ws_id, username, resume_token } = user_info;
+        InitMessage { user_id, ws_id, username, user_map, presence, rooms, direct_rooms, resume_token }
     }
 }
 
@@ -40,12 +55,35 @@ pub enum UserMessage {
     Typing(TypingMessage),
     UserRemoval(UserRemovalMessage),
     UserAddition(UserAdditionMessage),
+    Invite(InviteMessage),
+    RenameRoom(RenameRoomMessage),
+    SetRole(SetRoleMessage),
+    Kick(KickMessage),
+    Ban(BanMessage),
+    Unban(ModerationTargetMessage),
+    Mute(MuteMessage),
+    Unmute(ModerationTargetMessage),
+    Moderation(ModerationMessage),
     NewUser(NewUserMessage),
     ChangeRoom(ChangeRoomMessage),
     UsernameChange(UsernameChangeMessage),
     CreateRoomChange(CreateRoomChangeMessage),
     Initialization(InitMessage),
-    Deletion(DeletionMessage)
+    Deletion(DeletionMessage),
+    StartDirect(StartDirectMessage),
+    DirectRoom(DirectRoomMessage),
+    SetPresence(SetPresenceMessage),
+    Presence(PresenceMessage),
+    Edit(EditMessage),
+    Edited(EditedMessage),
+    RevisionsRequest(RevisionsRequestMessage),
+    Revisions(RevisionsMessage),
+    HistoryRequest(HistoryRequestMessage),
+    History(HistoryMessage),
+    Error(ErrorMessage),
+    Ack(AckMessage),
+    RateLimited(RateLimitedMessage),
+    Resumed(ResumedMessage),
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -63,18 +101,224 @@ pub struct BasicMessage {
     pub message_id: String,
     pub room_id: String,
     pub ws_id: String,
+    // Position in the room, counting up from 1 without gaps; assigned when the message is stored.
+    // Messages stored before rooms numbered them have 0.
+    #[serde(default)]
+    pub seq: u64,
+    #[serde(default)]
+    pub edited_at: Option<u64>,
+    // Set when the message has been deleted; content is cleared but the entry stays in history
+    #[serde(default)]
+    pub deleted_at: Option<u64>,
+    // Present on image messages, where content holds the caption
+    #[serde(default)]
+    pub image: Option<ImageAttachment>,
+    #[serde(default)]
+    pub attachments: Vec<AttachmentRef>,
+}
+
+// StartDirectMessage Struct
+#[derive(Serialize, Deserialize, Clone)]
+pub struct StartDirectMessage {
+    pub user_id: String,
+}
+
+// DirectRoomMessage Struct
+// user_id and username describe the other participant
+#[derive(Serialize, Deserialize, Clone)]
+pub struct DirectRoomMessage {
+    pub room_id: String,
+    pub user_id: String,
+    pub username: String,
+}
+
+impl DirectRoomMessage {
+    pub fn new(room_id: String, user_id: String, username: String) -> Self {
+        DirectRoomMessage { room_id, user_id, username }
+    }
+}
+
+// KickMessage Struct
+#[derive(Serialize, Deserialize, Clone)]
+pub struct KickMessage {
+    pub room_id: String,
+    pub user_id: String,
+    pub reason: Option<String>,
+}
+
+// BanMessage Struct
+// Without duration_secs the ban lasts until lifted with Unban; at most ten years otherwise
+#[derive(Serialize, Deserialize, Clone)]
+pub struct BanMessage {
+    pub room_id: String,
+    pub user_id: String,
+    pub reason: Option<String>,
+    pub duration_secs: Option<u64>,
+}
+
+// MuteMessage Struct
+#[derive(Serialize, Deserialize, Clone)]
+pub struct MuteMessage {
+    pub room_id: String,
+    pub user_id: String,
+    pub duration_secs: Option<u64>,
+}
+
+// ModerationTargetMessage Struct
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ModerationTargetMessage {
+    pub room_id: String,
+    pub user_id: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ModerationAction {
+    Kick,
+    Ban,
+    Unban,
+    Mute,
+    Unmute,
+}
+
+// ModerationMessage Struct
+// Broadcast to the room, and to the target, after a moderation action
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ModerationMessage {
+    pub room_id: String,
+    pub user_id: String,
+    pub action: ModerationAction,
+    pub reason: Option<String>,
+    pub expires_at: Option<u64>,
+    pub sender_id: String,
+}
+
+// SetPresenceMessage Struct
+// Online hands status back to activity tracking; Offline cannot be chosen
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SetPresenceMessage {
+    pub status: ConnectionState,
+}
+
+// PresenceMessage Struct
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PresenceMessage {
+    pub user_id: String,
+    pub status: ConnectionState,
+    pub last_seen: Option<u64>,
+}
+
+// EditMessage Struct
+#[derive(Serialize, Deserialize, Clone)]
+pub struct EditMessage {
+    pub message_id: String,
+    pub content: String,
+}
+
+// EditedMessage Struct
+#[derive(Serialize, Deserialize, Clone)]
+pub struct EditedMessage {
+    pub message_id: String,
+    pub room_id: String,
+    pub sender_id: String,
+    pub content: String,
+    pub edited_at: u64,
+}
+
+// MessageRevision Struct
+// content a message held from timestamp until it was next edited
+#[derive(Serialize, Deserialize, Clone)]
+pub struct MessageRevision {
+    pub message_id: String,
+    pub content: String,
+    pub timestamp: u64,
+}
+
+// RevisionsRequestMessage Struct
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RevisionsRequestMessage {
+    pub message_id: String,
+}
+
+// RevisionsMessage Struct
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RevisionsMessage {
+    pub message_id: String,
+    pub revisions: Vec<MessageRevision>,
+}
+
+impl RevisionsMessage {
+    pub fn new(message_id: String, revisions: Vec<MessageRevision>) -> Self {
+        RevisionsMessage { message_id, revisions }
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct TSBasicMessage {
     pub content: String,
+    // Ids returned by /upload for files uploaded to the current room
+    #[serde(default)]
+    pub attachments: Vec<String>,
+    // Chosen by the client and reused when it resends, so the message is posted only once
+    #[serde(default)]
+    pub idempotency_key: Option<String>,
 }
 
 // ImageMessage Struct
+// Posts an image uploaded through /upload to the current room; it comes back as a Basic message
 #[derive(Serialize, Deserialize, Clone)]
 pub struct ImageMessage {
-    pub image_url: String,
-    pub sender_id: String,
+    pub attachment_id: String,
+    #[serde(default)]
+    pub caption: String,
+    #[serde(default)]
+    pub idempotency_key: Option<String>,
+}
+
+// ImageAttachment Struct
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ImageAttachment {
+    pub attachment_id: String,
+    pub url: String,
+    pub thumbnail_url: String,
+    pub content_type: String,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl ImageAttachment {
+    pub fn new(attachment: &AttachmentMeta, width: u32, height: u32) -> Self {
+        ImageAttachment {
+            attachment_id: attachment.attachment_id.clone(),
+            url: attachment.url(),
+            thumbnail_url: attachment.thumbnail_url(),
+            content_type: attachment.content_type.clone(),
+            width,
+            height,
+        }
+    }
+}
+
+// AttachmentRef Struct
+// A file attached to a message; also the response body of /upload
+#[derive(Serialize, Deserialize, Clone)]
+pub struct AttachmentRef {
+    pub attachment_id: String,
+    pub url: String,
+    pub filename: String,
+    pub content_type: String,
+    pub size: u64,
+}
+
+impl AttachmentRef {
+    pub fn new(attachment: &AttachmentMeta) -> Self {
+        AttachmentRef {
+            attachment_id: attachment.attachment_id.clone(),
+            url: attachment.url(),
+            filename: attachment.filename.clone(),
+            content_type: attachment.content_type.clone(),
+            size: attachment.size,
+        }
+    }
 }
 
 // NotificationMessage Struct
@@ -84,9 +328,14 @@ pub struct NotificationMessage {
 }
 
 // TypingMessage Struct
+// sender_id and room_id are filled in by the server
 #[derive(Serialize, Deserialize, Clone)]
 pub struct TypingMessage {
+    #[serde(default)]
     pub sender_id: String,
+    #[serde(default)]
+    pub room_id: String,
+    pub typing: bool,
 }
 
 // UserRemovalMessage Struct
@@ -94,6 +343,7 @@ pub struct TypingMessage {
 pub struct UserRemovalMessage {
     pub removed_user: String,
     pub room_id: String,
+    #[serde(default)]
     pub sender_id: String,
 }
 
@@ -102,14 +352,44 @@ pub struct UserRemovalMessage {
 pub struct UserAdditionMessage {
     pub user_id: String,
     pub username: String,
+    #[serde(default)]
+    pub room_id: String,
 }
 
 impl UserAdditionMessage {
-    pub fn new(user_id: String, username: String) -> Self {
-        UserAdditionMessage { user_id, username }
+    pub fn new(user_id: String, username: String, room_id: String) -> Self {
+        UserAdditionMessage { user_id, username, room_id }
     }
 }
 
+// InviteMessage Struct
+#[derive(Serialize, Deserialize, Clone)]
+pub struct InviteMessage {
+    pub room_id: String,
+    pub user_id: String,
+}
+
+// RenameRoomMessage Struct
+// Sent by clients to rename and broadcast back with sender_id filled in
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RenameRoomMessage {
+    pub room_id: String,
+    pub name: String,
+    #[serde(default)]
+    pub sender_id: String,
+}
+
+// SetRoleMessage Struct
+// Sent by clients to promote or demote and broadcast back with sender_id filled in
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SetRoleMessage {
+    pub room_id: String,
+    pub user_id: String,
+    pub role: RoomRole,
+    #[serde(default)]
+    pub sender_id: String,
+}
+
 // NewUserMessage Struct
 #[derive(Serialize, Deserialize, Clone)]
 pub struct NewUserMessage {
@@ -137,12 +417,6 @@ pub struct UsernameChangeMessage {
     pub sender_id: String,
 }
 
-impl UsernameChangeMessage {
-    pub fn new(sender_id: String, new_username: String) -> Self {
-        UsernameChangeMessage { sender_id, new_username }
-    }
-}
-
 // CreateRoomChangeMessage Struct
 #[derive(Serialize, Deserialize, Clone)]
 pub struct CreateRoomChangeMessage {
@@ -150,12 +424,101 @@ pub struct CreateRoomChangeMessage {
     pub sender_id: String,
 }
 
-impl CreateRoomChangeMessage {
-    pub fn new(sender_id: String, room_name: String) -> Self {
-        CreateRoomChangeMessage { sender_id, room_name }
+// HistoryRequestMessage Struct
+// before/after are cursors from a previous History reply; with neither set the latest page is returned.
+// from_seq and to_seq instead ask for that range of seq, e.g. to fill a gap the client noticed.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct HistoryRequestMessage {
+    pub room_id: String,
+    pub before: Option<String>,
+    pub after: Option<String>,
+    pub from_seq: Option<u64>,
+    pub to_seq: Option<u64>,
+    pub limit: Option<usize>,
+}
+
+// HistoryMessage Struct
+#[derive(Serialize, Deserialize, Clone)]
+pub struct HistoryMessage {
+    pub room_id: String,
+    pub messages: Vec<BasicMessage>,
+    pub next_cursor: Option<String>,
+}
+
+impl HistoryMessage {
+    pub fn new(room_id: String, messages: Vec<BasicMessage>, next_cursor: Option<String>) -> Self {
+        HistoryMessage { room_id, messages, next_cursor }
     }
 }
 
+// Clients may put a client_msg_id in any request, e.g. {"TSBasic": {"content": "hi", "client_msg_id": "1"}},
+// and get it back on the Error, Ack or RateLimited frame answering that request.
+
+// Stable reasons a request failed, for clients to act on; message is only for display
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorCode {
+    // Malformed frame, or a field with a value the request cannot take
+    InvalidRequest,
+    NotFound,
+    NotMember,
+    // The user's role or ownership does not allow the request
+    Forbidden,
+    Muted,
+    Banned,
+    EmailNotVerified,
+    AlreadyMember,
+    // A send with the same idempotency key has not finished yet
+    InProgress,
+    // Failed on the server side; the same request may succeed if retried
+    Internal,
+}
+
+// ErrorMessage Struct
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ErrorMessage {
+    #[serde(default)]
+    pub client_msg_id: Option<String>,
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl ErrorMessage {
+    pub fn new(client_msg_id: Option<String>, code: ErrorCode, message: String) -> Self {
+        ErrorMessage { client_msg_id, code, message }
+    }
+}
+
+// AckMessage Struct
+// Confirms a request that carried a client_msg_id succeeded. message_id is the message
+// TSBasic, Image, Edit, Deletion or RevisionsRequest was about, with its timestamp;
+// requests about a room (including the one CreateRoomChange made) give the room_id,
+// and SetPresence the user's own id, with the time they were handled.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct AckMessage {
+    pub client_msg_id: Option<String>,
+    pub message_id: String,
+    pub timestamp: u64,
+}
+
+// RateLimitedMessage Struct
+// Sent instead of handling a frame the user has no tokens left for
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RateLimitedMessage {
+    #[serde(default)]
+    pub client_msg_id: Option<String>,
+    pub retry_after_ms: u64,
+}
+
+// ResumedMessage Struct
+// Sent instead of Initialization when a connection was resumed; the missed
+// messages follow as History frames, then the other missed events
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ResumedMessage {
+    pub ws_id: String,
+    pub room_id: String,
+    pub resume_token: String,
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct LoginErrorMessage {
     pub message: String,
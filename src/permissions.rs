@@ -0,0 +1,158 @@
+use crate::structs::{Room, RoomRole};
+
+// Things a member can try to do to a room or to another member of it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RoomAction {
+    Invite,
+    RemoveMember,
+    DeleteMessage,
+    Rename,
+    ManageRoles,
+    Kick,
+    Ban,
+    Mute,
+}
+
+impl RoomAction {
+    fn required_role(self) -> RoomRole {
+        match self {
+            RoomAction::Invite => RoomRole::Moderator,
+            RoomAction::RemoveMember => RoomRole::Moderator,
+            RoomAction::DeleteMessage => RoomRole::Moderator,
+            RoomAction::Rename => RoomRole::Moderator,
+            RoomAction::ManageRoles => RoomRole::Owner,
+            RoomAction::Kick => RoomRole::Moderator,
+            RoomAction::Ban => RoomRole::Moderator,
+            RoomAction::Mute => RoomRole::Moderator,
+        }
+    }
+}
+
+impl Room {
+    // None when user_id is not a member
+    pub fn role_of(&self, user_id: &str) -> Option<RoomRole> {
+        if !self.users.contains(user_id) {
+            return None;
+        }
+        Some(self.roles.get(user_id).copied().unwrap_or(RoomRole::Member))
+    }
+
+    pub fn can(&self, user_id: &str, action: RoomAction) -> bool {
+        if self.direct && action != RoomAction::DeleteMessage {
+            return false;
+        }
+        match self.role_of(user_id) {
+            Some(role) => role >= action.required_role(),
+            None => false,
+        }
+    }
+
+    // Actions aimed at another member also need a strictly higher role than the target
+    pub fn can_act_on(&self, user_id: &str, target_id: &str, action: RoomAction) -> bool {
+        if !self.can(user_id, action) {
+            return false;
+        }
+        match (self.role_of(user_id), self.role_of(target_id)) {
+            (Some(role), Some(target_role)) => role > target_role,
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::{HashMap, HashSet};
+
+    const ALL_ACTIONS: [RoomAction; 8] = [
+        RoomAction::Invite,
+        RoomAction::RemoveMember,
+        RoomAction::DeleteMessage,
+        RoomAction::Rename,
+        RoomAction::ManageRoles,
+        RoomAction::Kick,
+        RoomAction::Ban,
+        RoomAction::Mute,
+    ];
+
+    // An owner, a moderator and a plain member, who has no roles entry
+    fn room() -> Room {
+        Room {
+            room_id: "room".to_string(),
+            users: HashSet::from(["owner".to_string(), "moderator".to_string(), "member".to_string()]),
+            roles: HashMap::from([
+                ("owner".to_string(), RoomRole::Owner),
+                ("moderator".to_string(), RoomRole::Moderator),
+            ]),
+            ..Room::default()
+        }
+    }
+
+    #[test]
+    fn role_of_defaults_members_to_member() {
+        let room = room();
+        for (user_id, role) in [
+            ("owner", Some(RoomRole::Owner)),
+            ("moderator", Some(RoomRole::Moderator)),
+            ("member", Some(RoomRole::Member)),
+            ("stranger", None),
+        ] {
+            assert_eq!(room.role_of(user_id), role, "{}", user_id);
+        }
+    }
+
+    #[test]
+    fn can_needs_the_role_the_action_requires() {
+        let room = room();
+        for action in ALL_ACTIONS {
+            for (user_id, allowed) in [
+                ("owner", true),
+                ("moderator", action != RoomAction::ManageRoles),
+                ("member", false),
+                ("stranger", false),
+            ] {
+                assert_eq!(room.can(user_id, action), allowed, "{} {:?}", user_id, action);
+            }
+        }
+    }
+
+    #[test]
+    fn can_act_on_needs_a_higher_role_than_the_target() {
+        let room = room();
+        for (user_id, target_id, allowed) in [
+            ("owner", "moderator", true),
+            ("owner", "member", true),
+            ("owner", "owner", false),
+            ("moderator", "member", true),
+            ("moderator", "moderator", false),
+            ("moderator", "owner", false),
+            ("member", "member", false),
+            ("moderator", "stranger", false),
+            ("stranger", "member", false),
+        ] {
+            for action in [RoomAction::RemoveMember, RoomAction::Kick, RoomAction::Ban, RoomAction::Mute] {
+                assert_eq!(room.can_act_on(user_id, target_id, action), allowed, "{} on {} {:?}", user_id, target_id, action);
+            }
+        }
+    }
+
+    #[test]
+    fn can_act_on_still_needs_the_action_role() {
+        let room = room();
+        // Outranking the target is not enough without the Owner role ManageRoles requires
+        assert!(!room.can_act_on("moderator", "member", RoomAction::ManageRoles));
+        assert!(room.can_act_on("owner", "moderator", RoomAction::ManageRoles));
+    }
+
+    #[test]
+    fn direct_rooms_only_allow_deleting_messages() {
+        let mut room = Room::direct_between("alice", "bob");
+        room.roles.insert("alice".to_string(), RoomRole::Owner);
+        for action in ALL_ACTIONS {
+            let allowed = action == RoomAction::DeleteMessage;
+            assert_eq!(room.can("alice", action), allowed, "{:?}", action);
+            assert_eq!(room.can_act_on("alice", "bob", action), allowed, "{:?}", action);
+            assert!(!room.can("carol", action), "{:?}", action);
+        }
+    }
+}
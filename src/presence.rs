@@ -0,0 +1,91 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use crate::structs::ConnectionState;
+
+// A connection with no client frames for this long counts as idle
+pub const AWAY_TIMEOUT: Duration = Duration::from_secs(300);
+
+#[derive(Default)]
+struct UserPresence {
+    // ws_id -> last time the connection sent a frame
+    connections: HashMap<String, Instant>,
+    // Status picked by the user, None means Online/Away follow activity
+    chosen: Option<ConnectionState>,
+    status: ConnectionState,
+}
+
+impl UserPresence {
+    fn compute(&self, now: Instant) -> ConnectionState {
+        if self.connections.is_empty() {
+            return ConnectionState::Offline;
+        }
+        match self.chosen {
+            Some(chosen) => chosen,
+            None if self.connections.values().all(|last_active| now.duration_since(*last_active) >= AWAY_TIMEOUT) => ConnectionState::Away,
+            None => ConnectionState::Online,
+        }
+    }
+}
+
+// Tracks every live connection per user and derives one status from all of them.
+// Each method returns the new status when it changed.
+#[derive(Default)]
+pub struct PresenceTracker {
+    users: Mutex<HashMap<String, UserPresence>>,
+}
+
+impl PresenceTracker {
+    pub fn new() -> Self {
+        PresenceTracker::default()
+    }
+
+    pub fn connect(&self, user_id: &str, ws_id: &str) -> Option<ConnectionState> {
+        self.update(user_id, |presence, now| {
+            presence.connections.insert(ws_id.to_string(), now);
+        })
+    }
+
+    pub fn disconnect(&self, user_id: &str, ws_id: &str) -> Option<ConnectionState> {
+        self.update(user_id, |presence, _| {
+            presence.connections.remove(ws_id);
+        })
+    }
+
+    pub fn touch(&self, user_id: &str, ws_id: &str) -> Option<ConnectionState> {
+        self.update(user_id, |presence, now| {
+            if let Some(last_active) = presence.connections.get_mut(ws_id) {
+                *last_active = now;
+            }
+        })
+    }
+
+    // Re-evaluates idle timeouts
+    pub fn refresh(&self, user_id: &str) -> Option<ConnectionState> {
+        self.update(user_id, |_, _| {})
+    }
+
+    pub fn choose(&self, user_id: &str, chosen: Option<ConnectionState>) -> Option<ConnectionState> {
+        self.update(user_id, |presence, _| {
+            presence.chosen = chosen;
+        })
+    }
+
+    pub fn status(&self, user_id: &str) -> ConnectionState {
+        let users = self.users.lock().unwrap();
+        users.get(user_id).map(|presence| presence.status).unwrap_or_default()
+    }
+
+    fn update(&self, user_id: &str, change: impl FnOnce(&mut UserPresence, Instant)) -> Option<ConnectionState> {
+        let now = Instant::now();
+        let mut users = self.users.lock().unwrap();
+        let presence = users.entry(user_id.to_string()).or_default();
+        change(presence, now);
+        let status = presence.compute(now);
+        if status == presence.status {
+            return None;
+        }
+        presence.status = status;
+        Some(status)
+    }
+}
@@ -0,0 +1,147 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use crate::config::RateLimitConfig;
+use crate::message_structs::UserMessage;
+
+// Frames that fail to parse still cost something, or garbage would be free to send
+pub const INVALID_FRAME_COST: u64 = 1;
+// HTTP routes
+pub const LOGIN_COST: u64 = 2;
+pub const SIGNUP_COST: u64 = 20;
+pub const UPLOAD_COST: u64 = 20;
+pub const EMAIL_COST: u64 = 10;
+pub const ACCOUNT_CHANGE_COST: u64 = 5;
+
+// Tokens a websocket frame takes, by what it asks the server to do
+pub fn message_cost(message: &UserMessage) -> u64 {
+    match message {
+        UserMessage::Typing(_) | UserMessage::SetPresence(_) | UserMessage::ChangeRoom(_) => 1,
+        UserMessage::TSBasic(_)
+        | UserMessage::Edit(_)
+        | UserMessage::Deletion(_)
+        | UserMessage::RevisionsRequest(_)
+        | UserMessage::HistoryRequest(_) => 2,
+        UserMessage::Invite(_)
+        | UserMessage::UserRemoval(_)
+        | UserMessage::RenameRoom(_)
+        | UserMessage::SetRole(_)
+        | UserMessage::Kick(_)
+        | UserMessage::Ban(_)
+        | UserMessage::Unban(_)
+        | UserMessage::Mute(_)
+        | UserMessage::Unmute(_) => 5,
+        // Decodes the image and may generate a thumbnail
+        UserMessage::Image(_) => 10,
+        UserMessage::StartDirect(_) => 10,
+        UserMessage::CreateRoomChange(_) => 20,
+        // Server to client types, ignored when a client sends them
+        _ => 1,
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum RateLimitKey {
+    User(String),
+    Ip(String),
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+// Token buckets shared by every connection and request of the same user or
+// IP, so opening more tabs does not add to anyone's budget
+pub struct RateLimiter {
+    buckets: Mutex<HashMap<RateLimitKey, Bucket>>,
+    capacity: f64,
+    refill_per_sec: f64,
+}
+
+impl RateLimiter {
+    pub fn new(config: &RateLimitConfig) -> Self {
+        RateLimiter {
+            buckets: Mutex::new(HashMap::new()),
+            capacity: config.capacity as f64,
+            refill_per_sec: config.refill_per_sec as f64,
+        }
+    }
+
+    fn refill(&self, bucket: &mut Bucket, now: Instant) {
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.refill_per_sec).min(self.capacity);
+        bucket.updated = now;
+    }
+
+    // Takes cost tokens from the bucket of every key, or from none of them when
+    // one runs short, returning how long until all of them could pay. A cost
+    // above capacity needs a full bucket.
+    pub fn acquire(&self, keys: &[RateLimitKey], cost: u64) -> Result<(), Duration> {
+        let cost = (cost as f64).min(self.capacity);
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        let mut wait_secs: f64 = 0.0;
+        for key in keys {
+            let bucket = buckets.entry(key.clone()).or_insert(Bucket { tokens: self.capacity, updated: now });
+            self.refill(bucket, now);
+            if bucket.tokens < cost {
+                wait_secs = wait_secs.max((cost - bucket.tokens) / self.refill_per_sec);
+            }
+        }
+        if wait_secs > 0.0 {
+            return Err(Duration::from_secs_f64(wait_secs));
+        }
+        for key in keys {
+            if let Some(bucket) = buckets.get_mut(key) {
+                bucket.tokens -= cost;
+            }
+        }
+        Ok(())
+    }
+
+    // Drops buckets that have refilled completely, they are the same as no bucket
+    pub fn forget_full(&self) {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        buckets.retain(|_, bucket| {
+            self.refill(bucket, now);
+            bucket.tokens < self.capacity
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(capacity: u64, refill_per_sec: u64) -> RateLimiter {
+        RateLimiter::new(&RateLimitConfig { capacity, refill_per_sec })
+    }
+
+    #[test]
+    fn acquire_until_empty() {
+        let limiter = limiter(3, 1);
+        let user = [RateLimitKey::User("u".to_string())];
+        assert!(limiter.acquire(&user, 2).is_ok());
+        let wait = limiter.acquire(&user, 2).unwrap_err();
+        assert!(wait > Duration::from_millis(500) && wait <= Duration::from_secs(1), "{:?}", wait);
+        assert!(limiter.acquire(&user, 1).is_ok());
+    }
+
+    #[test]
+    fn short_key_takes_nothing_from_the_others() {
+        let limiter = limiter(4, 1);
+        let user = RateLimitKey::User("u".to_string());
+        let ip = RateLimitKey::Ip("127.0.0.1".to_string());
+        assert!(limiter.acquire(std::slice::from_ref(&user), 4).is_ok());
+        assert!(limiter.acquire(&[user, ip.clone()], 1).is_err());
+        assert!(limiter.acquire(&[ip], 4).is_ok());
+    }
+
+    #[test]
+    fn cost_is_capped_at_capacity() {
+        let limiter = limiter(3, 1);
+        assert!(limiter.acquire(&[RateLimitKey::Ip("127.0.0.1".to_string())], 100).is_ok());
+    }
+}
@@ -0,0 +1,202 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Mutex;
+use crate::auth::generate_token;
+use crate::config::SessionConfig;
+
+// Most events kept per user; resuming from before a dropped event is refused
+const MAX_EVENTS_PER_USER: usize = 256;
+
+struct Ticket {
+    user_id: String,
+    session_id: String,
+    // The room the connection was in
+    room_id: String,
+    // None while the connection is open
+    expires_at_secs: Option<u64>,
+    // How many events the user's log had recorded when the connection closed
+    released_at: u64,
+}
+
+// A frame sent to a user about a room
+struct Event {
+    room_id: String,
+    frame: String,
+}
+
+#[derive(Default)]
+struct UserLog {
+    events: VecDeque<Event>,
+    // Events recorded so far, kept or dropped; the newest event is number recorded - 1
+    recorded: u64,
+}
+
+impl UserLog {
+    // Number of the oldest event still kept
+    fn first_kept(&self) -> u64 {
+        self.recorded - self.events.len() as u64
+    }
+}
+
+#[derive(Default)]
+struct ResumeData {
+    tickets: HashMap<String, Ticket>,
+    logs: HashMap<String, UserLog>,
+}
+
+pub struct Resumed {
+    pub room_id: String,
+    // Frames to send again, oldest first
+    pub events: Vec<String>,
+}
+
+// Lets a dropped websocket connection be picked up again with its resume token.
+// Chat messages are replayed from the store by seq; the other room events a
+// user was sent (edits, deletions, membership changes) are kept here while
+// the user has a token, since they have no seq of their own. Only the events
+// recorded after the connection closed are replayed. Kept in memory, a
+// restart means a full init.
+pub struct ResumeLog {
+    data: Mutex<ResumeData>,
+    window_secs: u64,
+}
+
+impl ResumeLog {
+    pub fn new(config: &SessionConfig) -> Self {
+        ResumeLog {
+            data: Mutex::new(ResumeData::default()),
+            window_secs: config.resume_window_secs,
+        }
+    }
+
+    // A token for a new connection; events sent to user_id are kept from now on
+    pub fn issue(&self, user_id: &str, session_id: &str, room_id: &str) -> String {
+        let token = generate_token();
+        let mut data = self.data.lock().unwrap();
+        data.tickets.insert(token.clone(), Ticket {
+            user_id: user_id.to_string(),
+            session_id: session_id.to_string(),
+            room_id: room_id.to_string(),
+            expires_at_secs: None,
+            released_at: 0,
+        });
+        data.logs.entry(user_id.to_string()).or_default();
+        token
+    }
+
+    // The connection closed, its token can be resumed for resume_window_secs
+    pub fn release(&self, token: &str, room_id: &str, now: u64) {
+        let mut data = self.data.lock().unwrap();
+        let ResumeData { tickets, logs } = &mut *data;
+        if let Some(ticket) = tickets.get_mut(token) {
+            ticket.room_id = room_id.to_string();
+            ticket.expires_at_secs = Some(now + self.window_secs);
+            ticket.released_at = logs.get(&ticket.user_id).map_or(0, |log| log.recorded);
+        }
+    }
+
+    pub fn record(&self, user_id: &str, room_id: &str, frame: &str) {
+        let mut data = self.data.lock().unwrap();
+        let Some(log) = data.logs.get_mut(user_id) else {
+            return;
+        };
+        log.events.push_back(Event { room_id: room_id.to_string(), frame: frame.to_string() });
+        log.recorded += 1;
+        if log.events.len() > MAX_EVENTS_PER_USER {
+            log.events.pop_front();
+        }
+    }
+
+    // Uses up the token, returning the events about rooms that were recorded
+    // after the connection closed. Events about other rooms are not replayed.
+    // None when the token is unknown, expired, issued to another session, or
+    // an event recorded after the connection closed was dropped.
+    pub fn resume(&self, token: &str, user_id: &str, session_id: &str, rooms: &HashSet<String>, now: u64) -> Option<Resumed> {
+        let mut data = self.data.lock().unwrap();
+        let ticket = data.tickets.get(token)?;
+        if ticket.user_id != user_id || ticket.session_id != session_id || ticket.expires_at_secs.is_some_and(|expires_at_secs| expires_at_secs <= now) {
+            return None;
+        }
+        let ticket = data.tickets.remove(token)?;
+        let log = data.logs.get(user_id)?;
+        if ticket.released_at < log.first_kept() {
+            return None;
+        }
+        let events = log.events.iter()
+            .skip((ticket.released_at - log.first_kept()) as usize)
+            .filter(|event| rooms.contains(&event.room_id))
+            .map(|event| event.frame.clone())
+            .collect();
+        Some(Resumed { room_id: ticket.room_id, events })
+    }
+
+    pub fn forget_expired(&self, now: u64) {
+        let mut data = self.data.lock().unwrap();
+        data.tickets.retain(|_, ticket| ticket.expires_at_secs.is_none_or(|expires_at_secs| expires_at_secs > now));
+        let ResumeData { tickets, logs } = &mut *data;
+        logs.retain(|user_id, _| tickets.values().any(|ticket| &ticket.user_id == user_id));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn log() -> ResumeLog {
+        ResumeLog::new(&SessionConfig { resume_window_secs: 60, ..SessionConfig::default() })
+    }
+
+    fn rooms(room_ids: &[&str]) -> HashSet<String> {
+        room_ids.iter().map(|room_id| room_id.to_string()).collect()
+    }
+
+    #[test]
+    fn resume_replays_events_recorded_after_release() {
+        let log = log();
+        let token = log.issue("alice", "session", "general");
+        log.record("alice", "general", "seen before closing");
+        log.release(&token, "general", 1000);
+        for n in 1..=2 {
+            log.record("alice", "general", &format!("event {}", n));
+        }
+        log.record("alice", "other", "other event");
+        let resumed = log.resume(&token, "alice", "session", &rooms(&["general"]), 1010).unwrap();
+        assert_eq!(resumed.room_id, "general");
+        assert_eq!(resumed.events, ["event 1", "event 2"]);
+    }
+
+    #[test]
+    fn token_is_used_up() {
+        let log = log();
+        let token = log.issue("alice", "session", "general");
+        log.release(&token, "general", 1000);
+        assert!(log.resume(&token, "alice", "session", &HashSet::new(), 1010).is_some());
+        assert!(log.resume(&token, "alice", "session", &HashSet::new(), 1010).is_none());
+    }
+
+    #[test]
+    fn token_only_resumes_its_own_session_within_the_window() {
+        let log = log();
+        let token = log.issue("alice", "session", "general");
+        log.release(&token, "general", 1000);
+        assert!(log.resume(&token, "bob", "session", &HashSet::new(), 1010).is_none());
+        assert!(log.resume(&token, "alice", "other session", &HashSet::new(), 1010).is_none());
+        assert!(log.resume(&token, "alice", "session", &HashSet::new(), 1060).is_none());
+        assert!(log.resume(&token, "alice", "session", &HashSet::new(), 1059).is_some());
+    }
+
+    #[test]
+    fn resume_is_refused_past_a_dropped_event() {
+        let log = log();
+        let refused = log.issue("alice", "session", "general");
+        let accepted = log.issue("alice", "session", "general");
+        log.release(&refused, "general", 1000);
+        log.record("alice", "general", "event");
+        log.release(&accepted, "general", 1000);
+        for _ in 0..MAX_EVENTS_PER_USER {
+            log.record("alice", "general", "event");
+        }
+        assert!(log.resume(&refused, "alice", "session", &rooms(&["general"]), 1010).is_none());
+        let resumed = log.resume(&accepted, "alice", "session", &rooms(&["general"]), 1010).unwrap();
+        assert_eq!(resumed.events.len(), MAX_EVENTS_PER_USER);
+    }
+}
@@ -0,0 +1,239 @@
+use async_trait::async_trait;
+use std::fmt;
+use std::str::FromStr;
+use crate::structs::{AttachmentMeta, ConnectionState, LoginLockout, PasswordResetToken, Room, RoomBan, RoomMute, RoomRole, SessionRecord, TwoFactor, User, UserData};
+use crate::message_structs::{BasicMessage, MessageRevision};
+
+pub mod memory;
+pub mod surreal;
+
+pub use memory::MemoryStore;
+pub use surreal::SurrealStore;
+
+// Position of a message in a room's history, encoded for clients as "seq:timestamp:message_id".
+// History is ordered by seq; timestamp and message_id only order the messages from before seq, which all have 0.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MessageCursor {
+    pub seq: u64,
+    pub timestamp: u64,
+    pub message_id: String,
+}
+
+impl MessageCursor {
+    pub fn of(message: &BasicMessage) -> Self {
+        MessageCursor { seq: message.seq, timestamp: message.timestamp, message_id: message.message_id.clone() }
+    }
+
+    pub fn key(&self) -> (u64, u64, &str) {
+        (self.seq, self.timestamp, &self.message_id)
+    }
+}
+
+impl fmt::Display for MessageCursor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.seq, self.timestamp, self.message_id)
+    }
+}
+
+impl FromStr for MessageCursor {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(3, ':');
+        let (Some(seq), Some(timestamp), Some(message_id)) = (parts.next(), parts.next(), parts.next()) else {
+            anyhow::bail!("cursor {:?} is not seq:timestamp:message_id", s);
+        };
+        if message_id.is_empty() {
+            anyhow::bail!("cursor {:?} has no message id", s);
+        }
+        Ok(MessageCursor { seq: seq.parse()?, timestamp: timestamp.parse()?, message_id: message_id.to_string() })
+    }
+}
+
+pub enum HistoryCursor {
+    Latest,
+    Before(MessageCursor),
+    After(MessageCursor),
+    // Messages with from_seq <= seq <= to_seq
+    Range { from_seq: u64, to_seq: u64 },
+}
+
+// Messages are oldest first. next_cursor continues in the same direction and
+// is None once there is nothing further to fetch.
+pub struct HistoryPage {
+    pub messages: Vec<BasicMessage>,
+    pub next_cursor: Option<MessageCursor>,
+}
+
+impl HistoryPage {
+    // Builds a page from up to limit + 1 rows fetched in walk order, where the
+    // extra row only signals that another page exists.
+    pub fn from_walk(mut rows: Vec<BasicMessage>, cursor: &HistoryCursor, limit: usize) -> Self {
+        let has_more = rows.len() > limit;
+        rows.truncate(limit);
+        let next_cursor = if has_more { rows.last().map(MessageCursor::of) } else { None };
+        if matches!(cursor, HistoryCursor::Latest | HistoryCursor::Before(_)) {
+            rows.reverse();
+        }
+        HistoryPage { messages: rows, next_cursor }
+    }
+}
+
+// Persistence layer used by AppState and the websocket handlers.
+// Membership is kept on both sides: Room.users and UserData.rooms.
+#[async_trait]
+pub trait ChatStore: Send + Sync {
+    // Users
+    async fn create_user(&self, user: UserData) -> anyhow::Result<()>;
+    async fn get_user(&self, user_id: &str) -> anyhow::Result<Option<UserData>>;
+    async fn get_user_by_login(&self, login_username: &str) -> anyhow::Result<Option<UserData>>;
+    async fn username_taken(&self, username: &str) -> anyhow::Result<bool>;
+    async fn update_username(&self, user_id: &str, new_username: &str) -> anyhow::Result<()>;
+    // Also bumps session_epoch, which ends every existing session of the user
+    async fn set_password(&self, user_id: &str, hashed_password: &str) -> anyhow::Result<()>;
+    async fn set_email_verified(&self, user_id: &str) -> anyhow::Result<()>;
+
+    // Two-factor authentication
+    async fn set_pending_totp_secret(&self, user_id: &str, secret: Option<String>) -> anyhow::Result<()>;
+    // Also clears the pending secret
+    async fn set_two_factor(&self, user_id: &str, two_factor: Option<TwoFactor>) -> anyhow::Result<()>;
+    // Records step as used, returning false if it or a later step already was
+    async fn advance_totp_step(&self, user_id: &str, step: u64) -> anyhow::Result<bool>;
+    // Removes the recovery code, returning false if the user has no such unused code
+    async fn take_recovery_code(&self, user_id: &str, code_hash: &str) -> anyhow::Result<bool>;
+    // last_seen is only overwritten when given
+    async fn set_status(&self, user_id: &str, status: ConnectionState, last_seen: Option<u64>) -> anyhow::Result<()>;
+    async fn users_in_room(&self, room_id: &str) -> anyhow::Result<Vec<User>>;
+
+    // Password resets
+    async fn create_reset_token(&self, token: PasswordResetToken) -> anyhow::Result<()>;
+    // Removes the token while returning it, so it can only be used once
+    async fn take_reset_token(&self, token_hash: &str) -> anyhow::Result<Option<PasswordResetToken>>;
+    async fn delete_reset_tokens(&self, user_id: &str) -> anyhow::Result<()>;
+
+    // Sessions
+    async fn create_session(&self, session: SessionRecord) -> anyhow::Result<()>;
+    async fn get_session(&self, session_id: &str) -> anyhow::Result<Option<SessionRecord>>;
+    // Oldest first
+    async fn user_sessions(&self, user_id: &str) -> anyhow::Result<Vec<SessionRecord>>;
+    async fn touch_session(&self, session_id: &str, last_seen: u64) -> anyhow::Result<()>;
+    async fn delete_session(&self, session_id: &str) -> anyhow::Result<()>;
+    // Deletes every session of user_id except the one given
+    async fn delete_user_sessions(&self, user_id: &str, except: Option<&str>) -> anyhow::Result<()>;
+    async fn delete_idle_sessions(&self, last_seen_before: u64) -> anyhow::Result<()>;
+
+    // Login lockouts
+    async fn create_lockout(&self, lockout: LoginLockout) -> anyhow::Result<()>;
+    async fn get_lockout(&self, lockout_id: &str) -> anyhow::Result<Option<LoginLockout>>;
+    // Lockouts started at or after since, newest first
+    async fn recent_lockouts(&self, since: u64) -> anyhow::Result<Vec<LoginLockout>>;
+    // Ends the lockout at now if it is still running
+    async fn lift_lockout(&self, lockout_id: &str, lifted_by: &str, now: u64) -> anyhow::Result<()>;
+
+    // Rooms
+    async fn create_room(&self, room: Room) -> anyhow::Result<()>;
+    async fn get_room(&self, room_id: &str) -> anyhow::Result<Option<Room>>;
+    async fn add_room_member(&self, room_id: &str, user_id: &str) -> anyhow::Result<()>;
+    // Also clears any role the member held
+    async fn remove_room_member(&self, room_id: &str, user_id: &str) -> anyhow::Result<()>;
+    async fn set_room_role(&self, room_id: &str, user_id: &str, role: RoomRole) -> anyhow::Result<()>;
+    async fn rename_room(&self, room_id: &str, name: &str) -> anyhow::Result<()>;
+    // None lifts an existing ban or mute
+    async fn set_ban(&self, room_id: &str, user_id: &str, ban: Option<RoomBan>) -> anyhow::Result<()>;
+    async fn set_mute(&self, room_id: &str, user_id: &str, mute: Option<RoomMute>) -> anyhow::Result<()>;
+
+    // Messages
+    // Stores the message as the next seq of its room and returns that seq
+    async fn create_message(&self, message: BasicMessage) -> anyhow::Result<u64>;
+    async fn get_message(&self, message_id: &str) -> anyhow::Result<Option<BasicMessage>>;
+    // Replaces the content of previous and records its old content as a revision
+    async fn edit_message(&self, previous: &BasicMessage, content: &str, edited_at: u64) -> anyhow::Result<()>;
+    async fn message_revisions(&self, message_id: &str) -> anyhow::Result<Vec<MessageRevision>>;
+    // Tombstones the message, dropping its image, attachments and revisions
+    async fn delete_message(&self, message_id: &str, deleted_at: u64) -> anyhow::Result<()>;
+    async fn message_page(&self, room_id: &str, cursor: HistoryCursor, limit: usize) -> anyhow::Result<HistoryPage>;
+
+    // Attachments
+    async fn create_attachment(&self, attachment: AttachmentMeta) -> anyhow::Result<()>;
+    async fn get_attachment(&self, attachment_id: &str) -> anyhow::Result<Option<AttachmentMeta>>;
+    async fn user_attachments(&self, user_id: &str) -> anyhow::Result<Vec<AttachmentMeta>>;
+    // Total size of the attachments a user uploaded, and of those uploaded into a room
+    async fn user_attachment_usage(&self, user_id: &str) -> anyhow::Result<u64>;
+    async fn room_attachment_usage(&self, room_id: &str) -> anyhow::Result<u64>;
+    async fn delete_attachment(&self, attachment_id: &str) -> anyhow::Result<()>;
+    // Whether any attachment still points at the blob
+    async fn blob_in_use(&self, sha256: &str) -> anyhow::Result<bool>;
+    // Attachments created before created_before that no message refers to
+    async fn unreferenced_attachments(&self, created_before: u64) -> anyhow::Result<Vec<AttachmentMeta>>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(message_id: &str, seq: u64) -> BasicMessage {
+        BasicMessage {
+            content: String::new(),
+            sender_id: "sender".to_string(),
+            timestamp: 1000 + seq,
+            message_id: message_id.to_string(),
+            room_id: "room".to_string(),
+            ws_id: String::new(),
+            seq,
+            edited_at: None,
+            deleted_at: None,
+            image: None,
+            attachments: Vec::new(),
+        }
+    }
+
+    fn ids(page: &HistoryPage) -> Vec<&str> {
+        page.messages.iter().map(|message| message.message_id.as_str()).collect()
+    }
+
+    #[test]
+    fn cursor_round_trips() {
+        let cursor = MessageCursor { seq: 7, timestamp: 1700000000, message_id: "abc".to_string() };
+        assert_eq!(cursor.to_string(), "7:1700000000:abc");
+        assert_eq!(cursor.to_string().parse::<MessageCursor>().unwrap(), cursor);
+    }
+
+    #[test]
+    fn cursor_message_id_may_contain_colons() {
+        let cursor: MessageCursor = "1:2:a:b".parse().unwrap();
+        assert_eq!(cursor.key(), (1, 2, "a:b"));
+    }
+
+    #[test]
+    fn cursor_rejects_malformed_input() {
+        for input in ["", "1", "1:2", "1:2:", "x:2:id", "1:y:id", "-1:2:id"] {
+            assert!(input.parse::<MessageCursor>().is_err(), "{:?} parsed", input);
+        }
+    }
+
+    #[test]
+    fn latest_page_is_oldest_first_and_continues_older() {
+        // Walked newest first, with one row more than the limit
+        let rows = vec![message("c", 3), message("b", 2), message("a", 1)];
+        let page = HistoryPage::from_walk(rows, &HistoryCursor::Latest, 2);
+        assert_eq!(ids(&page), ["b", "c"]);
+        assert_eq!(page.next_cursor.unwrap().message_id, "b");
+    }
+
+    #[test]
+    fn after_page_keeps_walk_order() {
+        let after = MessageCursor::of(&message("a", 1));
+        let rows = vec![message("b", 2), message("c", 3), message("d", 4)];
+        let page = HistoryPage::from_walk(rows, &HistoryCursor::After(after), 2);
+        assert_eq!(ids(&page), ["b", "c"]);
+        assert_eq!(page.next_cursor.unwrap().message_id, "c");
+    }
+
+    #[test]
+    fn last_page_has_no_cursor() {
+        let rows = vec![message("b", 2), message("a", 1)];
+        let page = HistoryPage::from_walk(rows, &HistoryCursor::Latest, 2);
+        assert_eq!(ids(&page), ["a", "b"]);
+        assert!(page.next_cursor.is_none());
+    }
+}
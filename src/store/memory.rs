@@ -0,0 +1,516 @@
+use async_trait::async_trait;
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use crate::structs::{AttachmentMeta, ConnectionState, LoginLockout, PasswordResetToken, Room, RoomBan, RoomMute, RoomRole, SessionRecord, TwoFactor, User, UserData};
+use crate::message_structs::{BasicMessage, MessageRevision};
+use super::{ChatStore, HistoryCursor, HistoryPage, MessageCursor};
+
+// Keeps everything in process memory, for running without a SurrealDB server.
+#[derive(Default)]
+pub struct MemoryStore {
+    data: Mutex<MemoryData>,
+}
+
+#[derive(Default)]
+struct MemoryData {
+    users: HashMap<String, UserData>,
+    rooms: HashMap<String, Room>,
+    messages: Vec<BasicMessage>,
+    revisions: HashMap<String, Vec<MessageRevision>>,
+    attachments: HashMap<String, AttachmentMeta>,
+    reset_tokens: HashMap<String, PasswordResetToken>,
+    lockouts: HashMap<String, LoginLockout>,
+    sessions: HashMap<String, SessionRecord>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        MemoryStore::default()
+    }
+}
+
+#[async_trait]
+impl ChatStore for MemoryStore {
+    async fn create_user(&self, user: UserData) -> anyhow::Result<()> {
+        let mut data = self.data.lock().unwrap();
+        if data.users.contains_key(&user.user_id) {
+            anyhow::bail!("user {} already exists", user.user_id);
+        }
+        data.users.insert(user.user_id.clone(), user);
+        Ok(())
+    }
+
+    async fn get_user(&self, user_id: &str) -> anyhow::Result<Option<UserData>> {
+        let data = self.data.lock().unwrap();
+        Ok(data.users.get(user_id).cloned())
+    }
+
+    async fn get_user_by_login(&self, login_username: &str) -> anyhow::Result<Option<UserData>> {
+        let data = self.data.lock().unwrap();
+        Ok(data.users.values().find(|user| user.login_username == login_username).cloned())
+    }
+
+    async fn username_taken(&self, username: &str) -> anyhow::Result<bool> {
+        let data = self.data.lock().unwrap();
+        Ok(data.users.values().any(|user| user.username == username))
+    }
+
+    async fn update_username(&self, user_id: &str, new_username: &str) -> anyhow::Result<()> {
+        let mut data = self.data.lock().unwrap();
+        if let Some(user) = data.users.get_mut(user_id) {
+            user.username = new_username.to_string();
+        }
+        Ok(())
+    }
+
+    async fn set_password(&self, user_id: &str, hashed_password: &str) -> anyhow::Result<()> {
+        let mut data = self.data.lock().unwrap();
+        if let Some(user) = data.users.get_mut(user_id) {
+            user.hashed_password = hashed_password.to_string();
+            user.session_epoch += 1;
+        }
+        Ok(())
+    }
+
+    async fn set_email_verified(&self, user_id: &str) -> anyhow::Result<()> {
+        let mut data = self.data.lock().unwrap();
+        if let Some(user) = data.users.get_mut(user_id) {
+            user.email_verified = true;
+        }
+        Ok(())
+    }
+
+    async fn set_pending_totp_secret(&self, user_id: &str, secret: Option<String>) -> anyhow::Result<()> {
+        let mut data = self.data.lock().unwrap();
+        if let Some(user) = data.users.get_mut(user_id) {
+            user.pending_totp_secret = secret;
+        }
+        Ok(())
+    }
+
+    async fn set_two_factor(&self, user_id: &str, two_factor: Option<TwoFactor>) -> anyhow::Result<()> {
+        let mut data = self.data.lock().unwrap();
+        if let Some(user) = data.users.get_mut(user_id) {
+            user.two_factor = two_factor;
+            user.pending_totp_secret = None;
+        }
+        Ok(())
+    }
+
+    async fn advance_totp_step(&self, user_id: &str, step: u64) -> anyhow::Result<bool> {
+        let mut data = self.data.lock().unwrap();
+        match data.users.get_mut(user_id).and_then(|user| user.two_factor.as_mut()) {
+            Some(two_factor) if two_factor.last_step < step => {
+                two_factor.last_step = step;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    async fn take_recovery_code(&self, user_id: &str, code_hash: &str) -> anyhow::Result<bool> {
+        let mut data = self.data.lock().unwrap();
+        let Some(two_factor) = data.users.get_mut(user_id).and_then(|user| user.two_factor.as_mut()) else {
+            return Ok(false);
+        };
+        let before = two_factor.recovery_codes.len();
+        two_factor.recovery_codes.retain(|hash| hash != code_hash);
+        Ok(two_factor.recovery_codes.len() < before)
+    }
+
+    async fn set_status(&self, user_id: &str, status: ConnectionState, last_seen: Option<u64>) -> anyhow::Result<()> {
+        let mut data = self.data.lock().unwrap();
+        if let Some(user) = data.users.get_mut(user_id) {
+            user.status = status;
+            if last_seen.is_some() {
+                user.last_seen = last_seen;
+            }
+        }
+        Ok(())
+    }
+
+    async fn users_in_room(&self, room_id: &str) -> anyhow::Result<Vec<User>> {
+        let data = self.data.lock().unwrap();
+        Ok(data.users.values()
+            .filter(|user| user.rooms.iter().any(|room| room == room_id))
+            .map(|user| User { user_id: user.user_id.clone(), username: user.username.clone() })
+            .collect())
+    }
+
+    async fn create_reset_token(&self, token: PasswordResetToken) -> anyhow::Result<()> {
+        let mut data = self.data.lock().unwrap();
+        data.reset_tokens.insert(token.token_hash.clone(), token);
+        Ok(())
+    }
+
+    async fn take_reset_token(&self, token_hash: &str) -> anyhow::Result<Option<PasswordResetToken>> {
+        let mut data = self.data.lock().unwrap();
+        Ok(data.reset_tokens.remove(token_hash))
+    }
+
+    async fn delete_reset_tokens(&self, user_id: &str) -> anyhow::Result<()> {
+        let mut data = self.data.lock().unwrap();
+        data.reset_tokens.retain(|_, token| token.user_id != user_id);
+        Ok(())
+    }
+
+    async fn create_session(&self, session: SessionRecord) -> anyhow::Result<()> {
+        let mut data = self.data.lock().unwrap();
+        data.sessions.insert(session.session_id.clone(), session);
+        Ok(())
+    }
+
+    async fn get_session(&self, session_id: &str) -> anyhow::Result<Option<SessionRecord>> {
+        let data = self.data.lock().unwrap();
+        Ok(data.sessions.get(session_id).cloned())
+    }
+
+    async fn user_sessions(&self, user_id: &str) -> anyhow::Result<Vec<SessionRecord>> {
+        let data = self.data.lock().unwrap();
+        let mut sessions: Vec<SessionRecord> = data.sessions.values()
+            .filter(|session| session.user_id == user_id)
+            .cloned()
+            .collect();
+        sessions.sort_by_key(|session| session.created_at);
+        Ok(sessions)
+    }
+
+    async fn touch_session(&self, session_id: &str, last_seen: u64) -> anyhow::Result<()> {
+        let mut data = self.data.lock().unwrap();
+        if let Some(session) = data.sessions.get_mut(session_id) {
+            session.last_seen = last_seen;
+        }
+        Ok(())
+    }
+
+    async fn delete_session(&self, session_id: &str) -> anyhow::Result<()> {
+        let mut data = self.data.lock().unwrap();
+        data.sessions.remove(session_id);
+        Ok(())
+    }
+
+    async fn delete_user_sessions(&self, user_id: &str, except: Option<&str>) -> anyhow::Result<()> {
+        let mut data = self.data.lock().unwrap();
+        data.sessions.retain(|session_id, session| session.user_id != user_id || Some(session_id.as_str()) == except);
+        Ok(())
+    }
+
+    async fn delete_idle_sessions(&self, last_seen_before: u64) -> anyhow::Result<()> {
+        let mut data = self.data.lock().unwrap();
+        data.sessions.retain(|_, session| session.last_seen >= last_seen_before);
+        Ok(())
+    }
+
+    async fn create_lockout(&self, lockout: LoginLockout) -> anyhow::Result<()> {
+        let mut data = self.data.lock().unwrap();
+        data.lockouts.insert(lockout.lockout_id.clone(), lockout);
+        Ok(())
+    }
+
+    async fn get_lockout(&self, lockout_id: &str) -> anyhow::Result<Option<LoginLockout>> {
+        let data = self.data.lock().unwrap();
+        Ok(data.lockouts.get(lockout_id).cloned())
+    }
+
+    async fn recent_lockouts(&self, since: u64) -> anyhow::Result<Vec<LoginLockout>> {
+        let data = self.data.lock().unwrap();
+        let mut lockouts: Vec<LoginLockout> = data.lockouts.values()
+            .filter(|lockout| lockout.locked_at >= since)
+            .cloned()
+            .collect();
+        lockouts.sort_by_key(|lockout| std::cmp::Reverse(lockout.locked_at));
+        Ok(lockouts)
+    }
+
+    async fn lift_lockout(&self, lockout_id: &str, lifted_by: &str, now: u64) -> anyhow::Result<()> {
+        let mut data = self.data.lock().unwrap();
+        if let Some(lockout) = data.lockouts.get_mut(lockout_id) {
+            if lockout.expires_at > now {
+                lockout.expires_at = now;
+                lockout.lifted_by = Some(lifted_by.to_string());
+            }
+        }
+        Ok(())
+    }
+
+    async fn create_room(&self, room: Room) -> anyhow::Result<()> {
+        let mut data = self.data.lock().unwrap();
+        if data.rooms.contains_key(&room.room_id) {
+            anyhow::bail!("room {} already exists", room.room_id);
+        }
+        for user_id in &room.users {
+            if let Some(user) = data.users.get_mut(user_id) {
+                if !user.rooms.contains(&room.room_id) {
+                    user.rooms.push(room.room_id.clone());
+                }
+            }
+        }
+        data.rooms.insert(room.room_id.clone(), room);
+        Ok(())
+    }
+
+    async fn get_room(&self, room_id: &str) -> anyhow::Result<Option<Room>> {
+        let data = self.data.lock().unwrap();
+        Ok(data.rooms.get(room_id).cloned())
+    }
+
+    async fn add_room_member(&self, room_id: &str, user_id: &str) -> anyhow::Result<()> {
+        let mut data = self.data.lock().unwrap();
+        if let Some(room) = data.rooms.get_mut(room_id) {
+            if room.direct && !room.users.contains(user_id) {
+                anyhow::bail!("room {} is a direct conversation and cannot take more members", room_id);
+            }
+            room.users.insert(user_id.to_string());
+        }
+        if let Some(user) = data.users.get_mut(user_id) {
+            if !user.rooms.iter().any(|room| room == room_id) {
+                user.rooms.push(room_id.to_string());
+            }
+        }
+        Ok(())
+    }
+
+    async fn remove_room_member(&self, room_id: &str, user_id: &str) -> anyhow::Result<()> {
+        let mut data = self.data.lock().unwrap();
+        if let Some(room) = data.rooms.get_mut(room_id) {
+            room.users.remove(user_id);
+            room.roles.remove(user_id);
+        }
+        if let Some(user) = data.users.get_mut(user_id) {
+            user.rooms.retain(|room| room != room_id);
+        }
+        Ok(())
+    }
+
+    async fn set_room_role(&self, room_id: &str, user_id: &str, role: RoomRole) -> anyhow::Result<()> {
+        let mut data = self.data.lock().unwrap();
+        let room = data.rooms.get_mut(room_id)
+            .ok_or_else(|| anyhow::anyhow!("room {} does not exist", room_id))?;
+        match role {
+            RoomRole::Member => room.roles.remove(user_id),
+            role => room.roles.insert(user_id.to_string(), role),
+        };
+        Ok(())
+    }
+
+    async fn rename_room(&self, room_id: &str, name: &str) -> anyhow::Result<()> {
+        let mut data = self.data.lock().unwrap();
+        if let Some(room) = data.rooms.get_mut(room_id) {
+            room.name = name.to_string();
+        }
+        Ok(())
+    }
+
+    async fn set_ban(&self, room_id: &str, user_id: &str, ban: Option<RoomBan>) -> anyhow::Result<()> {
+        let mut data = self.data.lock().unwrap();
+        let room = data.rooms.get_mut(room_id)
+            .ok_or_else(|| anyhow::anyhow!("room {} does not exist", room_id))?;
+        match ban {
+            Some(ban) => room.bans.insert(user_id.to_string(), ban),
+            None => room.bans.remove(user_id),
+        };
+        Ok(())
+    }
+
+    async fn set_mute(&self, room_id: &str, user_id: &str, mute: Option<RoomMute>) -> anyhow::Result<()> {
+        let mut data = self.data.lock().unwrap();
+        let room = data.rooms.get_mut(room_id)
+            .ok_or_else(|| anyhow::anyhow!("room {} does not exist", room_id))?;
+        match mute {
+            Some(mute) => room.mutes.insert(user_id.to_string(), mute),
+            None => room.mutes.remove(user_id),
+        };
+        Ok(())
+    }
+
+    async fn create_message(&self, message: BasicMessage) -> anyhow::Result<u64> {
+        let mut data = self.data.lock().unwrap();
+        let room = data.rooms.get_mut(&message.room_id)
+            .ok_or_else(|| anyhow::anyhow!("room {} does not exist", message.room_id))?;
+        room.last_seq += 1;
+        let seq = room.last_seq;
+        data.messages.push(BasicMessage { seq, ..message });
+        Ok(seq)
+    }
+
+    async fn get_message(&self, message_id: &str) -> anyhow::Result<Option<BasicMessage>> {
+        let data = self.data.lock().unwrap();
+        Ok(data.messages.iter().find(|message| message.message_id == message_id).cloned())
+    }
+
+    async fn edit_message(&self, previous: &BasicMessage, content: &str, edited_at: u64) -> anyhow::Result<()> {
+        let mut data = self.data.lock().unwrap();
+        let message = data.messages.iter_mut()
+            .find(|message| message.message_id == previous.message_id)
+            .ok_or_else(|| anyhow::anyhow!("message {} does not exist", previous.message_id))?;
+        message.content = content.to_string();
+        message.edited_at = Some(edited_at);
+        data.revisions.entry(previous.message_id.clone()).or_default().push(MessageRevision {
+            message_id: previous.message_id.clone(),
+            content: previous.content.clone(),
+            timestamp: previous.edited_at.unwrap_or(previous.timestamp),
+        });
+        Ok(())
+    }
+
+    async fn message_revisions(&self, message_id: &str) -> anyhow::Result<Vec<MessageRevision>> {
+        let data = self.data.lock().unwrap();
+        Ok(data.revisions.get(message_id).cloned().unwrap_or_default())
+    }
+
+    async fn delete_message(&self, message_id: &str, deleted_at: u64) -> anyhow::Result<()> {
+        let mut data = self.data.lock().unwrap();
+        if let Some(message) = data.messages.iter_mut().find(|message| message.message_id == message_id) {
+            message.content.clear();
+            message.image = None;
+            message.attachments.clear();
+            message.deleted_at = Some(deleted_at);
+        }
+        data.revisions.remove(message_id);
+        Ok(())
+    }
+
+    async fn message_page(&self, room_id: &str, cursor: HistoryCursor, limit: usize) -> anyhow::Result<HistoryPage> {
+        let data = self.data.lock().unwrap();
+        let key = |message: &BasicMessage| (message.seq, message.timestamp, message.message_id.clone());
+        let mut rows: Vec<BasicMessage> = data.messages.iter()
+            .filter(|message| message.room_id == room_id)
+            .filter(|message| match &cursor {
+                HistoryCursor::Latest => true,
+                HistoryCursor::Before(position) => MessageCursor::of(message).key() < position.key(),
+                HistoryCursor::After(position) => MessageCursor::of(message).key() > position.key(),
+                HistoryCursor::Range { from_seq, to_seq } => (*from_seq..=*to_seq).contains(&message.seq),
+            })
+            .cloned()
+            .collect();
+        match cursor {
+            HistoryCursor::After(_) | HistoryCursor::Range { .. } => rows.sort_by_key(key),
+            _ => rows.sort_by_key(|message| std::cmp::Reverse(key(message))),
+        }
+        rows.truncate(limit + 1);
+        Ok(HistoryPage::from_walk(rows, &cursor, limit))
+    }
+
+    async fn create_attachment(&self, attachment: AttachmentMeta) -> anyhow::Result<()> {
+        let mut data = self.data.lock().unwrap();
+        data.attachments.insert(attachment.attachment_id.clone(), attachment);
+        Ok(())
+    }
+
+    async fn get_attachment(&self, attachment_id: &str) -> anyhow::Result<Option<AttachmentMeta>> {
+        let data = self.data.lock().unwrap();
+        Ok(data.attachments.get(attachment_id).cloned())
+    }
+
+    async fn user_attachments(&self, user_id: &str) -> anyhow::Result<Vec<AttachmentMeta>> {
+        let data = self.data.lock().unwrap();
+        let mut attachments: Vec<AttachmentMeta> = data.attachments.values()
+            .filter(|attachment| attachment.uploader_id == user_id)
+            .cloned()
+            .collect();
+        attachments.sort_by_key(|attachment| attachment.created_at);
+        Ok(attachments)
+    }
+
+    async fn user_attachment_usage(&self, user_id: &str) -> anyhow::Result<u64> {
+        let data = self.data.lock().unwrap();
+        Ok(data.attachments.values()
+            .filter(|attachment| attachment.uploader_id == user_id)
+            .map(|attachment| attachment.size)
+            .sum())
+    }
+
+    async fn room_attachment_usage(&self, room_id: &str) -> anyhow::Result<u64> {
+        let data = self.data.lock().unwrap();
+        Ok(data.attachments.values()
+            .filter(|attachment| attachment.room_id == room_id)
+            .map(|attachment| attachment.size)
+            .sum())
+    }
+
+    async fn delete_attachment(&self, attachment_id: &str) -> anyhow::Result<()> {
+        let mut data = self.data.lock().unwrap();
+        data.attachments.remove(attachment_id);
+        Ok(())
+    }
+
+    async fn blob_in_use(&self, sha256: &str) -> anyhow::Result<bool> {
+        let data = self.data.lock().unwrap();
+        Ok(data.attachments.values().any(|attachment| attachment.sha256 == sha256))
+    }
+
+    async fn unreferenced_attachments(&self, created_before: u64) -> anyhow::Result<Vec<AttachmentMeta>> {
+        let data = self.data.lock().unwrap();
+        let referenced: HashSet<&str> = data.messages.iter()
+            .flat_map(|message| {
+                let image = message.image.iter().map(|image| image.attachment_id.as_str());
+                let attachments = message.attachments.iter().map(|attachment| attachment.attachment_id.as_str());
+                image.chain(attachments)
+            })
+            .collect();
+        Ok(data.attachments.values()
+            .filter(|attachment| attachment.created_at < created_before)
+            .filter(|attachment| !referenced.contains(attachment.attachment_id.as_str()))
+            .cloned()
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(message_id: &str, room_id: &str) -> BasicMessage {
+        BasicMessage {
+            content: String::new(),
+            sender_id: "sender".to_string(),
+            timestamp: 1000,
+            message_id: message_id.to_string(),
+            room_id: room_id.to_string(),
+            ws_id: String::new(),
+            seq: 0,
+            edited_at: None,
+            deleted_at: None,
+            image: None,
+            attachments: Vec::new(),
+        }
+    }
+
+    async fn store_with_rooms(room_ids: &[&str]) -> MemoryStore {
+        let store = MemoryStore::new();
+        for room_id in room_ids {
+            store.create_room(Room { room_id: room_id.to_string(), ..Room::default() }).await.unwrap();
+        }
+        store
+    }
+
+    #[actix_web::test]
+    async fn messages_are_numbered_per_room() {
+        let store = store_with_rooms(&["a", "b"]).await;
+        assert_eq!(store.create_message(message("1", "a")).await.unwrap(), 1);
+        assert_eq!(store.create_message(message("2", "a")).await.unwrap(), 2);
+        assert_eq!(store.create_message(message("3", "b")).await.unwrap(), 1);
+        assert_eq!(store.create_message(message("4", "a")).await.unwrap(), 3);
+        assert_eq!(store.get_message("4").await.unwrap().unwrap().seq, 3);
+        assert_eq!(store.get_room("a").await.unwrap().unwrap().last_seq, 3);
+    }
+
+    #[actix_web::test]
+    async fn message_to_missing_room_is_refused() {
+        let store = store_with_rooms(&[]).await;
+        assert!(store.create_message(message("1", "missing")).await.is_err());
+        assert!(store.get_message("1").await.unwrap().is_none());
+    }
+
+    #[actix_web::test]
+    async fn range_page_is_ordered_by_seq() {
+        let store = store_with_rooms(&["a"]).await;
+        for message_id in ["1", "2", "3", "4"] {
+            store.create_message(message(message_id, "a")).await.unwrap();
+        }
+        let page = store.message_page("a", HistoryCursor::Range { from_seq: 2, to_seq: 3 }, 10).await.unwrap();
+        let seqs: Vec<u64> = page.messages.iter().map(|message| message.seq).collect();
+        assert_eq!(seqs, [2, 3]);
+        assert!(page.next_cursor.is_none());
+    }
+}
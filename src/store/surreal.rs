@@ -0,0 +1,535 @@
+use async_trait::async_trait;
+use surrealdb::engine::remote::ws::{Client, Ws};
+use surrealdb::opt::auth::Root;
+use surrealdb::Surreal;
+use std::collections::HashMap;
+use std::time::Duration;
+use serde::Serialize;
+use crate::structs::{AttachmentMeta, ConnectionState, LoginLockout, PasswordResetToken, Room, RoomBan, RoomMute, RoomRole, SessionRecord, TwoFactor, User, UserData};
+use crate::message_structs::{BasicMessage, MessageRevision};
+use super::{ChatStore, HistoryCursor, HistoryPage};
+
+pub struct SurrealStore {
+    db: Surreal<Client>,
+}
+
+impl SurrealStore {
+    pub async fn connect(address: &str, username: &str, password: &str, namespace: &str, database: &str) -> anyhow::Result<Self> {
+        let db = Surreal::new::<Ws>(address).await?;
+        db.signin(Root { username, password }).await?;
+        db.use_ns(namespace).use_db(database).await?;
+        let store = SurrealStore { db };
+        store.migrate().await?;
+        Ok(store)
+    }
+
+    // Runs the migrations newer than the version in meta:schema, each along with its version bump
+    async fn migrate(&self) -> anyhow::Result<()> {
+        let versions: Vec<usize> = self.db.query("SELECT VALUE version FROM type::thing('meta', 'schema');")
+            .await?
+            .take(0)?;
+        let current = versions.first().copied().unwrap_or(0);
+        for (version, migration) in MIGRATIONS.iter().enumerate().map(|(i, m)| (i + 1, m)).skip(current) {
+            log::info!("Applying database migration {}", version);
+            let query = format!("BEGIN TRANSACTION;
+                {}
+                UPDATE type::thing('meta', 'schema') SET version = $version;
+                COMMIT TRANSACTION;", migration);
+            self.db.query(query)
+                .bind(("version", version))
+                .await?
+                .check()?;
+        }
+        Ok(())
+    }
+}
+
+// Schema migrations, the nth entry takes the database to version n. Only ever append.
+const MIGRATIONS: &[&str] = &[
+    // 1: messages stored before rooms numbered them sort first, as seq 0
+    "UPDATE messages SET seq = 0 WHERE seq = NONE;",
+];
+
+// Concurrent sends to one room conflict on last_seq, one of them is retried this many times in all
+const CREATE_MESSAGE_ATTEMPTS: u32 = 5;
+
+// RocksDB reports a conflicting transaction as a busy resource, TiKV and FoundationDB as a conflict
+fn is_conflict(e: &anyhow::Error) -> bool {
+    let text = e.to_string().to_lowercase();
+    ["conflict", "resource busy", "can be retried"].iter().any(|needle| text.contains(needle))
+}
+
+impl SurrealStore {
+    // Sets the entry of user_id in one of a room's maps, or removes it when value is None.
+    // MERGE changes that one key in place, so concurrent changes to other keys are kept.
+    async fn set_room_entry<T: Serialize>(&self, room_id: &str, field: &str, user_id: &str, value: Option<T>) -> anyhow::Result<()> {
+        let query = "BEGIN TRANSACTION;
+            IF array::len((SELECT VALUE id FROM type::thing('rooms', $room_id))) = 0 {
+                THROW 'room does not exist';
+            };
+            UPDATE type::thing('rooms', $room_id) MERGE $patch;
+            COMMIT TRANSACTION;";
+        let patch = HashMap::from([(field, HashMap::from([(user_id, value)]))]);
+        self.db.query(query)
+            .bind(("room_id", room_id))
+            .bind(("patch", patch))
+            .await?
+            .check()?;
+        Ok(())
+    }
+
+    // Takes the next seq of the room and stores the message with it, or nothing if the room is gone
+    async fn insert_message(&self, message: &BasicMessage) -> anyhow::Result<u64> {
+        let query = "BEGIN TRANSACTION;
+            IF array::len((SELECT VALUE id FROM type::thing('rooms', $room_id))) = 0 {
+                THROW 'room does not exist';
+            };
+            LET $seq = (UPDATE type::thing('rooms', $room_id) SET last_seq = (last_seq OR 0) + 1 RETURN VALUE last_seq)[0];
+            CREATE type::thing('messages', $message_id) CONTENT $message;
+            UPDATE type::thing('messages', $message_id) SET seq = $seq RETURN VALUE seq;
+            COMMIT TRANSACTION;";
+        let mut response = self.db.query(query)
+            .bind(("room_id", message.room_id.clone()))
+            .bind(("message_id", message.message_id.clone()))
+            .bind(("message", message.clone()))
+            .await?
+            .check()?;
+        let last = response.num_statements() - 1;
+        let seq: Option<u64> = response.take(last)?;
+        seq.ok_or_else(|| anyhow::anyhow!("message was stored without a seq"))
+    }
+}
+
+#[async_trait]
+impl ChatStore for SurrealStore {
+    async fn create_user(&self, user: UserData) -> anyhow::Result<()> {
+        let _: Option<UserData> = self.db.create(("users", user.user_id.clone())).content(user).await?;
+        Ok(())
+    }
+
+    async fn get_user(&self, user_id: &str) -> anyhow::Result<Option<UserData>> {
+        let query = "SELECT * FROM users WHERE user_id = $user_id;";
+        let mut response = self.db.query(query).bind(("user_id", user_id)).await?;
+        Ok(response.take(0)?)
+    }
+
+    async fn get_user_by_login(&self, login_username: &str) -> anyhow::Result<Option<UserData>> {
+        let query = "SELECT * FROM users WHERE login_username = $login_username;";
+        let mut response = self.db.query(query).bind(("login_username", login_username)).await?;
+        Ok(response.take(0)?)
+    }
+
+    async fn username_taken(&self, username: &str) -> anyhow::Result<bool> {
+        let query = "SELECT username FROM users WHERE username = $username;";
+        let mut response = self.db.query(query).bind(("username", username)).await?;
+        let result: Option<String> = response.take((0, "username"))?;
+        Ok(result.is_some())
+    }
+
+    async fn update_username(&self, user_id: &str, new_username: &str) -> anyhow::Result<()> {
+        let query = "UPDATE users SET username = $new_username WHERE user_id = $user_id;";
+        self.db.query(query)
+            .bind(("new_username", new_username))
+            .bind(("user_id", user_id))
+            .await?
+            .check()?;
+        Ok(())
+    }
+
+    async fn set_password(&self, user_id: &str, hashed_password: &str) -> anyhow::Result<()> {
+        let query = "UPDATE users SET hashed_password = $hashed_password, session_epoch = (session_epoch OR 0) + 1 WHERE user_id = $user_id;";
+        self.db.query(query)
+            .bind(("hashed_password", hashed_password))
+            .bind(("user_id", user_id))
+            .await?
+            .check()?;
+        Ok(())
+    }
+
+    async fn set_email_verified(&self, user_id: &str) -> anyhow::Result<()> {
+        let query = "UPDATE users SET email_verified = true WHERE user_id = $user_id;";
+        self.db.query(query)
+            .bind(("user_id", user_id))
+            .await?
+            .check()?;
+        Ok(())
+    }
+
+    async fn set_pending_totp_secret(&self, user_id: &str, secret: Option<String>) -> anyhow::Result<()> {
+        let query = "UPDATE users SET pending_totp_secret = $secret WHERE user_id = $user_id;";
+        self.db.query(query)
+            .bind(("secret", secret))
+            .bind(("user_id", user_id))
+            .await?
+            .check()?;
+        Ok(())
+    }
+
+    async fn set_two_factor(&self, user_id: &str, two_factor: Option<TwoFactor>) -> anyhow::Result<()> {
+        let query = "UPDATE users SET two_factor = $two_factor, pending_totp_secret = NONE WHERE user_id = $user_id;";
+        self.db.query(query)
+            .bind(("two_factor", two_factor))
+            .bind(("user_id", user_id))
+            .await?
+            .check()?;
+        Ok(())
+    }
+
+    // The conditions make both updates a single check-and-set, so concurrent logins cannot share a code
+    async fn advance_totp_step(&self, user_id: &str, step: u64) -> anyhow::Result<bool> {
+        let query = "UPDATE users SET two_factor.last_step = $step WHERE user_id = $user_id AND two_factor != NONE AND two_factor.last_step < $step;";
+        let updated: Vec<UserData> = self.db.query(query)
+            .bind(("step", step))
+            .bind(("user_id", user_id))
+            .await?
+            .take(0)?;
+        Ok(!updated.is_empty())
+    }
+
+    async fn take_recovery_code(&self, user_id: &str, code_hash: &str) -> anyhow::Result<bool> {
+        let query = "UPDATE users SET two_factor.recovery_codes -= $code_hash WHERE user_id = $user_id AND two_factor.recovery_codes CONTAINS $code_hash;";
+        let updated: Vec<UserData> = self.db.query(query)
+            .bind(("code_hash", code_hash))
+            .bind(("user_id", user_id))
+            .await?
+            .take(0)?;
+        Ok(!updated.is_empty())
+    }
+
+    async fn set_status(&self, user_id: &str, status: ConnectionState, last_seen: Option<u64>) -> anyhow::Result<()> {
+        let query = match last_seen {
+            Some(_) => "UPDATE users SET status = $status, last_seen = $last_seen WHERE user_id = $user_id;",
+            None => "UPDATE users SET status = $status WHERE user_id = $user_id;",
+        };
+        self.db.query(query)
+            .bind(("status", status))
+            .bind(("last_seen", last_seen))
+            .bind(("user_id", user_id))
+            .await?
+            .check()?;
+        Ok(())
+    }
+
+    async fn users_in_room(&self, room_id: &str) -> anyhow::Result<Vec<User>> {
+        let query = "SELECT user_id, username FROM users WHERE $room_id IN rooms;";
+        let mut response = self.db.query(query).bind(("room_id", room_id)).await?;
+        Ok(response.take(0)?)
+    }
+
+    async fn create_reset_token(&self, token: PasswordResetToken) -> anyhow::Result<()> {
+        let _: Option<PasswordResetToken> = self.db.create(("password_resets", token.token_hash.clone())).content(token).await?;
+        Ok(())
+    }
+
+    async fn take_reset_token(&self, token_hash: &str) -> anyhow::Result<Option<PasswordResetToken>> {
+        Ok(self.db.delete(("password_resets", token_hash)).await?)
+    }
+
+    async fn delete_reset_tokens(&self, user_id: &str) -> anyhow::Result<()> {
+        self.db.query("DELETE password_resets WHERE user_id = $user_id;")
+            .bind(("user_id", user_id))
+            .await?
+            .check()?;
+        Ok(())
+    }
+
+    async fn create_session(&self, session: SessionRecord) -> anyhow::Result<()> {
+        let _: Option<SessionRecord> = self.db.create(("sessions", session.session_id.clone())).content(session).await?;
+        Ok(())
+    }
+
+    async fn get_session(&self, session_id: &str) -> anyhow::Result<Option<SessionRecord>> {
+        Ok(self.db.select(("sessions", session_id)).await?)
+    }
+
+    async fn user_sessions(&self, user_id: &str) -> anyhow::Result<Vec<SessionRecord>> {
+        let sessions: Vec<SessionRecord> = self.db.query("SELECT * FROM sessions WHERE user_id = $user_id ORDER BY created_at ASC;")
+            .bind(("user_id", user_id))
+            .await?
+            .take(0)?;
+        Ok(sessions)
+    }
+
+    async fn touch_session(&self, session_id: &str, last_seen: u64) -> anyhow::Result<()> {
+        self.db.query("UPDATE type::thing('sessions', $session_id) SET last_seen = $last_seen;")
+            .bind(("session_id", session_id))
+            .bind(("last_seen", last_seen))
+            .await?
+            .check()?;
+        Ok(())
+    }
+
+    async fn delete_session(&self, session_id: &str) -> anyhow::Result<()> {
+        let _: Option<SessionRecord> = self.db.delete(("sessions", session_id)).await?;
+        Ok(())
+    }
+
+    async fn delete_user_sessions(&self, user_id: &str, except: Option<&str>) -> anyhow::Result<()> {
+        self.db.query("DELETE sessions WHERE user_id = $user_id AND session_id != $except;")
+            .bind(("user_id", user_id))
+            .bind(("except", except))
+            .await?
+            .check()?;
+        Ok(())
+    }
+
+    async fn delete_idle_sessions(&self, last_seen_before: u64) -> anyhow::Result<()> {
+        self.db.query("DELETE sessions WHERE last_seen < $last_seen_before;")
+            .bind(("last_seen_before", last_seen_before))
+            .await?
+            .check()?;
+        Ok(())
+    }
+
+    async fn create_lockout(&self, lockout: LoginLockout) -> anyhow::Result<()> {
+        let _: Option<LoginLockout> = self.db.create(("login_lockouts", lockout.lockout_id.clone())).content(lockout).await?;
+        Ok(())
+    }
+
+    async fn get_lockout(&self, lockout_id: &str) -> anyhow::Result<Option<LoginLockout>> {
+        Ok(self.db.select(("login_lockouts", lockout_id)).await?)
+    }
+
+    async fn recent_lockouts(&self, since: u64) -> anyhow::Result<Vec<LoginLockout>> {
+        let lockouts: Vec<LoginLockout> = self.db.query("SELECT * FROM login_lockouts WHERE locked_at >= $since ORDER BY locked_at DESC;")
+            .bind(("since", since))
+            .await?
+            .take(0)?;
+        Ok(lockouts)
+    }
+
+    async fn lift_lockout(&self, lockout_id: &str, lifted_by: &str, now: u64) -> anyhow::Result<()> {
+        let query = "UPDATE type::thing('login_lockouts', $lockout_id) SET expires_at = $now, lifted_by = $lifted_by WHERE expires_at > $now;";
+        self.db.query(query)
+            .bind(("lockout_id", lockout_id))
+            .bind(("lifted_by", lifted_by))
+            .bind(("now", now))
+            .await?
+            .check()?;
+        Ok(())
+    }
+
+    async fn create_room(&self, room: Room) -> anyhow::Result<()> {
+        let room_id = room.room_id.clone();
+        let users: Vec<String> = room.users.iter().cloned().collect();
+        let _: Option<Room> = self.db.create(("rooms", room_id.clone())).content(room).await?;
+        let query = "UPDATE users SET rooms += $room_id WHERE user_id IN $users AND rooms CONTAINSNOT $room_id;";
+        self.db.query(query)
+            .bind(("room_id", room_id))
+            .bind(("users", users))
+            .await?
+            .check()?;
+        Ok(())
+    }
+
+    async fn get_room(&self, room_id: &str) -> anyhow::Result<Option<Room>> {
+        let query = "SELECT * FROM rooms WHERE room_id = $room_id;";
+        let mut response = self.db.query(query).bind(("room_id", room_id)).await?;
+        Ok(response.take(0)?)
+    }
+
+    async fn add_room_member(&self, room_id: &str, user_id: &str) -> anyhow::Result<()> {
+        if let Some(room) = self.get_room(room_id).await? {
+            if room.direct && !room.users.contains(user_id) {
+                anyhow::bail!("room {} is a direct conversation and cannot take more members", room_id);
+            }
+        }
+        let query = "UPDATE rooms SET users += $user_id WHERE room_id = $room_id AND users CONTAINSNOT $user_id;
+            UPDATE users SET rooms += $room_id WHERE user_id = $user_id AND rooms CONTAINSNOT $room_id;";
+        self.db.query(query)
+            .bind(("user_id", user_id))
+            .bind(("room_id", room_id))
+            .await?
+            .check()?;
+        Ok(())
+    }
+
+    async fn remove_room_member(&self, room_id: &str, user_id: &str) -> anyhow::Result<()> {
+        let query = "UPDATE rooms SET users -= $user_id WHERE room_id = $room_id;
+            UPDATE users SET rooms -= $room_id WHERE user_id = $user_id;";
+        self.db.query(query)
+            .bind(("user_id", user_id))
+            .bind(("room_id", room_id))
+            .await?
+            .check()?;
+        if let Some(room) = self.get_room(room_id).await? {
+            if room.roles.contains_key(user_id) {
+                self.set_room_role(room_id, user_id, RoomRole::Member).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn set_room_role(&self, room_id: &str, user_id: &str, role: RoomRole) -> anyhow::Result<()> {
+        let role = match role {
+            RoomRole::Member => None,
+            role => Some(role),
+        };
+        self.set_room_entry(room_id, "roles", user_id, role).await
+    }
+
+    async fn set_ban(&self, room_id: &str, user_id: &str, ban: Option<RoomBan>) -> anyhow::Result<()> {
+        self.set_room_entry(room_id, "bans", user_id, ban).await
+    }
+
+    async fn set_mute(&self, room_id: &str, user_id: &str, mute: Option<RoomMute>) -> anyhow::Result<()> {
+        self.set_room_entry(room_id, "mutes", user_id, mute).await
+    }
+
+    async fn rename_room(&self, room_id: &str, name: &str) -> anyhow::Result<()> {
+        let query = "UPDATE type::thing('rooms', $room_id) SET name = $name;";
+        self.db.query(query)
+            .bind(("room_id", room_id))
+            .bind(("name", name))
+            .await?
+            .check()?;
+        Ok(())
+    }
+
+    async fn create_message(&self, message: BasicMessage) -> anyhow::Result<u64> {
+        let mut attempt = 1;
+        loop {
+            match self.insert_message(&message).await {
+                Err(e) if attempt < CREATE_MESSAGE_ATTEMPTS && is_conflict(&e) => {
+                    actix_web::rt::time::sleep(Duration::from_millis(10 * attempt as u64)).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    async fn get_message(&self, message_id: &str) -> anyhow::Result<Option<BasicMessage>> {
+        Ok(self.db.select(("messages", message_id)).await?)
+    }
+
+    async fn edit_message(&self, previous: &BasicMessage, content: &str, edited_at: u64) -> anyhow::Result<()> {
+        let query = "BEGIN TRANSACTION;
+            CREATE revisions CONTENT $revision;
+            UPDATE type::thing('messages', $message_id) SET content = $content, edited_at = $edited_at;
+            COMMIT TRANSACTION;";
+        let revision = MessageRevision {
+            message_id: previous.message_id.clone(),
+            content: previous.content.clone(),
+            timestamp: previous.edited_at.unwrap_or(previous.timestamp),
+        };
+        self.db.query(query)
+            .bind(("revision", revision))
+            .bind(("message_id", previous.message_id.clone()))
+            .bind(("content", content))
+            .bind(("edited_at", edited_at))
+            .await?
+            .check()?;
+        Ok(())
+    }
+
+    async fn message_revisions(&self, message_id: &str) -> anyhow::Result<Vec<MessageRevision>> {
+        let query = "SELECT * FROM revisions WHERE message_id = $message_id ORDER BY timestamp ASC;";
+        let mut response = self.db.query(query).bind(("message_id", message_id)).await?;
+        Ok(response.take(0)?)
+    }
+
+    async fn delete_message(&self, message_id: &str, deleted_at: u64) -> anyhow::Result<()> {
+        let query = "BEGIN TRANSACTION;
+            UPDATE type::thing('messages', $message_id) SET content = '', image = NONE, attachments = [], deleted_at = $deleted_at;
+            DELETE revisions WHERE message_id = $message_id;
+            COMMIT TRANSACTION;";
+        self.db.query(query)
+            .bind(("message_id", message_id))
+            .bind(("deleted_at", deleted_at))
+            .await?
+            .check()?;
+        Ok(())
+    }
+
+    async fn message_page(&self, room_id: &str, cursor: HistoryCursor, limit: usize) -> anyhow::Result<HistoryPage> {
+        let query = match cursor {
+            HistoryCursor::Latest => "SELECT * FROM messages WHERE room_id = $room_id
+                ORDER BY seq DESC, timestamp DESC, message_id DESC LIMIT $limit;",
+            HistoryCursor::Before(_) => "SELECT * FROM messages WHERE room_id = $room_id
+                AND (seq < $seq OR (seq = $seq AND (timestamp < $timestamp OR (timestamp = $timestamp AND message_id < $message_id))))
+                ORDER BY seq DESC, timestamp DESC, message_id DESC LIMIT $limit;",
+            HistoryCursor::After(_) => "SELECT * FROM messages WHERE room_id = $room_id
+                AND (seq > $seq OR (seq = $seq AND (timestamp > $timestamp OR (timestamp = $timestamp AND message_id > $message_id))))
+                ORDER BY seq ASC, timestamp ASC, message_id ASC LIMIT $limit;",
+            HistoryCursor::Range { .. } => "SELECT * FROM messages WHERE room_id = $room_id
+                AND seq >= $from_seq AND seq <= $to_seq
+                ORDER BY seq ASC LIMIT $limit;",
+        };
+        let mut request = self.db.query(query)
+            .bind(("room_id", room_id))
+            .bind(("limit", limit + 1));
+        if let HistoryCursor::Before(position) | HistoryCursor::After(position) = &cursor {
+            request = request
+                .bind(("seq", position.seq))
+                .bind(("timestamp", position.timestamp))
+                .bind(("message_id", position.message_id.clone()));
+        }
+        if let HistoryCursor::Range { from_seq, to_seq } = cursor {
+            request = request
+                .bind(("from_seq", from_seq))
+                .bind(("to_seq", to_seq));
+        }
+        let rows: Vec<BasicMessage> = request.await?.take(0)?;
+        Ok(HistoryPage::from_walk(rows, &cursor, limit))
+    }
+
+    async fn create_attachment(&self, attachment: AttachmentMeta) -> anyhow::Result<()> {
+        let _: Option<AttachmentMeta> = self.db.create(("attachments", attachment.attachment_id.clone())).content(attachment).await?;
+        Ok(())
+    }
+
+    async fn get_attachment(&self, attachment_id: &str) -> anyhow::Result<Option<AttachmentMeta>> {
+        Ok(self.db.select(("attachments", attachment_id)).await?)
+    }
+
+    async fn user_attachments(&self, user_id: &str) -> anyhow::Result<Vec<AttachmentMeta>> {
+        let attachments: Vec<AttachmentMeta> = self.db.query("SELECT * FROM attachments WHERE uploader_id = $user_id ORDER BY created_at ASC;")
+            .bind(("user_id", user_id))
+            .await?
+            .take(0)?;
+        Ok(attachments)
+    }
+
+    async fn user_attachment_usage(&self, user_id: &str) -> anyhow::Result<u64> {
+        let usage: Option<u64> = self.db.query("RETURN math::sum((SELECT VALUE size FROM attachments WHERE uploader_id = $user_id));")
+            .bind(("user_id", user_id))
+            .await?
+            .take(0)?;
+        Ok(usage.unwrap_or(0))
+    }
+
+    async fn room_attachment_usage(&self, room_id: &str) -> anyhow::Result<u64> {
+        let usage: Option<u64> = self.db.query("RETURN math::sum((SELECT VALUE size FROM attachments WHERE room_id = $room_id));")
+            .bind(("room_id", room_id))
+            .await?
+            .take(0)?;
+        Ok(usage.unwrap_or(0))
+    }
+
+    async fn delete_attachment(&self, attachment_id: &str) -> anyhow::Result<()> {
+        let _: Option<AttachmentMeta> = self.db.delete(("attachments", attachment_id)).await?;
+        Ok(())
+    }
+
+    async fn blob_in_use(&self, sha256: &str) -> anyhow::Result<bool> {
+        let in_use: Option<bool> = self.db.query("RETURN count((SELECT * FROM attachments WHERE sha256 = $sha256 LIMIT 1)) > 0;")
+            .bind(("sha256", sha256))
+            .await?
+            .take(0)?;
+        Ok(in_use.unwrap_or(false))
+    }
+
+    async fn unreferenced_attachments(&self, created_before: u64) -> anyhow::Result<Vec<AttachmentMeta>> {
+        let query = "LET $referenced = array::union(
+                array::flatten((SELECT VALUE attachments.*.attachment_id FROM messages)),
+                (SELECT VALUE image.attachment_id FROM messages WHERE image != NONE)
+            );
+            SELECT * FROM attachments WHERE created_at < $created_before AND attachment_id NOTINSIDE $referenced;";
+        let attachments: Vec<AttachmentMeta> = self.db.query(query)
+            .bind(("created_before", created_before))
+            .await?
+            .take(1)?;
+        Ok(attachments)
+    }
+}
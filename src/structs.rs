@@ -4,7 +4,7 @@ use std::fmt;
 
 use validator::Validate;
 
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct UserData {
@@ -14,28 +14,199 @@ pub struct UserData {
     pub hashed_password: String,
     pub status: ConnectionState,
     pub rooms: Vec<String>,
+    #[serde(default)]
+    pub last_seen: Option<u64>,
+    // Sessions record this at login and stop being accepted once it changes
+    #[serde(default)]
+    pub session_epoch: u64,
+    // Whether the user has opened the link emailed to login_username
+    #[serde(default)]
+    pub email_verified: bool,
+    #[serde(default)]
+    pub two_factor: Option<TwoFactor>,
+    // Secret handed out by enrollment, until a code from it is confirmed
+    #[serde(default)]
+    pub pending_totp_secret: Option<String>,
 }
 
+// Confirmed TOTP enrollment, with SHA-256 hashes of the recovery codes not yet used
 #[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TwoFactor {
+    // Base32, as shown to authenticator apps
+    pub secret: String,
+    pub recovery_codes: Vec<String>,
+    // Latest time step a code was accepted for, so no code works twice
+    pub last_step: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
 pub enum ConnectionState {
     Online,
+    Away,
+    DoNotDisturb,
+    Invisible,
+    #[default]
     Offline,
 }
 
+impl ConnectionState {
+    // What other users get to see; Invisible users appear offline
+    pub fn visible(self) -> Self {
+        match self {
+            ConnectionState::Invisible => ConnectionState::Offline,
+            state => state,
+        }
+    }
+}
+
 impl fmt::Display for ConnectionState {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
-            ConnectionState::Online => write!(f, "Offline"),
+            ConnectionState::Online => write!(f, "Online"),
+            ConnectionState::Away => write!(f, "Away"),
+            ConnectionState::DoNotDisturb => write!(f, "Do Not Disturb"),
+            ConnectionState::Invisible => write!(f, "Invisible"),
             ConnectionState::Offline => write!(f, "Offline"),
         }
     }
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, Default)]
 pub struct Room {
     pub name: String,
     pub room_id: String,
     pub users: HashSet<String>,
+    // Members without an entry are plain members
+    #[serde(default)]
+    pub roles: HashMap<String, RoomRole>,
+    // One-to-one conversation, always exactly two members
+    #[serde(default)]
+    pub direct: bool,
+    #[serde(default)]
+    pub bans: HashMap<String, RoomBan>,
+    #[serde(default)]
+    pub mutes: HashMap<String, RoomMute>,
+    // seq of the newest message in the room
+    #[serde(default)]
+    pub last_seq: u64,
+}
+
+// expires_at is a unix timestamp in seconds, None means until lifted
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RoomBan {
+    pub reason: Option<String>,
+    pub expires_at: Option<u64>,
+    pub banned_by: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RoomMute {
+    pub expires_at: Option<u64>,
+    pub muted_by: String,
+}
+
+// One upload of a file into a room. Uploads of identical content share the
+// blob named by sha256.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AttachmentMeta {
+    pub attachment_id: String,
+    pub sha256: String,
+    pub filename: String,
+    pub content_type: String,
+    pub size: u64,
+    pub uploader_id: String,
+    pub room_id: String,
+    pub created_at: u64,
+}
+
+// Only a hash of the token is kept, so a leaked store cannot be used to reset passwords
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PasswordResetToken {
+    pub token_hash: String,
+    pub user_id: String,
+    pub expires_at: u64,
+}
+
+// A logged in browser or device. Its id is kept in the cookie session, and
+// deleting the record logs that session out.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SessionRecord {
+    pub session_id: String,
+    pub user_id: String,
+    pub created_at: u64,
+    pub last_seen: u64,
+    pub user_agent: Option<String>,
+    pub ip: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum LockoutKind {
+    Account,
+    Ip,
+}
+
+// A lockout started by repeated failed logins, kept after it ends so admins can review it
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LoginLockout {
+    pub lockout_id: String,
+    pub kind: LockoutKind,
+    // Lowercased login name as submitted, whether or not an account uses it, or the client IP
+    pub target: String,
+    pub failures: u64,
+    pub locked_at: u64,
+    pub expires_at: u64,
+    // Admin who ended the lockout early
+    #[serde(default)]
+    pub lifted_by: Option<String>,
+}
+
+impl AttachmentMeta {
+    pub fn url(&self) -> String {
+        format!("/attachments/{}", self.attachment_id)
+    }
+
+    pub fn thumbnail_url(&self) -> String {
+        format!("/attachments/{}/thumbnail", self.attachment_id)
+    }
+}
+
+impl Room {
+    // Both users map to the same id whichever of them starts the conversation
+    pub fn direct_room_id(user_a: &str, user_b: &str) -> String {
+        let (first, second) = if user_a <= user_b { (user_a, user_b) } else { (user_b, user_a) };
+        format!("dm_{}_{}", first, second)
+    }
+
+    pub fn direct_between(user_a: &str, user_b: &str) -> Room {
+        Room {
+            name: String::new(),
+            room_id: Room::direct_room_id(user_a, user_b),
+            users: HashSet::from([user_a.to_string(), user_b.to_string()]),
+            direct: true,
+            ..Room::default()
+        }
+    }
+
+    pub fn is_banned(&self, user_id: &str, now: u64) -> bool {
+        self.bans.get(user_id).is_some_and(|ban| ban.expires_at.is_none_or(|expires_at| expires_at > now))
+    }
+
+    pub fn is_muted(&self, user_id: &str, now: u64) -> bool {
+        self.mutes.get(user_id).is_some_and(|mute| mute.expires_at.is_none_or(|expires_at| expires_at > now))
+    }
+
+    // For a direct room, the member that isn't user_id
+    pub fn other_member(&self, user_id: &str) -> Option<&String> {
+        self.users.iter().find(|member| *member != user_id)
+    }
+}
+
+// Ordered from least to most privileged
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum RoomRole {
+    Member,
+    Moderator,
+    Owner,
 }
 
 #[derive(Deserialize, Validate)]
@@ -51,9 +222,3 @@ pub struct User {
     pub user_id: String,
     pub username: String,
 }
-
-
-#[derive(Deserialize)]
-pub struct RoomUsers {
-    pub users: Vec<String>,
-}
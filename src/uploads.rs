@@ -0,0 +1,434 @@
+use actix_multipart::Field;
+use actix_web::rt::task::spawn_blocking;
+use actix_web::web::Bytes as Chunk;
+use anyhow::Context;
+use futures_util::TryStreamExt;
+use image::metadata::Orientation;
+use image::{DynamicImage, GenericImageView, ImageFormat, ImageReader};
+use img_parts::{Bytes, DynImage, ImageEXIF};
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::{Cursor, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver};
+use uuid::Uuid;
+use crate::config::UploadsConfig;
+
+// Longest magic number checked when sniffing
+const SNIFF_LEN: usize = 12;
+// Thumbnails fit in a square of this many pixels
+const THUMBNAIL_SIZE: u32 = 320;
+const BLOB_DIR: &str = "blobs";
+const THUMBNAIL_DIR: &str = "thumbnails";
+const MAX_FILENAME_LEN: usize = 255;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImageKind {
+    Png,
+    Jpeg,
+    Gif,
+    Webp,
+}
+
+impl ImageKind {
+    // Identifies the file from its leading bytes, ignoring whatever type the client claimed
+    pub fn sniff(bytes: &[u8]) -> Option<ImageKind> {
+        if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+            Some(ImageKind::Png)
+        } else if bytes.starts_with(b"\xff\xd8\xff") {
+            Some(ImageKind::Jpeg)
+        } else if bytes.starts_with(b"GIF87a") || bytes.starts_with(b"GIF89a") {
+            Some(ImageKind::Gif)
+        } else if bytes.len() >= SNIFF_LEN && &bytes[0..4] == b"RIFF" && &bytes[8..12] == b"WEBP" {
+            Some(ImageKind::Webp)
+        } else {
+            None
+        }
+    }
+
+    pub fn from_content_type(content_type: &str) -> Option<ImageKind> {
+        [ImageKind::Png, ImageKind::Jpeg, ImageKind::Gif, ImageKind::Webp]
+            .into_iter()
+            .find(|kind| kind.content_type() == content_type)
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ImageKind::Png => "image/png",
+            ImageKind::Jpeg => "image/jpeg",
+            ImageKind::Gif => "image/gif",
+            ImageKind::Webp => "image/webp",
+        }
+    }
+
+    // Jpeg thumbnails stay jpeg, everything else becomes png to keep transparency
+    pub fn thumbnail_kind(self) -> ImageKind {
+        match self {
+            ImageKind::Jpeg => ImageKind::Jpeg,
+            _ => ImageKind::Png,
+        }
+    }
+
+    fn format(self) -> ImageFormat {
+        match self {
+            ImageKind::Png => ImageFormat::Png,
+            ImageKind::Jpeg => ImageFormat::Jpeg,
+            ImageKind::Gif => ImageFormat::Gif,
+            ImageKind::Webp => ImageFormat::WebP,
+        }
+    }
+}
+
+// Image types only come from sniffing, so a file is never served inline as an
+// image (or as svg) just because of its name. Other formats we can recognise
+// are sniffed too, the rest are guessed from the filename extension.
+fn detect_content_type(head: &[u8], filename: &str) -> String {
+    if let Some(kind) = ImageKind::sniff(head) {
+        return kind.content_type().to_string();
+    }
+    if head.starts_with(b"%PDF-") {
+        return "application/pdf".to_string();
+    }
+    if head.starts_with(b"\x1f\x8b") {
+        return "application/gzip".to_string();
+    }
+    match mime_guess::from_path(filename).first() {
+        Some(guess) if guess.type_() != mime_guess::mime::IMAGE => guess.to_string(),
+        _ if head.starts_with(b"PK\x03\x04") => "application/zip".to_string(),
+        _ => "application/octet-stream".to_string(),
+    }
+}
+
+// Keeps only the last path component of a client supplied filename, without control characters
+pub fn sanitize_filename(filename: Option<&str>) -> String {
+    let name = filename.unwrap_or_default();
+    let name = name.rsplit(['/', '\\']).next().unwrap_or_default();
+    let name: String = name.chars().filter(|c| !c.is_control()).take(MAX_FILENAME_LEN).collect();
+    match name.trim() {
+        "" | "." | ".." => "file".to_string(),
+        trimmed => trimmed.to_string(),
+    }
+}
+
+// EXIF can carry the camera, time and GPS position a photo was taken at. GIF has no EXIF.
+// A photo taken sideways is turned upright first, as its Orientation goes with the EXIF.
+fn strip_exif(kind: ImageKind, data: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+    if kind == ImageKind::Gif {
+        return Ok(data);
+    }
+    let data = Bytes::from(data);
+    let mut image = DynImage::from_bytes(data.clone())?
+        .context("image format is not supported for EXIF removal")?;
+    match image.exif().and_then(|exif| Orientation::from_exif_chunk(&exif)) {
+        Some(orientation) if orientation != Orientation::NoTransforms => {
+            // Re-encoding the pixels leaves every EXIF field behind
+            let mut decoded = image::load_from_memory_with_format(&data, kind.format())?;
+            decoded.apply_orientation(orientation);
+            let mut encoded = Cursor::new(Vec::new());
+            decoded.write_to(&mut encoded, kind.format())?;
+            Ok(encoded.into_inner())
+        }
+        _ => {
+            image.set_exif(None);
+            Ok(image.encoder().bytes().to_vec())
+        }
+    }
+}
+
+fn write_thumbnail(image: &DynamicImage, kind: ImageKind, path: &Path) -> anyhow::Result<()> {
+    let thumbnail = image.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE);
+    let mut encoded = Cursor::new(Vec::new());
+    match kind.thumbnail_kind() {
+        ImageKind::Jpeg => DynamicImage::ImageRgb8(thumbnail.to_rgb8()).write_to(&mut encoded, ImageFormat::Jpeg)?,
+        _ => thumbnail.write_to(&mut encoded, ImageFormat::Png)?,
+    }
+    std::fs::write(path, encoded.into_inner())?;
+    Ok(())
+}
+
+fn is_sha256(sha256: &str) -> bool {
+    sha256.len() == 64 && sha256.bytes().all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+}
+
+#[derive(Debug)]
+pub enum UploadError {
+    TooLarge(u64),
+    // Would take the uploader or the room past its storage quota
+    OverQuota,
+    // Sniffed as an image but could not be parsed as one
+    InvalidImage,
+    Multipart(actix_multipart::MultipartError),
+    Io(std::io::Error),
+}
+
+impl From<actix_multipart::MultipartError> for UploadError {
+    fn from(e: actix_multipart::MultipartError) -> Self {
+        UploadError::Multipart(e)
+    }
+}
+
+impl From<std::io::Error> for UploadError {
+    fn from(e: std::io::Error) -> Self {
+        UploadError::Io(e)
+    }
+}
+
+// Writes the chunks of an upload until the None marking its end, leaving it
+// ready to be kept under blobs/ by its hash. Images have their EXIF data
+// removed before they are hashed, so the stored blob never contains it;
+// InvalidData means an image could not be parsed. Blocks, so run it off the
+// async executor.
+fn store_blob(dir: &Path, filename: &str, chunks: Receiver<Option<Chunk>>) -> std::io::Result<StoredBlob> {
+    let partial_path = dir.join(format!("{}.part", Uuid::new_v4().to_string().replace('-', "")));
+    let result = write_blob(dir, filename, &partial_path, chunks);
+    if result.is_err() {
+        let _ = std::fs::remove_file(&partial_path);
+    }
+    result
+}
+
+fn write_blob(dir: &Path, filename: &str, partial_path: &Path, chunks: Receiver<Option<Chunk>>) -> std::io::Result<StoredBlob> {
+    let mut file = File::create(partial_path)?;
+    let mut hasher = Sha256::new();
+    let mut head = Vec::with_capacity(SNIFF_LEN);
+    let mut size = 0u64;
+    loop {
+        let chunk = match chunks.recv() {
+            Ok(Some(chunk)) => chunk,
+            Ok(None) => break,
+            Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::Interrupted, "upload was not completed")),
+        };
+        size += chunk.len() as u64;
+        if head.len() < SNIFF_LEN {
+            head.extend_from_slice(&chunk[..chunk.len().min(SNIFF_LEN - head.len())]);
+        }
+        hasher.update(&chunk);
+        file.write_all(&chunk)?;
+    }
+    drop(file);
+
+    let mut sha256 = format!("{:x}", hasher.finalize());
+    if let Some(kind) = ImageKind::sniff(&head) {
+        let stripped = strip_exif(kind, std::fs::read(partial_path)?)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        sha256 = format!("{:x}", Sha256::digest(&stripped));
+        size = stripped.len() as u64;
+        std::fs::write(partial_path, stripped)?;
+    }
+    Ok(StoredBlob {
+        blob_path: dir.join(BLOB_DIR).join(&sha256),
+        partial_path: partial_path.to_path_buf(),
+        sha256,
+        content_type: detect_content_type(&head, filename),
+        size,
+    })
+}
+
+// Content of an upload once it is on disk, waiting to be kept under blobs/.
+// Dropping it without keeping it deletes the file.
+pub struct StoredBlob {
+    pub sha256: String,
+    pub content_type: String,
+    pub size: u64,
+    partial_path: PathBuf,
+    blob_path: PathBuf,
+}
+
+impl StoredBlob {
+    // Moves the content under blobs/, unless a blob with the same hash is there already. Blocks.
+    pub fn keep(self) -> std::io::Result<()> {
+        if !self.blob_path.exists() {
+            std::fs::rename(&self.partial_path, &self.blob_path)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for StoredBlob {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.partial_path);
+    }
+}
+
+// Stores file contents once per SHA-256 under blobs/, with image thumbnails
+// under the same name in thumbnails/. Which uploads point at which blob is
+// kept in the ChatStore.
+#[derive(Clone)]
+pub struct UploadStore {
+    dir: PathBuf,
+    max_size: u64,
+    pub user_quota: u64,
+    pub room_quota: u64,
+}
+
+impl UploadStore {
+    pub fn new(config: &UploadsConfig) -> anyhow::Result<Self> {
+        for sub_dir in [BLOB_DIR, THUMBNAIL_DIR] {
+            let path = config.dir.join(sub_dir);
+            std::fs::create_dir_all(&path)
+                .with_context(|| format!("failed to create uploads directory {}", path.display()))?;
+        }
+        Ok(UploadStore {
+            dir: config.dir.clone(),
+            max_size: config.max_size,
+            user_quota: config.user_quota,
+            room_quota: config.room_quota,
+        })
+    }
+
+    // Streams field to disk, rejecting it as soon as it is larger than max_size
+    // or than the quota_left of the uploader. The chunks are hashed and written
+    // on a blocking thread as they arrive.
+    pub async fn save(&self, mut field: Field, filename: &str, quota_left: u64) -> Result<StoredBlob, UploadError> {
+        let (chunks, received) = mpsc::channel();
+        let dir = self.dir.clone();
+        let filename = filename.to_string();
+        let writer = spawn_blocking(move || store_blob(&dir, &filename, received));
+        let streamed = self.stream_chunks(&mut field, quota_left, &chunks).await;
+        if streamed.is_ok() {
+            let _ = chunks.send(None);
+        }
+        drop(chunks);
+        let stored = writer.await.map_err(std::io::Error::other)?;
+        streamed?;
+        stored.map_err(|e| match e.kind() {
+            std::io::ErrorKind::InvalidData => UploadError::InvalidImage,
+            _ => UploadError::Io(e),
+        })
+    }
+
+    // Sends the chunks of field on, then returns without sending the end of the
+    // upload if a limit is crossed
+    async fn stream_chunks(&self, field: &mut Field, quota_left: u64, chunks: &mpsc::Sender<Option<Chunk>>) -> Result<(), UploadError> {
+        let mut size = 0u64;
+        while let Some(chunk) = field.try_next().await? {
+            size += chunk.len() as u64;
+            if size > self.max_size {
+                return Err(UploadError::TooLarge(self.max_size));
+            }
+            if size > quota_left {
+                return Err(UploadError::OverQuota);
+            }
+            // The writer stopped on an error, which it returns itself
+            if chunks.send(Some(chunk)).is_err() {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    // Only well formed hashes map to a path, so nothing outside dir can be reached
+    pub fn blob_path(&self, sha256: &str) -> Option<PathBuf> {
+        is_sha256(sha256).then(|| self.dir.join(BLOB_DIR).join(sha256))
+    }
+
+    pub fn thumbnail_path(&self, sha256: &str) -> Option<PathBuf> {
+        is_sha256(sha256).then(|| self.dir.join(THUMBNAIL_DIR).join(sha256))
+    }
+
+    // Deletes a blob and its thumbnail; only call once no attachment refers to it. Blocks.
+    pub fn remove_blob(&self, sha256: &str) -> std::io::Result<()> {
+        for path in [self.blob_path(sha256), self.thumbnail_path(sha256)].into_iter().flatten() {
+            match std::fs::remove_file(path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    // Width and height of an image blob, generating its thumbnail the first time.
+    // Decodes the whole image, so run it off the async executor.
+    pub fn prepare_image(&self, sha256: &str) -> anyhow::Result<Option<(u32, u32)>> {
+        let (Some(blob_path), Some(thumbnail_path)) = (self.blob_path(sha256), self.thumbnail_path(sha256)) else {
+            return Ok(None);
+        };
+        let data = match std::fs::read(blob_path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let Some(kind) = ImageKind::sniff(&data) else {
+            return Ok(None);
+        };
+        if thumbnail_path.exists() {
+            return Ok(Some(ImageReader::with_format(Cursor::new(&data), kind.format()).into_dimensions()?));
+        }
+        let image = image::load_from_memory_with_format(&data, kind.format())?;
+        write_thumbnail(&image, kind, &thumbnail_path)?;
+        Ok(Some(image.dimensions()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sanitize_filename_keeps_only_the_last_component() {
+        assert_eq!(sanitize_filename(Some("../../etc/passwd")), "passwd");
+        assert_eq!(sanitize_filename(Some("C:\\Users\\me\\photo.png")), "photo.png");
+        assert_eq!(sanitize_filename(Some("  notes.txt ")), "notes.txt");
+    }
+
+    #[test]
+    fn sanitize_filename_falls_back_to_file() {
+        for name in [None, Some(""), Some("."), Some(".."), Some("dir/"), Some("\n\t")] {
+            assert_eq!(sanitize_filename(name), "file", "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn sanitize_filename_drops_control_characters_and_caps_length() {
+        assert_eq!(sanitize_filename(Some("a\u{0}b\u{1b}c.txt")), "abc.txt");
+        assert_eq!(sanitize_filename(Some(&"x".repeat(1000))).chars().count(), MAX_FILENAME_LEN);
+    }
+
+    #[test]
+    fn sniff_recognizes_magic_bytes() {
+        assert_eq!(ImageKind::sniff(b"\x89PNG\r\n\x1a\n...."), Some(ImageKind::Png));
+        assert_eq!(ImageKind::sniff(b"\xff\xd8\xff\xe0"), Some(ImageKind::Jpeg));
+        assert_eq!(ImageKind::sniff(b"GIF89a"), Some(ImageKind::Gif));
+        assert_eq!(ImageKind::sniff(b"GIF87a"), Some(ImageKind::Gif));
+        assert_eq!(ImageKind::sniff(b"RIFF\x00\x00\x00\x00WEBP"), Some(ImageKind::Webp));
+    }
+
+    // A little endian TIFF header with one IFD holding just the Orientation tag
+    fn exif_with_orientation(orientation: u8) -> Bytes {
+        let mut exif = b"II*\x00\x08\x00\x00\x00\x01\x00\x12\x01\x03\x00\x01\x00\x00\x00".to_vec();
+        exif.extend_from_slice(&[orientation, 0, 0, 0, 0, 0, 0, 0]);
+        Bytes::from(exif)
+    }
+
+    fn png_with_exif(width: u32, height: u32, exif: Bytes) -> Vec<u8> {
+        let mut encoded = Cursor::new(Vec::new());
+        DynamicImage::new_rgb8(width, height).write_to(&mut encoded, ImageFormat::Png).unwrap();
+        let mut image = DynImage::from_bytes(Bytes::from(encoded.into_inner())).unwrap().unwrap();
+        image.set_exif(Some(exif));
+        image.encoder().bytes().to_vec()
+    }
+
+    #[test]
+    fn strip_exif_turns_the_image_upright_before_removing_it() {
+        let stripped = strip_exif(ImageKind::Png, png_with_exif(3, 2, exif_with_orientation(6))).unwrap();
+        let image = DynImage::from_bytes(Bytes::from(stripped.clone())).unwrap().unwrap();
+        assert!(image.exif().is_none());
+        assert_eq!(image::load_from_memory(&stripped).unwrap().dimensions(), (2, 3));
+    }
+
+    #[test]
+    fn strip_exif_keeps_upright_images_as_they_are() {
+        let stripped = strip_exif(ImageKind::Png, png_with_exif(3, 2, exif_with_orientation(1))).unwrap();
+        let image = DynImage::from_bytes(Bytes::from(stripped.clone())).unwrap().unwrap();
+        assert!(image.exif().is_none());
+        assert_eq!(image::load_from_memory(&stripped).unwrap().dimensions(), (3, 2));
+    }
+
+    #[test]
+    fn sniff_rejects_everything_else() {
+        assert_eq!(ImageKind::sniff(b""), None);
+        assert_eq!(ImageKind::sniff(b"<svg xmlns=\"http://www.w3.org/2000/svg\"/>"), None);
+        assert_eq!(ImageKind::sniff(b"RIFF\x00\x00\x00\x00WAVE"), None);
+        assert_eq!(ImageKind::sniff(b"RIFF\x00\x00\x00\x00WEB"), None);
+    }
+}
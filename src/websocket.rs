@@ -1,82 +1,309 @@
 use crate::appstate::AppState;
+use crate::idempotency::{Claim, MAX_IDEMPOTENCY_KEY_LEN};
+use crate::rate_limit::{message_cost, RateLimitKey, INVALID_FRAME_COST};
+use crate::auth::current_session_id;
 use crate::message_structs::*;
-use crate::structs::{Room, User, UserData};
-use actix::{Actor, Addr, AsyncContext, Handler, StreamHandler};
+use crate::store::HistoryCursor;
+use crate::permissions::RoomAction;
+use crate::structs::{AttachmentMeta, ConnectionState, Room, RoomBan, RoomMute, RoomRole};
+use actix::{Actor, ActorContext, ActorFutureExt, Addr, AsyncContext, Handler, SpawnHandle, StreamHandler};
 use actix_session::Session;
 use actix_web::{web, HttpResponse, Error};
 use actix_web_actors::ws;
 use chrono::Utc;
 use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
-use surrealdb::engine::remote::ws::Client;
-use surrealdb::Surreal;
 use uuid::Uuid;
+use serde::Deserialize;
 use serde_json::json;
-use std::time::{Instant, Duration};
+use std::time::Duration;
 
-const MESSAGE_TOKENS: u32 = 100;
-const TIME_FRAME: Duration = Duration::from_secs(10);
+const HISTORY_PAGE_SIZE: usize = 50;
+const MAX_HISTORY_PAGE_SIZE: usize = 200;
+// A typing indicator lapses unless the client refreshes it within this window
+const TYPING_TIMEOUT: Duration = Duration::from_secs(5);
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+const MAX_MESSAGE_ATTACHMENTS: usize = 10;
+// Stores keep seq within i64
+const MAX_SEQ: u64 = i64::MAX as u64;
+// Longest ban or mute a duration_secs may ask for, leave it out for one until lifted
+const MAX_MODERATION_SECS: u64 = 10 * 365 * 24 * 60 * 60;
 
+// Where the answers to one client request go, tagged with that request's client_msg_id
+#[derive(Clone)]
+pub struct Reply {
+    addr: Addr<WsActor>,
+    client_msg_id: Option<String>,
+}
+
+impl Reply {
+    pub fn new(addr: Addr<WsActor>, client_msg_id: Option<String>) -> Self {
+        Reply { addr, client_msg_id }
+    }
+
+    pub fn send(&self, frame: String) {
+        self.addr.do_send(WsMessage(frame));
+    }
+
+    pub fn error(&self, code: ErrorCode, message: &str) {
+        let error = ErrorMessage::new(self.client_msg_id.clone(), code, message.to_string());
+        self.send(serde_json::to_string(&UserMessage::Error(error)).unwrap());
+    }
+
+    // Only requests that carried a client_msg_id are acknowledged
+    pub fn ack(&self, message_id: &str, timestamp: u64) {
+        if self.client_msg_id.is_none() {
+            return;
+        }
+        let ack = AckMessage {
+            client_msg_id: self.client_msg_id.clone(),
+            message_id: message_id.to_string(),
+            timestamp,
+        };
+        self.send(serde_json::to_string(&UserMessage::Ack(ack)).unwrap());
+    }
+
+    // For requests that did not create or change a message, id is what they acted on
+    pub fn ack_now(&self, id: &str) {
+        self.ack(id, Utc::now().timestamp() as u64);
+    }
+}
+
+// The client_msg_id sits in the object inside the variant, e.g. {"TSBasic": {"client_msg_id": "1", ..}}
+fn client_msg_id(frame: &serde_json::Value) -> Option<String> {
+    frame.as_object()?.values().next()?.get("client_msg_id")?.as_str().map(str::to_string)
+}
+
+// Sends the latest page of a room's history
 pub async fn get_messages(
     app_state: Arc<AppState>, 
     actor_addr: Addr<WsActor>, 
     room_id: String) {
-    if let Some(messages) = app_state.catch_up(&room_id).await {
-        for message in messages {
-            let serialized_msg = serde_json::to_string(&message).unwrap();
-            actor_addr.do_send(WsMessage(serialized_msg));
+    if let Some(page) = app_state.history_page(&room_id, HistoryCursor::Latest, HISTORY_PAGE_SIZE).await {
+        let serialized_msg = serde_json::to_string(&UserMessage::History(page)).unwrap();
+        actor_addr.do_send(WsMessage(serialized_msg));
+    }
+}
+
+pub async fn get_history(
+    app_state: Arc<AppState>,
+    reply: Reply,
+    user_id: String,
+    request: HistoryRequestMessage,
+) {
+    let cursor = match (&request.before, &request.after, request.from_seq, request.to_seq) {
+        (None, None, None, None) => Ok(HistoryCursor::Latest),
+        (Some(before), None, None, None) => before.parse().map(HistoryCursor::Before),
+        (None, Some(after), None, None) => after.parse().map(HistoryCursor::After),
+        (None, None, Some(from_seq), Some(to_seq)) if from_seq <= to_seq => Ok(HistoryCursor::Range { from_seq, to_seq }),
+        _ => {
+            reply.error(ErrorCode::InvalidRequest, "Set only one of before, after, or from_seq up to to_seq");
+            return;
+        }
+    };
+    let cursor = match cursor {
+        Ok(cursor) => cursor,
+        Err(_) => {
+            reply.error(ErrorCode::InvalidRequest, "Invalid history cursor");
+            return;
         }
+    };
+    match app_state.store.get_room(&request.room_id).await {
+        Ok(Some(room)) if room.users.contains(&user_id) => {}
+        Ok(_) => {
+            reply.error(ErrorCode::NotMember, "You are not a member of that room");
+            return;
+        }
+        Err(e) => {
+            log::error!("Failed to get room: fn get_history, error: {:?}", e);
+            reply.error(ErrorCode::Internal, "Failed to get history");
+            return;
+        }
+    }
+    let limit = request.limit.unwrap_or(HISTORY_PAGE_SIZE).clamp(1, MAX_HISTORY_PAGE_SIZE);
+    match app_state.history_page(&request.room_id, cursor, limit).await {
+        Some(page) => {
+            let serialized_msg = serde_json::to_string(&UserMessage::History(page)).unwrap();
+            reply.send(serialized_msg);
+            reply.ack_now(&request.room_id);
+        }
+        None => reply.error(ErrorCode::Internal, "Failed to get history"),
     }
 }
 
-pub async fn change_to_online(db: Arc<Surreal<Client>>, user_id: String) {
-    let query = "UPDATE users SET status = 'Online' WHERE user_id = $user_id;";
-    if let Err(e) = db.query(query).bind(("user_id", user_id)).await {
-        log::error!(
-            "Failed to change user to online in db: fn change_to_online, error: {:?}",
-            e
-        );
+// Sends a resumed connection the messages it missed in each room, then its other missed events
+async fn replay_missed(app_state: Arc<AppState>, actor_addr: Addr<WsActor>, rooms: Vec<(String, u64)>, events: Vec<String>) {
+    for (room_id, last_seen) in rooms {
+        let cursor = HistoryCursor::Range { from_seq: last_seen.saturating_add(1), to_seq: MAX_SEQ };
+        match app_state.history_page(&room_id, cursor, MAX_HISTORY_PAGE_SIZE).await {
+            Some(page) if !page.messages.is_empty() => {
+                let serialized_msg = serde_json::to_string(&UserMessage::History(page)).unwrap();
+                actor_addr.do_send(WsMessage(serialized_msg));
+            }
+            _ => {}
+        }
+    }
+    for frame in events {
+        actor_addr.do_send(WsMessage(frame));
     }
 }
 
-pub async fn change_to_offline(db: Arc<Surreal<Client>>, user_id: String) {
-    let query = "UPDATE users SET status = 'Offline' WHERE user_id = $user_id;";
-    if let Err(e) = db.query(query).bind(("user_id", user_id)).await {
+// Persists a presence change and tells everyone sharing a room with the user
+pub async fn publish_presence(state: Arc<AppState>, user_id: String, status: ConnectionState) {
+    let visible = status.visible();
+    let last_seen = match visible {
+        ConnectionState::Offline => Some(Utc::now().timestamp() as u64),
+        _ => None,
+    };
+    if let Err(e) = state.store.set_status(&user_id, status, last_seen).await {
         log::error!(
-            "Failed to change user to offline in db: fn change_to_offline, error: {:?}",
+            "Failed to change user status in db: fn publish_presence, error: {:?}",
             e
         );
     }
+    let message = UserMessage::Presence(PresenceMessage {
+        user_id: user_id.clone(),
+        status: visible,
+        last_seen,
+    });
+    let serialized_msg = serde_json::to_string(&message).unwrap();
+    state.broadcast_to_contacts(serialized_msg, &user_id).await;
 }
 
 pub struct WsActor {
     pub ws_id: String,
     pub user_id: String,
+    // The login session the connection was opened with
+    pub session_id: String,
     pub username: String,
     pub current_room: String,
     pub rooms: Vec<String>,
     pub state: Arc<AppState>,
-    pub request_token_count: u32,
-    pub start_time: Instant,
+    pub typing_timeout: Option<SpawnHandle>,
+    // Issued when the connection starts
+    pub resume_token: String,
+    // Taken when the connection starts, if it asked to resume an earlier one
+    pub resume_request: Option<ResumeRequest>,
+}
+
+pub struct ResumeRequest {
+    pub token: String,
+    // The last seq the client saw in each room it knows
+    pub last_seqs: HashMap<String, u64>,
 }
 
 impl WsActor {
-    fn reset_rate_limit(&mut self) {
-        self.request_token_count = 10;
-        self.start_time = Instant::now();
-    }
-    
-    fn check_and_update_rate_limit(&mut self) -> bool {
-        let elapsed = self.start_time.elapsed();
-        
-        // Check if the current period has exceeded the time frame
-        if elapsed > TIME_FRAME {
-            // Time frame exceeded, reset rate limiting counters
-            self.reset_rate_limit();
-            true
-        } else {
-            false
+    fn publish_presence(&self, change: Option<ConnectionState>) {
+        if let Some(status) = change {
+            actix::spawn(publish_presence(self.state.clone(), self.user_id.clone(), status));
+        }
+    }
+
+    fn set_presence(&mut self, status: ConnectionState, reply: &Reply) {
+        let chosen = match status {
+            ConnectionState::Online => None,
+            ConnectionState::Offline => {
+                reply.error(ErrorCode::InvalidRequest, "Offline cannot be chosen, use Invisible instead");
+                return;
+            }
+            chosen => Some(chosen),
+        };
+        let change = self.state.presence.choose(&self.user_id, chosen);
+        self.publish_presence(change);
+        reply.ack_now(&self.user_id);
+    }
+
+    fn start_typing(&mut self, ctx: &mut ws::WebsocketContext<Self>) {
+        match self.typing_timeout.take() {
+            Some(handle) => {
+                ctx.cancel_future(handle);
+            }
+            None => self.broadcast_typing(true),
         }
+        self.typing_timeout = Some(ctx.run_later(TYPING_TIMEOUT, |actor, _ctx| {
+            actor.typing_timeout = None;
+            actor.broadcast_typing(false);
+        }));
+    }
+
+    fn stop_typing(&mut self, ctx: &mut ws::WebsocketContext<Self>) {
+        if let Some(handle) = self.typing_timeout.take() {
+            ctx.cancel_future(handle);
+            self.broadcast_typing(false);
+        }
+    }
+
+    // A message from this connection to its current room
+    fn new_message(&self, content: String) -> BasicMessage {
+        BasicMessage {
+            content,
+            sender_id: self.user_id.clone(),
+            timestamp: Utc::now().timestamp() as u64,
+            message_id: Uuid::new_v4().to_string().replace('-', ""),
+            room_id: self.current_room.clone(),
+            ws_id: self.ws_id.clone(),
+            seq: 0,
+            edited_at: None,
+            deleted_at: None,
+            image: None,
+            attachments: Vec::new(),
+        }
+    }
+
+    fn broadcast_typing(&self, typing: bool) {
+        let message = UserMessage::Typing(TypingMessage {
+            sender_id: self.user_id.clone(),
+            room_id: self.current_room.clone(),
+            typing,
+        });
+        let serialized_msg = serde_json::to_string(&message).unwrap();
+        let app_state = self.state.clone();
+        let room_id = self.current_room.clone();
+        let user_id = self.user_id.clone();
+        actix::spawn(async move {
+            app_state.broadcast_to_others(serialized_msg, room_id, user_id).await;
+        });
+    }
+
+    // Switches this connection to room_id once membership is confirmed, then
+    // sends that room's user map and history.
+    fn change_room(&mut self, room_id: String, reply: Reply, ctx: &mut ws::WebsocketContext<Self>) {
+        let store = self.state.store.clone();
+        let lookup = async move { store.get_room(&room_id).await };
+        ctx.spawn(actix::fut::wrap_future::<_, Self>(lookup).map(move |result, actor, ctx| {
+            let room = match result {
+                Ok(Some(room)) if room.users.contains(&actor.user_id) => room,
+                Ok(_) => {
+                    reply.error(ErrorCode::NotMember, "You are not a member of that room");
+                    return;
+                }
+                Err(e) => {
+                    log::error!("Failed to get room: fn change_room, error: {:?}", e);
+                    reply.error(ErrorCode::Internal, "Failed to change room");
+                    return;
+                }
+            };
+            actor.stop_typing(ctx);
+            actor.current_room = room.room_id.clone();
+            reply.ack_now(&room.room_id);
+            let user_info = UserInfo::new(
+                actor.user_id.clone(),
+                actor.ws_id.clone(),
+                actor.username.clone(),
+                actor.resume_token.clone(),
+            );
+            ctx.spawn(actix::fut::wrap_future(get_users(
+                actor.state.clone(),
+                ctx.address(),
+                room.room_id.clone(),
+                user_info,
+            )));
+            ctx.spawn(actix::fut::wrap_future(get_messages(
+                actor.state.clone(),
+                ctx.address(),
+                room.room_id,
+            )));
+        }));
     }
 }
 
@@ -96,17 +323,52 @@ impl Actor for WsActor {
                 actor_registry.insert(self.user_id.clone(), hashmap);
             }
         }
-        let db = self.state.db.clone();
+        drop(actor_registry);
+        let change = self.state.presence.connect(&self.user_id, &self.ws_id);
+        self.publish_presence(change);
+        ctx.run_interval(IDLE_CHECK_INTERVAL, |actor, _ctx| {
+            let change = actor.state.presence.refresh(&actor.user_id);
+            actor.publish_presence(change);
+        });
+
+        let resumed = self.resume_request.take().and_then(|request| {
+            // Events about rooms joined while away are replayed too
+            let rooms: HashSet<String> = request.last_seqs.keys().chain(&self.rooms).cloned().collect();
+            let now = Utc::now().timestamp() as u64;
+            let resumed = self.state.resume.resume(&request.token, &self.user_id, &self.session_id, &rooms, now)?;
+            Some((resumed, request.last_seqs))
+        });
+        if let Some((resumed, _)) = &resumed {
+            if self.rooms.contains(&resumed.room_id) {
+                self.current_room = resumed.room_id.clone();
+            }
+        }
+        self.resume_token = self.state.resume.issue(&self.user_id, &self.session_id, &self.current_room);
+
         let app_state = self.state.clone();
+        if let Some((resumed, last_seqs)) = resumed {
+            let message = UserMessage::Resumed(ResumedMessage {
+                ws_id: self.ws_id.clone(),
+                room_id: self.current_room.clone(),
+                resume_token: self.resume_token.clone(),
+            });
+            ctx.text(serde_json::to_string(&message).unwrap());
+            // Only rooms the client already knows and is still in get their messages replayed
+            let rooms: Vec<(String, u64)> = last_seqs.into_iter()
+                .filter(|(room_id, _)| self.rooms.contains(room_id))
+                .collect();
+            ctx.spawn(actix::fut::wrap_future(replay_missed(app_state, ctx.address(), rooms, resumed.events)));
+            return;
+        }
         let room_id = self.current_room.clone();
-        let user_id = self.user_id.clone();
         let user_info = UserInfo::new(
             self.user_id.clone(),
             self.ws_id.clone(),
             self.username.clone(),
+            self.resume_token.clone(),
         );
         ctx.spawn(actix::fut::wrap_future(get_users(
-            db.clone(),
+            app_state.clone(),
             ctx.address(),
             room_id.clone(),
             user_info,
@@ -116,39 +378,22 @@ impl Actor for WsActor {
             ctx.address(),
             room_id,
         )));
-        ctx.spawn(actix::fut::wrap_future(change_to_online(db, user_id)));
     }
 
     fn stopped(&mut self, ctx: &mut Self::Context) {
-        let user_id = self.user_id.clone();
-        let db = self.state.db.clone();
+        self.stop_typing(ctx);
+        self.state.resume.release(&self.resume_token, &self.current_room, Utc::now().timestamp() as u64);
         let mut actor_registry = self.state.actor_registry.lock().unwrap();
         if let Some(hashmap) = actor_registry.get_mut(&self.user_id.clone()) {
             hashmap.remove(&self.ws_id.clone());
         }
-        actix::spawn(async move { change_to_offline(db, user_id).await });
+        drop(actor_registry);
+        let change = self.state.presence.disconnect(&self.user_id, &self.ws_id);
+        self.publish_presence(change);
     }
 
 }
 
-pub async fn add_user_to_room(
-    user_id: String, 
-    room_id: String, 
-    db: Arc<Surreal<Client>>) {
-    let query = "UPDATE rooms SET users += $user_id WHERE room_id = $room_id;";
-    if let Err(e) = db
-        .query(query)
-        .bind(("user_id", user_id))
-        .bind(("room_id", room_id))
-        .await
-    {
-        log::error!(
-            "Failed to add user to room: fn add_user_to_room, error: {:?}",
-            e
-        );
-    }
-}
-
 pub struct WsMessage(pub String);
 
 impl actix::Message for WsMessage {
@@ -164,59 +409,361 @@ impl Handler<WsMessage> for WsActor {
     }
 }
 
-pub async fn delete_message(message: DeletionMessage, sender_id: String, room_id: String, state: Arc<AppState>) {
-    let query = "SELECT * FROM messages WHERE sender_id = $sender_id AND message_id = $message_id;";
-    let mut response = match state.db.query(query).bind(("sender_id", sender_id.clone())).bind(("message_id", message.message_id.clone())).await {
-        Ok(x) => x,
+// Closes the connection, for when the session behind it is no longer valid
+#[derive(Clone)]
+pub enum Disconnect {
+    All,
+    Session(String),
+    AllExcept(String),
+}
+
+impl actix::Message for Disconnect {
+    type Result = ();
+}
+
+impl Handler<Disconnect> for WsActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: Disconnect, ctx: &mut Self::Context) {
+        let ended = match msg {
+            Disconnect::All => true,
+            Disconnect::Session(session_id) => session_id == self.session_id,
+            Disconnect::AllExcept(session_id) => session_id != self.session_id,
+        };
+        if !ended {
+            return;
+        }
+        ctx.close(Some(ws::CloseReason {
+            code: ws::CloseCode::Policy,
+            description: Some("Session ended".to_string()),
+        }));
+        ctx.stop();
+    }
+}
+
+// Tells a connection it no longer belongs to a room; if it is viewing that room it moves back to main
+pub struct LeaveRoom(pub String);
+
+impl actix::Message for LeaveRoom {
+    type Result = ();
+}
+
+impl Handler<LeaveRoom> for WsActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: LeaveRoom, ctx: &mut Self::Context) {
+        self.rooms.retain(|room_id| *room_id != msg.0);
+        if self.current_room == msg.0 {
+            if let Some(handle) = self.typing_timeout.take() {
+                ctx.cancel_future(handle);
+            }
+            let main_room_id = self.state.main_room_id.clone();
+            self.change_room(main_room_id, Reply::new(ctx.address(), None), ctx);
+        }
+    }
+}
+
+// Detaches every live connection of user_id from room_id
+fn detach_from_room(state: &AppState, user_id: &str, room_id: &str) {
+    let actor_registry = state.actor_registry.lock().unwrap();
+    if let Some(client) = actor_registry.get(user_id) {
+        for instance in client.values() {
+            instance.do_send(LeaveRoom(room_id.to_string()));
+        }
+    }
+}
+
+// The room user_id writes a message to, if they are a member of it and not muted there.
+// Kicked and banned users are no longer members.
+async fn writable_room(state: &AppState, reply: &Reply, room_id: &str, user_id: &str, now: u64, failure: &str) -> Option<Room> {
+    match state.store.get_room(room_id).await {
+        Ok(Some(room)) if !room.users.contains(user_id) => {
+            reply.error(ErrorCode::NotMember, "You are not a member of this room");
+            None
+        }
+        Ok(Some(room)) if room.is_muted(user_id, now) => {
+            reply.error(ErrorCode::Muted, "You are muted in this room");
+            None
+        }
+        Ok(Some(room)) => Some(room),
+        Ok(None) => {
+            reply.error(ErrorCode::NotFound, "Room not found");
+            None
+        }
         Err(e) => {
-            log::error!(
-                "Failed to delete message: fn delete_message, error: {:?}",
-                e
-            );
-            return
+            log::error!("Failed to get room: fn writable_room, error: {:?}", e);
+            reply.error(ErrorCode::Internal, failure);
+            None
         }
+    }
+}
+
+// Stores a new message if its sender is a member of its room and not muted there, returning its seq
+async fn store_message(state: &AppState, reply: &Reply, basic_message: &BasicMessage) -> Option<u64> {
+    writable_room(state, reply, &basic_message.room_id, &basic_message.sender_id, basic_message.timestamp, "Failed to send message").await?;
+    match state.store.create_message(basic_message.clone()).await {
+        Ok(seq) => Some(seq),
+        Err(e) => {
+            log::error!("Failed to create message in db: fn store_message, error: {:?}", e);
+            reply.error(ErrorCode::Internal, "Failed to send message");
+            None
+        }
+    }
+}
+
+// Stores a new message and sends it to everyone in the room. A message resent
+// with the same idempotency key gets the first one back instead of posting again.
+pub async fn post_message(app_state: Arc<AppState>, reply: Reply, idempotency_key: Option<String>, mut basic_message: BasicMessage) {
+    if let Some(key) = &idempotency_key {
+        if key.is_empty() || key.len() > MAX_IDEMPOTENCY_KEY_LEN {
+            reply.error(ErrorCode::InvalidRequest, &format!("An idempotency key must be 1 to {} bytes long", MAX_IDEMPOTENCY_KEY_LEN));
+            return;
+        }
+        match app_state.sent_messages.claim(&basic_message.sender_id, key, basic_message.timestamp) {
+            Claim::New => {}
+            Claim::Pending => {
+                reply.error(ErrorCode::InProgress, "That message is still being sent");
+                return;
+            }
+            Claim::Posted(original) => {
+                reply.send(serde_json::to_string(&UserMessage::Basic(*original.clone())).unwrap());
+                reply.ack(&original.message_id, original.timestamp);
+                return;
+            }
+        }
+    }
+    let seq = store_message(&app_state, &reply, &basic_message).await;
+    if let Some(seq) = seq {
+        basic_message.seq = seq;
+    }
+    if let Some(key) = &idempotency_key {
+        if seq.is_some() {
+            app_state.sent_messages.complete(&basic_message.sender_id, key, basic_message.clone());
+        } else {
+            app_state.sent_messages.release(&basic_message.sender_id, key);
+        }
+    }
+    if seq.is_none() {
+        return;
+    }
+    reply.ack(&basic_message.message_id, basic_message.timestamp);
+    let serialized_msg = match serde_json::to_string(&UserMessage::Basic(basic_message.clone(),)){
+        Ok(serialized) => serialized,
+        Err(e) => {log::error!("Failed to serialize message: fn post_message, error: {:?}", e);
+        return}
     };
-    let _: Option<BasicMessage> = match response.take(0) {
-        Ok(x) => x,
-        Err(e) => {log::error!("Failed to delete message: fn delete_message, error: {:?}", e);
-            return}
-    };
-    let _: Option<BasicMessage> = match state.db.delete(("messages", message.message_id.clone())).await {
-        Ok(x) => x,
+    app_state
+        .broadcast_chat_message(
+            serialized_msg,
+            basic_message.room_id,
+            basic_message.sender_id,
+        )
+        .await;
+}
+
+// Looks up an attachment that was uploaded to room_id
+async fn fetch_attachment(state: &AppState, attachment_id: &str, room_id: &str, reply: &Reply) -> Option<AttachmentMeta> {
+    match state.store.get_attachment(attachment_id).await {
+        Ok(Some(attachment)) if attachment.room_id == room_id => Some(attachment),
+        Ok(_) => {
+            reply.error(ErrorCode::NotFound, "Attachment not found in this room");
+            None
+        }
         Err(e) => {
-            log::error!(
-                "Failed to delete message: fn delete_message, error: {:?}",
-                e
-            );
+            log::error!("Failed to get attachment: fn fetch_attachment, error: {:?}", e);
+            reply.error(ErrorCode::Internal, "Failed to get attachment");
             None
         }
+    }
+}
+
+// Resolves the attachments a TSBasic message refers to before posting it
+pub async fn post_with_attachments(app_state: Arc<AppState>, reply: Reply, message: TSBasicMessage, mut basic_message: BasicMessage) {
+    if message.attachments.len() > MAX_MESSAGE_ATTACHMENTS {
+        reply.error(ErrorCode::InvalidRequest, &format!("A message can have at most {} attachments", MAX_MESSAGE_ATTACHMENTS));
+        return;
+    }
+    for attachment_id in message.attachments {
+        if basic_message.attachments.iter().any(|attached| attached.attachment_id == attachment_id) {
+            continue;
+        }
+        let Some(attachment) = fetch_attachment(&app_state, &attachment_id, &basic_message.room_id, &reply).await else {
+            return;
+        };
+        basic_message.attachments.push(AttachmentRef::new(&attachment));
+    }
+    post_message(app_state, reply, message.idempotency_key, basic_message).await;
+}
+
+// Attaches an uploaded image to basic_message before posting it
+pub async fn post_image(app_state: Arc<AppState>, reply: Reply, message: ImageMessage, mut basic_message: BasicMessage) {
+    let Some(attachment) = fetch_attachment(&app_state, &message.attachment_id, &basic_message.room_id, &reply).await else {
+        return;
+    };
+    let state = app_state.clone();
+    let sha256 = attachment.sha256.clone();
+    let details = web::block(move || state.uploads.prepare_image(&sha256)).await;
+    match details {
+        Ok(Ok(Some((width, height)))) => basic_message.image = Some(ImageAttachment::new(&attachment, width, height)),
+        Ok(Ok(None)) => {
+            reply.error(ErrorCode::InvalidRequest, "That attachment is not an image");
+            return;
+        }
+        Ok(Err(e)) => {
+            log::error!("Failed to process image: fn post_image, error: {:?}", e);
+            reply.error(ErrorCode::Internal, "Failed to process image");
+            return;
+        }
+        Err(e) => {
+            log::error!("Failed to process image: fn post_image, error: {:?}", e);
+            reply.error(ErrorCode::Internal, "Failed to process image");
+            return;
+        }
+    }
+    post_message(app_state, reply, message.idempotency_key, basic_message).await;
+}
+
+// Only the author or a moderator of the message's room may delete it, while a member and not muted there
+pub async fn delete_message(message: DeletionMessage, sender_id: String, state: Arc<AppState>, reply: Reply) {
+    let target = match state.store.get_message(&message.message_id).await {
+        Ok(Some(target)) if target.deleted_at.is_none() => target,
+        Ok(_) => {
+            reply.error(ErrorCode::NotFound, "Message not found");
+            return;
+        }
+        Err(e) => {
+            log::error!("Failed to get message: fn delete_message, error: {:?}", e);
+            reply.error(ErrorCode::Internal, "Failed to delete message");
+            return;
+        }
+    };
+    let deleted_at = Utc::now().timestamp() as u64;
+    let Some(room) = writable_room(&state, &reply, &target.room_id, &sender_id, deleted_at, "Failed to delete message").await else {
+        return;
     };
-    let serialized_message = match serde_json::to_string(&UserMessage::Deletion(message)){
+    if target.sender_id != sender_id && !room.can(&sender_id, RoomAction::DeleteMessage) {
+        reply.error(ErrorCode::Forbidden, "You cannot delete that message");
+        return;
+    }
+    if let Err(e) = state.store.delete_message(&target.message_id, deleted_at).await {
+        log::error!(
+            "Failed to delete message: fn delete_message, error: {:?}",
+            e
+        );
+        reply.error(ErrorCode::Internal, "Failed to delete message");
+        return;
+    }
+    reply.ack(&target.message_id, deleted_at);
+    let deletion = DeletionMessage {
+        sender_id: sender_id.clone(),
+        message_id: target.message_id,
+    };
+    let serialized_message = match serde_json::to_string(&UserMessage::Deletion(deletion)){
         Ok(x) => x,
         Err(e) => {log::error!("Failed to delete message: fn delete_message, error: {:?}", e);
         return},
     };
-    state.broadcast_message(serialized_message, room_id, sender_id).await;
+    state.broadcast_message(serialized_message, target.room_id, sender_id).await;
 }
 
-pub async fn get_users(
-    db: Arc<Surreal<Client>>,
-    actor_addr: Addr<WsActor>,
-    room_id: String,
-    user_info: UserInfo,
+pub async fn edit_message(
+    message: EditMessage,
+    sender_id: String,
+    state: Arc<AppState>,
+    reply: Reply,
 ) {
-    let query = "SELECT user_id, username FROM users WHERE $room_id IN rooms;";
-    let mut response = match db.query(query).bind(("room_id", room_id)).await {
-        Ok(retrieved) => retrieved,
+    let content = message.content.trim();
+    if content.is_empty() {
+        reply.error(ErrorCode::InvalidRequest, "Message content must not be empty");
+        return;
+    }
+    let previous = match state.store.get_message(&message.message_id).await {
+        Ok(Some(previous)) if previous.deleted_at.is_none() => previous,
+        Ok(_) => {
+            reply.error(ErrorCode::NotFound, "Message not found");
+            return;
+        }
         Err(e) => {
-            log::error!(
-                "Failed to query users that are in requested room: fn get_users, error: {:?}",
-                e
-            );
+            log::error!("Failed to get message: fn edit_message, error: {:?}", e);
+            reply.error(ErrorCode::Internal, "Failed to edit message");
+            return;
+        }
+    };
+    if previous.sender_id != sender_id {
+        reply.error(ErrorCode::Forbidden, "You can only edit your own messages");
+        return;
+    }
+    let edited_at = Utc::now().timestamp() as u64;
+    if writable_room(&state, &reply, &previous.room_id, &sender_id, edited_at, "Failed to edit message").await.is_none() {
+        return;
+    }
+    if let Err(e) = state.store.edit_message(&previous, content, edited_at).await {
+        log::error!("Failed to edit message: fn edit_message, error: {:?}", e);
+        reply.error(ErrorCode::Internal, "Failed to edit message");
+        return;
+    }
+    reply.ack(&previous.message_id, edited_at);
+    let edited = UserMessage::Edited(EditedMessage {
+        message_id: previous.message_id,
+        room_id: previous.room_id.clone(),
+        sender_id: sender_id.clone(),
+        content: content.to_string(),
+        edited_at,
+    });
+    let serialized_msg = serde_json::to_string(&edited).unwrap();
+    state.broadcast_message(serialized_msg, previous.room_id, sender_id).await;
+}
+
+pub async fn get_revisions(
+    request: RevisionsRequestMessage,
+    user_id: String,
+    state: Arc<AppState>,
+    reply: Reply,
+) {
+    let message = match state.store.get_message(&request.message_id).await {
+        Ok(Some(message)) if message.deleted_at.is_none() => message,
+        Ok(_) => {
+            reply.error(ErrorCode::NotFound, "Message not found");
+            return;
+        }
+        Err(e) => {
+            log::error!("Failed to get message: fn get_revisions, error: {:?}", e);
+            reply.error(ErrorCode::Internal, "Failed to get revisions");
             return;
         }
     };
-    let users: Vec<User> = match response.take(0) {
+    match state.store.get_room(&message.room_id).await {
+        Ok(Some(room)) if room.users.contains(&user_id) => {}
+        Ok(_) => {
+            reply.error(ErrorCode::NotMember, "You are not a member of that room");
+            return;
+        }
+        Err(e) => {
+            log::error!("Failed to get room: fn get_revisions, error: {:?}", e);
+            reply.error(ErrorCode::Internal, "Failed to get revisions");
+            return;
+        }
+    }
+    match state.store.message_revisions(&message.message_id).await {
+        Ok(revisions) => {
+            reply.ack_now(&message.message_id);
+            let revisions = UserMessage::Revisions(RevisionsMessage::new(message.message_id, revisions));
+            reply.send(serde_json::to_string(&revisions).unwrap());
+        }
+        Err(e) => {
+            log::error!("Failed to get revisions: fn get_revisions, error: {:?}", e);
+            reply.error(ErrorCode::Internal, "Failed to get revisions");
+        }
+    }
+}
+
+pub async fn get_users(
+    app_state: Arc<AppState>,
+    actor_addr: Addr<WsActor>,
+    room_id: String,
+    user_info: UserInfo,
+) {
+    let users = match app_state.store.users_in_room(&room_id).await {
         Ok(user) => user,
         Err(e) => {
             log::error!(
@@ -226,71 +773,416 @@ pub async fn get_users(
             return;
         }
     };
+    let mut rooms = HashMap::new();
+    let mut direct_rooms = Vec::new();
+    match app_state.store.get_user(&user_info.user_id).await {
+        Ok(Some(user)) => {
+            for joined_room_id in &user.rooms {
+                let room = match app_state.store.get_room(joined_room_id).await {
+                    Ok(Some(room)) => room,
+                    Ok(None) => continue,
+                    Err(e) => {
+                        log::error!("Failed to get room: fn get_users, error: {:?}", e);
+                        continue;
+                    }
+                };
+                if !room.direct {
+                    rooms.insert(room.room_id, room.name);
+                    continue;
+                }
+                let Some(other_id) = room.other_member(&user_info.user_id) else {
+                    continue;
+                };
+                match app_state.store.get_user(other_id).await {
+                    Ok(Some(other)) => direct_rooms.push(DirectRoomMessage::new(room.room_id.clone(), other.user_id, other.username)),
+                    Ok(None) => {}
+                    Err(e) => log::error!("Failed to get user data: fn get_users, error: {:?}", e),
+                }
+            }
+        }
+        Ok(None) => {}
+        Err(e) => log::error!("Failed to get user data: fn get_users, error: {:?}", e),
+    }
+    let presence: HashMap<String, ConnectionState> = users
+        .iter()
+        .map(|user| (user.user_id.clone(), app_state.presence.status(&user.user_id).visible()))
+        .collect();
     let user_map: HashMap<String, String> = users
         .into_iter()
         .map(|user| (user.user_id, user.username))
         .collect();
     let init_message = UserMessage::Initialization(InitMessage::new(
-        user_info.user_id,
-        user_info.ws_id,
-        user_info.username,
+        user_info,
         user_map,
+        presence,
+        rooms,
+        direct_rooms,
     ));
     let serialized = serde_json::to_string(&init_message).unwrap();
     actor_addr.do_send(WsMessage(serialized));
 }
 
+// Creates a room owned by user_id, returning its id
+pub async fn create_room(
+    state: Arc<AppState>,
+    reply: Reply,
+    user_id: String,
+    room_name: String,
+) -> Option<String> {
+    match state.store.get_user(&user_id).await {
+        Ok(Some(user)) if state.can_join_rooms(&user) => {}
+        Ok(_) => {
+            reply.error(ErrorCode::EmailNotVerified, "Verify your email before creating rooms");
+            return None;
+        }
+        Err(e) => {
+            log::error!("Failed to get user data: fn create_room, error: {:?}", e);
+            reply.error(ErrorCode::Internal, "Failed to create room");
+            return None;
+        }
+    }
+    let room_id = Uuid::new_v4().to_string().replace('-', "");
+    let room = Room {
+        name: room_name,
+        room_id: room_id.clone(),
+        users: HashSet::from([user_id.clone()]),
+        roles: HashMap::from([(user_id, RoomRole::Owner)]),
+        ..Room::default()
+    };
+    if let Err(e) = state.store.create_room(room).await {
+        log::error!("Failed to create room in db: fn create_room, error: {:?}", e);
+        reply.error(ErrorCode::Internal, "Failed to create room");
+        return None;
+    }
+    reply.ack_now(&room_id);
+    Some(room_id)
+}
+
+// Finds or creates the direct room between user_id and target_id and tells both users about it
+pub async fn start_direct_room(
+    app_state: Arc<AppState>,
+    reply: Reply,
+    user_id: String,
+    username: String,
+    target_id: String,
+) {
+    if target_id == user_id {
+        reply.error(ErrorCode::InvalidRequest, "You cannot start a direct conversation with yourself");
+        return;
+    }
+    let (user, target) = match (app_state.store.get_user(&user_id).await, app_state.store.get_user(&target_id).await) {
+        (Ok(Some(user)), Ok(Some(target))) => (user, target),
+        (Ok(_), Ok(_)) => {
+            reply.error(ErrorCode::NotFound, "User not found");
+            return;
+        }
+        (Err(e), _) | (_, Err(e)) => {
+            log::error!("Failed to get user data: fn start_direct_room, error: {:?}", e);
+            reply.error(ErrorCode::Internal, "Failed to start direct conversation");
+            return;
+        }
+    };
+    if !app_state.can_join_rooms(&user) {
+        reply.error(ErrorCode::EmailNotVerified, "Verify your email before starting direct conversations");
+        return;
+    }
+    if !app_state.can_join_rooms(&target) {
+        reply.error(ErrorCode::EmailNotVerified, "That user has not verified their email yet");
+        return;
+    }
+    let room_id = Room::direct_room_id(&user_id, &target_id);
+    let existing = match app_state.store.get_room(&room_id).await {
+        Ok(existing) => existing,
+        Err(e) => {
+            log::error!("Failed to get room: fn start_direct_room, error: {:?}", e);
+            reply.error(ErrorCode::Internal, "Failed to start direct conversation");
+            return;
+        }
+    };
+    if existing.is_none() {
+        if let Err(e) = app_state.store.create_room(Room::direct_between(&user_id, &target_id)).await {
+            // Both users may have asked at once, in which case the room now exists
+            if !matches!(app_state.store.get_room(&room_id).await, Ok(Some(_))) {
+                log::error!("Failed to create room in db: fn start_direct_room, error: {:?}", e);
+                reply.error(ErrorCode::Internal, "Failed to start direct conversation");
+                return;
+            }
+        }
+    }
+
+    let for_user = UserMessage::DirectRoom(DirectRoomMessage::new(room_id.clone(), target.user_id.clone(), target.username));
+    let for_target = UserMessage::DirectRoom(DirectRoomMessage::new(room_id.clone(), user_id.clone(), username));
+    for (member, message) in [(&user_id, for_user), (&target.user_id, for_target)] {
+        let serialized_msg = serde_json::to_string(&message).unwrap();
+        app_state.send_room_event(&serialized_msg, member, &room_id);
+    }
+    reply.ack_now(&room_id);
+}
+
+// Loads a room, replying with an error frame when it is missing or the lookup fails
+async fn fetch_room(state: &AppState, room_id: &str, reply: &Reply) -> Option<Room> {
+    match state.store.get_room(room_id).await {
+        Ok(Some(room)) => Some(room),
+        Ok(None) => {
+            reply.error(ErrorCode::NotFound, "Room not found");
+            None
+        }
+        Err(e) => {
+            log::error!("Failed to get room: fn fetch_room, error: {:?}", e);
+            reply.error(ErrorCode::Internal, "Failed to get room");
+            None
+        }
+    }
+}
+
+// Members other than the owner may always leave; removing someone else needs a higher role
+pub async fn remove_member(
+    state: Arc<AppState>,
+    reply: Reply,
+    sender_id: String,
+    message: UserRemovalMessage,
+) {
+    let Some(room) = fetch_room(&state, &message.room_id, &reply).await else {
+        return;
+    };
+    let allowed = if message.removed_user == sender_id {
+        !room.direct && matches!(room.role_of(&sender_id), Some(role) if role != RoomRole::Owner)
+    } else {
+        room.can_act_on(&sender_id, &message.removed_user, RoomAction::RemoveMember)
+    };
+    if !allowed {
+        reply.error(ErrorCode::Forbidden, "You cannot remove that member");
+        return;
+    }
+    if let Err(e) = state.store.remove_room_member(&room.room_id, &message.removed_user).await {
+        log::error!("Error removing from room: {:?}", e);
+        reply.error(ErrorCode::Internal, "Failed to remove member");
+        return;
+    }
+    let removal = UserMessage::UserRemoval(UserRemovalMessage {
+        removed_user: message.removed_user.clone(),
+        room_id: room.room_id.clone(),
+        sender_id,
+    });
+    let serialized_msg = serde_json::to_string(&removal).unwrap();
+    // The removed member is no longer in the room, so tell them directly
+    state.send_room_event(&serialized_msg, &message.removed_user, &room.room_id);
+    detach_from_room(&state, &message.removed_user, &room.room_id);
+    reply.ack_now(&room.room_id);
+    state.broadcast_to_room(serialized_msg, room.room_id).await;
+}
+
+// When a ban or mute lasting duration_secs from now ends, None when it is longer than allowed
+fn moderation_expiry(duration_secs: u64) -> Option<u64> {
+    (duration_secs <= MAX_MODERATION_SECS).then(|| (Utc::now().timestamp() as u64).saturating_add(duration_secs))
+}
+
+// Applies the action described by event once event.sender_id is allowed to take it
+pub async fn moderate(state: Arc<AppState>, reply: Reply, event: ModerationMessage) {
+    let Some(room) = fetch_room(&state, &event.room_id, &reply).await else {
+        return;
+    };
+    let sender_id = &event.sender_id;
+    let target_id = &event.user_id;
+    let is_member = room.users.contains(target_id);
+    let allowed = match event.action {
+        ModerationAction::Kick => room.can_act_on(sender_id, target_id, RoomAction::Kick),
+        ModerationAction::Mute => room.can_act_on(sender_id, target_id, RoomAction::Mute),
+        ModerationAction::Ban if is_member => room.can_act_on(sender_id, target_id, RoomAction::Ban),
+        // Users outside the room can be banned ahead of time
+        ModerationAction::Ban | ModerationAction::Unban => room.can(sender_id, RoomAction::Ban) && target_id != sender_id,
+        ModerationAction::Unmute if is_member => room.can_act_on(sender_id, target_id, RoomAction::Mute),
+        ModerationAction::Unmute => room.can(sender_id, RoomAction::Mute) && target_id != sender_id,
+    };
+    if !allowed {
+        reply.error(ErrorCode::Forbidden, "You cannot do that to this member");
+        return;
+    }
+    let result = match event.action {
+        ModerationAction::Kick => state.store.remove_room_member(&room.room_id, target_id).await,
+        ModerationAction::Ban => {
+            let ban = RoomBan {
+                reason: event.reason.clone(),
+                expires_at: event.expires_at,
+                banned_by: sender_id.clone(),
+            };
+            match state.store.set_ban(&room.room_id, target_id, Some(ban)).await {
+                Ok(()) if is_member => state.store.remove_room_member(&room.room_id, target_id).await,
+                other => other,
+            }
+        }
+        ModerationAction::Unban => state.store.set_ban(&room.room_id, target_id, None).await,
+        ModerationAction::Mute => {
+            let mute = RoomMute {
+                expires_at: event.expires_at,
+                muted_by: sender_id.clone(),
+            };
+            state.store.set_mute(&room.room_id, target_id, Some(mute)).await
+        }
+        ModerationAction::Unmute => state.store.set_mute(&room.room_id, target_id, None).await,
+    };
+    if let Err(e) = result {
+        log::error!("Failed to apply {:?}: fn moderate, error: {:?}", event.action, e);
+        reply.error(ErrorCode::Internal, "Failed to moderate member");
+        return;
+    }
+
+    let serialized_msg = serde_json::to_string(&UserMessage::Moderation(event.clone())).unwrap();
+    if is_member && matches!(event.action, ModerationAction::Kick | ModerationAction::Ban) {
+        state.send_room_event(&serialized_msg, target_id, &room.room_id);
+        detach_from_room(&state, target_id, &room.room_id);
+    }
+    reply.ack_now(&room.room_id);
+    state.broadcast_to_room(serialized_msg, room.room_id).await;
+}
+
+pub async fn invite_member(
+    state: Arc<AppState>,
+    reply: Reply,
+    sender_id: String,
+    invite: InviteMessage,
+) {
+    let Some(room) = fetch_room(&state, &invite.room_id, &reply).await else {
+        return;
+    };
+    if !room.can(&sender_id, RoomAction::Invite) {
+        reply.error(ErrorCode::Forbidden, "You cannot invite members to that room");
+        return;
+    }
+    if room.users.contains(&invite.user_id) {
+        reply.error(ErrorCode::AlreadyMember, "User is already a member");
+        return;
+    }
+    if room.is_banned(&invite.user_id, Utc::now().timestamp() as u64) {
+        reply.error(ErrorCode::Banned, "User is banned from that room");
+        return;
+    }
+    let invited = match state.store.get_user(&invite.user_id).await {
+        Ok(Some(invited)) => invited,
+        Ok(None) => {
+            reply.error(ErrorCode::NotFound, "User not found");
+            return;
+        }
+        Err(e) => {
+            log::error!("Failed to get user data: fn invite_member, error: {:?}", e);
+            reply.error(ErrorCode::Internal, "Failed to invite member");
+            return;
+        }
+    };
+    if !state.can_join_rooms(&invited) {
+        reply.error(ErrorCode::EmailNotVerified, "That user has not verified their email yet");
+        return;
+    }
+    if let Err(e) = state.store.add_room_member(&room.room_id, &invited.user_id).await {
+        log::error!("Failed to add user to room: fn invite_member, error: {:?}", e);
+        reply.error(ErrorCode::Internal, "Failed to invite member");
+        return;
+    }
+    let addition = UserMessage::UserAddition(UserAdditionMessage::new(invited.user_id, invited.username, room.room_id.clone()));
+    let serialized_msg = serde_json::to_string(&addition).unwrap();
+    reply.ack_now(&room.room_id);
+    state.broadcast_message(serialized_msg, room.room_id, sender_id).await;
+}
+
+pub async fn rename_room(
+    state: Arc<AppState>,
+    reply: Reply,
+    sender_id: String,
+    rename: RenameRoomMessage,
+) {
+    let name = rename.name.trim().to_string();
+    if name.is_empty() {
+        reply.error(ErrorCode::InvalidRequest, "Room name must not be empty");
+        return;
+    }
+    let Some(room) = fetch_room(&state, &rename.room_id, &reply).await else {
+        return;
+    };
+    if !room.can(&sender_id, RoomAction::Rename) {
+        reply.error(ErrorCode::Forbidden, "You cannot rename that room");
+        return;
+    }
+    if let Err(e) = state.store.rename_room(&room.room_id, &name).await {
+        log::error!("Failed to rename room: fn rename_room, error: {:?}", e);
+        reply.error(ErrorCode::Internal, "Failed to rename room");
+        return;
+    }
+    let renamed = UserMessage::RenameRoom(RenameRoomMessage {
+        room_id: room.room_id.clone(),
+        name,
+        sender_id: sender_id.clone(),
+    });
+    let serialized_msg = serde_json::to_string(&renamed).unwrap();
+    reply.ack_now(&room.room_id);
+    state.broadcast_message(serialized_msg, room.room_id, sender_id).await;
+}
+
+// Owners promote members to moderator or demote them back; ownership itself is not assignable
+pub async fn set_member_role(
+    state: Arc<AppState>,
+    reply: Reply,
+    sender_id: String,
+    set_role: SetRoleMessage,
+) {
+    if set_role.role == RoomRole::Owner {
+        reply.error(ErrorCode::InvalidRequest, "Ownership cannot be assigned");
+        return;
+    }
+    let Some(room) = fetch_room(&state, &set_role.room_id, &reply).await else {
+        return;
+    };
+    if !room.can_act_on(&sender_id, &set_role.user_id, RoomAction::ManageRoles) {
+        reply.error(ErrorCode::Forbidden, "You cannot change that member's role");
+        return;
+    }
+    if let Err(e) = state.store.set_room_role(&room.room_id, &set_role.user_id, set_role.role).await {
+        log::error!("Failed to set role: fn set_member_role, error: {:?}", e);
+        reply.error(ErrorCode::Internal, "Failed to change role");
+        return;
+    }
+    let changed = UserMessage::SetRole(SetRoleMessage {
+        room_id: room.room_id.clone(),
+        user_id: set_role.user_id,
+        role: set_role.role,
+        sender_id: sender_id.clone(),
+    });
+    let serialized_msg = serde_json::to_string(&changed).unwrap();
+    reply.ack_now(&room.room_id);
+    state.broadcast_message(serialized_msg, room.room_id, sender_id).await;
+}
+
 pub async fn check_and_update_username(
     user_id: String,
-    current_username: String,
     new_username: String,
     state: Arc<AppState>,
     message: UserMessage,
 ) -> Result<HttpResponse, Error> {
-    let query = "SELECT username FROM users WHERE username = $username;";
-    if let Ok(mut response) = state
-        .db
-        .query(query)
-        .bind(("username", new_username.clone()))
-        .await
-    {
-        let result: Option<String> = match response.take((0, "username")) {
-            Ok(retrieved) => retrieved,
-            Err(e) => {
-                log::error!(
-                    "Failed to get user: fn check_and_update_username, error: {:?}",
-                    e
-                );
-                return Ok(HttpResponse::InternalServerError().json(json!({"error": "Internal DB Error"})));
-            }
-        };
-        match result {
-            Some(_) => Ok(HttpResponse::BadRequest().json(json!({"error": "Username Already In Use"}))),
-            None => {
-                let query = "UPDATE users SET username = $new_username WHERE username = $username;";
-                if let Err(e) = state
-                    .db
-                    .query(query)
-                    .bind(("new_username", new_username.clone()))
-                    .bind(("username", current_username)).await{
-                        log::error!(
-                            "Failed to update username: fn check_and_update_username, error: {:?}",
-                            e
-                        );
-                        return Ok(HttpResponse::InternalServerError().json(json!({"error": "Internal DB Error"})));
-                    };
-
-                let serialized_msg = serde_json::to_string(&message).unwrap();
-                state
-                    .broadcast_message(serialized_msg, state.main_room_id.clone(), user_id)
-                    .await;
-                Ok(HttpResponse::Ok().json(json!({"message": "Username updated successfully"}))
-            )
-            }
+    let taken = match state.store.username_taken(&new_username).await {
+        Ok(taken) => taken,
+        Err(e) => {
+            log::error!(
+                "Failed to get user: fn check_and_update_username, error: {:?}",
+                e
+            );
+            return Ok(HttpResponse::InternalServerError().json(json!({"error": "Internal DB Error"})));
         }
-    } else {
-        Ok(HttpResponse::BadRequest().json(json!({"error": "Failed to query database"})))
+    };
+    if taken {
+        return Ok(HttpResponse::BadRequest().json(json!({"error": "Username Already In Use"})));
+    }
+    if let Err(e) = state.store.update_username(&user_id, &new_username).await {
+        log::error!(
+            "Failed to update username: fn check_and_update_username, error: {:?}",
+            e
+        );
+        return Ok(HttpResponse::InternalServerError().json(json!({"error": "Internal DB Error"})));
     }
+
+    let serialized_msg = serde_json::to_string(&message).unwrap();
+    state
+        .broadcast_message(serialized_msg, state.main_room_id.clone(), user_id)
+        .await;
+    Ok(HttpResponse::Ok().json(json!({"message": "Username updated successfully"})))
 }
 
 impl StreamHandler<std::result::Result<ws::Message, ws::ProtocolError>> for WsActor {
@@ -299,172 +1191,255 @@ impl StreamHandler<std::result::Result<ws::Message, ws::ProtocolError>> for WsAc
         msg: std::result::Result<ws::Message, ws::ProtocolError>,
         ctx: &mut Self::Context,
     ) {
-        if !self.check_and_update_rate_limit() && self.request_token_count == 0  {
-            return;
-        }
-        match self.request_token_count.checked_sub(1){
-            Some(result) => self.request_token_count = result,
-            None => println!("Underflow occurred"),
-        }
         if let Ok(ws::Message::Text(text)) = msg {
-            match serde_json::from_str::<UserMessage>(&text) {
+            let frame = serde_json::from_str::<serde_json::Value>(&text);
+            let reply = Reply::new(ctx.address(), frame.as_ref().ok().and_then(client_msg_id));
+            let parsed = frame.and_then(serde_json::from_value::<UserMessage>);
+            let cost = parsed.as_ref().map_or(INVALID_FRAME_COST, message_cost);
+            let key = RateLimitKey::User(self.user_id.clone());
+            if let Err(retry_after) = self.state.rate_limiter.acquire(&[key], cost) {
+                let message = UserMessage::RateLimited(RateLimitedMessage {
+                    client_msg_id: reply.client_msg_id.clone(),
+                    retry_after_ms: retry_after.as_millis() as u64,
+                });
+                ctx.text(serde_json::to_string(&message).unwrap());
+                return;
+            }
+            let change = self.state.presence.touch(&self.user_id, &self.ws_id);
+            self.publish_presence(change);
+            match parsed {
                 Ok(message) => match message {
                     UserMessage::TSBasic(ts_basic_message) => {
-                        let app_state = self.state.clone();
-                        let now = Utc::now();
-                        let basic_message = BasicMessage {
-                            content: ts_basic_message.content,
-                            sender_id: self.user_id.clone(),
-                            timestamp: now.timestamp() as u64,
-                            message_id: Uuid::new_v4().to_string().replace('-', ""),
-                            room_id: self.current_room.clone(),
-                            ws_id: self.ws_id.clone(),
-                        };
-                        actix::spawn(async move {
-                            let _: Option<BasicMessage> = match app_state
-                                .db
-                                .create(("messages", basic_message.message_id.clone()))
-                                .content(basic_message.clone())
-                                .await {
-                                    Ok(retrieved) => retrieved,
-                                    Err(e) => {log::error!("Failed to create message in db: fn handle, error: {:?}", e);
-                                    return}
-                                };
-                            let serialized_msg = match serde_json::to_string(&UserMessage::Basic(basic_message.clone(),)){
-                                Ok(serialized) => serialized,
-                                Err(e) => {log::error!("Failed to create message in db: fn handle, error: {:?}", e);
-                                return}
-                            };
-                            app_state
-                                .broadcast_message(
-                                    serialized_msg,
-                                    basic_message.room_id,
-                                    basic_message.sender_id,
-                                )
-                                .await;
-                        });
+                        self.stop_typing(ctx);
+                        let basic_message = self.new_message(ts_basic_message.content.clone());
+                        actix::spawn(post_with_attachments(self.state.clone(), reply.clone(), ts_basic_message, basic_message));
+                    }
+                    UserMessage::Image(image_message) => {
+                        self.stop_typing(ctx);
+                        let basic_message = self.new_message(image_message.caption.clone());
+                        actix::spawn(post_image(self.state.clone(), reply.clone(), image_message, basic_message));
                     }
                     UserMessage::Deletion(message) => {
                         let sender_id = self.user_id.clone();
                         let state = self.state.clone();
-                        let room_id = self.current_room.clone();
-                        ctx.spawn(actix::fut::wrap_future(delete_message(message, sender_id, room_id, state)));
+                        ctx.spawn(actix::fut::wrap_future(delete_message(message, sender_id, state, reply.clone())));
                         
                     }
+                    UserMessage::SetPresence(set_presence) => {
+                        self.set_presence(set_presence.status, &reply);
+                    }
+                    UserMessage::Typing(typing_message) => {
+                        if typing_message.typing {
+                            self.start_typing(ctx);
+                        } else {
+                            self.stop_typing(ctx);
+                        }
+                        reply.ack_now(&self.current_room);
+                    }
+                    UserMessage::Edit(edit) => {
+                        ctx.spawn(actix::fut::wrap_future(edit_message(
+                            edit,
+                            self.user_id.clone(),
+                            self.state.clone(),
+                            reply.clone(),
+                        )));
+                    }
+                    UserMessage::RevisionsRequest(request) => {
+                        ctx.spawn(actix::fut::wrap_future(get_revisions(
+                            request,
+                            self.user_id.clone(),
+                            self.state.clone(),
+                            reply.clone(),
+                        )));
+                    }
                     UserMessage::CreateRoomChange(create_room_change_message) => {
-                        let room_id = Uuid::new_v4().to_string().replace('-', "");
-                        let room_name = create_room_change_message.room_name;
-                        let app_state = self.state.clone();
-                        self.rooms.push(room_id.clone());
-                        let mut users = HashSet::new();
-                        users.insert(self.user_id.clone());
-                        actix::spawn(async move {
-                            let _: Vec<Room> = match app_state
-                                .db
-                                .create("rooms")
-                                .content(Room {
-                                    name: room_name,
-                                    room_id,
-                                    users,
-                                })
-                                .await {
-                                    Ok(retrieved) => retrieved,
-                                    Err(e) => {log::error!("Failed to create room in db: fn handle, error: {:?}", e);
-                                    return}
-                                };
-                        });
+                        let creation = create_room(
+                            self.state.clone(),
+                            reply.clone(),
+                            self.user_id.clone(),
+                            create_room_change_message.room_name,
+                        );
+                        ctx.spawn(actix::fut::wrap_future::<_, Self>(creation).map(|room_id, actor, _| {
+                            if let Some(room_id) = room_id {
+                                actor.rooms.push(room_id);
+                            }
+                        }));
+                    }
+                    UserMessage::StartDirect(start_direct) => {
+                        ctx.spawn(actix::fut::wrap_future(start_direct_room(
+                            self.state.clone(),
+                            reply.clone(),
+                            self.user_id.clone(),
+                            self.username.clone(),
+                            start_direct.user_id,
+                        )));
                     }
                     UserMessage::ChangeRoom(change_room_message) => {
-                        let room_id = change_room_message.room_id;
-                        let app_state = self.state.clone();
-                        let actor_addr = ctx.address().clone();
-                        ctx.spawn(actix::fut::wrap_future(get_messages(
-                            app_state, actor_addr, room_id,
+                        self.change_room(change_room_message.room_id, reply, ctx);
+                    }
+                    UserMessage::HistoryRequest(history_request) => {
+                        ctx.spawn(actix::fut::wrap_future(get_history(
+                            self.state.clone(),
+                            reply.clone(),
+                            self.user_id.clone(),
+                            history_request,
                         )));
                     }
                     UserMessage::UserRemoval(user_removal_message) => {
-                        let app_state = self.state.clone();
-                        actix::spawn(async move {
-                            let query =
-                                "UPDATE rooms SET users -= $removed_user WHERE room_id = $room_id;";
-                            if let Err(e) = app_state
-                                .db
-                                .query(query)
-                                .bind(("removed_user", user_removal_message.removed_user))
-                                .bind(("room_id", user_removal_message.room_id))
-                                .await
-                            {
-                                log::error!("Error removing from room: {:?}", e);
-                            }
-                        });
+                        ctx.spawn(actix::fut::wrap_future(remove_member(
+                            self.state.clone(),
+                            reply.clone(),
+                            self.user_id.clone(),
+                            user_removal_message,
+                        )));
+                    }
+                    UserMessage::Invite(invite) => {
+                        ctx.spawn(actix::fut::wrap_future(invite_member(
+                            self.state.clone(),
+                            reply.clone(),
+                            self.user_id.clone(),
+                            invite,
+                        )));
+                    }
+                    UserMessage::RenameRoom(rename) => {
+                        ctx.spawn(actix::fut::wrap_future(rename_room(
+                            self.state.clone(),
+                            reply.clone(),
+                            self.user_id.clone(),
+                            rename,
+                        )));
+                    }
+                    UserMessage::Kick(kick) => {
+                        let event = ModerationMessage {
+                            room_id: kick.room_id,
+                            user_id: kick.user_id,
+                            action: ModerationAction::Kick,
+                            reason: kick.reason,
+                            expires_at: None,
+                            sender_id: self.user_id.clone(),
+                        };
+                        ctx.spawn(actix::fut::wrap_future(moderate(self.state.clone(), reply.clone(), event)));
+                    }
+                    UserMessage::Ban(ban) => match ban.duration_secs.map(moderation_expiry) {
+                        Some(None) => reply.error(ErrorCode::InvalidRequest, "duration_secs is too long"),
+                        expires_at => {
+                            let event = ModerationMessage {
+                                room_id: ban.room_id,
+                                user_id: ban.user_id,
+                                action: ModerationAction::Ban,
+                                reason: ban.reason,
+                                expires_at: expires_at.flatten(),
+                                sender_id: self.user_id.clone(),
+                            };
+                            ctx.spawn(actix::fut::wrap_future(moderate(self.state.clone(), reply.clone(), event)));
+                        }
+                    },
+                    UserMessage::Mute(mute) => match mute.duration_secs.map(moderation_expiry) {
+                        Some(None) => reply.error(ErrorCode::InvalidRequest, "duration_secs is too long"),
+                        expires_at => {
+                            let event = ModerationMessage {
+                                room_id: mute.room_id,
+                                user_id: mute.user_id,
+                                action: ModerationAction::Mute,
+                                reason: None,
+                                expires_at: expires_at.flatten(),
+                                sender_id: self.user_id.clone(),
+                            };
+                            ctx.spawn(actix::fut::wrap_future(moderate(self.state.clone(), reply.clone(), event)));
+                        }
+                    },
+                    UserMessage::Unban(target) => {
+                        let event = ModerationMessage {
+                            room_id: target.room_id,
+                            user_id: target.user_id,
+                            action: ModerationAction::Unban,
+                            reason: None,
+                            expires_at: None,
+                            sender_id: self.user_id.clone(),
+                        };
+                        ctx.spawn(actix::fut::wrap_future(moderate(self.state.clone(), reply.clone(), event)));
+                    }
+                    UserMessage::Unmute(target) => {
+                        let event = ModerationMessage {
+                            room_id: target.room_id,
+                            user_id: target.user_id,
+                            action: ModerationAction::Unmute,
+                            reason: None,
+                            expires_at: None,
+                            sender_id: self.user_id.clone(),
+                        };
+                        ctx.spawn(actix::fut::wrap_future(moderate(self.state.clone(), reply.clone(), event)));
+                    }
+                    UserMessage::SetRole(set_role) => {
+                        ctx.spawn(actix::fut::wrap_future(set_member_role(
+                            self.state.clone(),
+                            reply.clone(),
+                            self.user_id.clone(),
+                            set_role,
+                        )));
                     }
-                    _ => {}
+                    _ => reply.error(ErrorCode::InvalidRequest, "That message type cannot be sent by clients"),
                 },
-                Err(e) => log::error!("Error processing message: {:?}", e),
+                Err(e) => {
+                    log::error!("Error processing message: {:?}", e);
+                    reply.error(ErrorCode::InvalidRequest, "Invalid message");
+                }
             }
         }
     }
 }
 
+#[derive(Deserialize)]
+pub struct ResumeQuery {
+    resume_token: Option<String>,
+    // room_id:seq pairs separated by commas
+    last_seqs: Option<String>,
+}
+
+impl ResumeQuery {
+    // None unless both a token and well formed last_seqs were given
+    fn request(&self) -> Option<ResumeRequest> {
+        let token = self.resume_token.clone()?;
+        let last_seqs = self.last_seqs.as_deref()?
+            .split(',')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let (room_id, seq) = pair.rsplit_once(':')?;
+                let seq: u64 = seq.parse().ok()?;
+                (seq <= MAX_SEQ).then(|| (room_id.to_string(), seq))
+            })
+            .collect::<Option<HashMap<String, u64>>>()?;
+        Some(ResumeRequest { token, last_seqs })
+    }
+}
+
 pub async fn ws_index(
     req: actix_web::HttpRequest,
     stream: web::Payload,
+    query: web::Query<ResumeQuery>,
     state: web::Data<AppState>,
     session: Session,
 ) -> std::result::Result<HttpResponse, actix_web::Error> {
     let main_room_id = state.main_room_id.clone();
-    let user_id = match session.get::<String>("key").unwrap() {
-        Some(user_id) => user_id,
+    let user = match state.session_user(&session).await {
+        Some(user) => user,
         None => {
             return Ok(HttpResponse::Found()
                 .append_header(("LOCATION", "/login"))
                 .finish());
         }
     };
-    let query = "SELECT * FROM users WHERE user_id = $user_id;";
-    let mut response = match state
-        .db
-        .query(query)
-        .bind(("user_id", user_id.clone()))
-        .await {
-            Ok(retrieved) => retrieved,
-            Err(e) => {
-                log::error!("Failed to query user data: fn ws_index, error: {:?}", e);
-                session.purge();
-                return Ok(HttpResponse::Found()
-                    .append_header(("LOCATION", "/login"))
-                    .finish());
-            }
-        };
-    let user_query: Option<UserData> = match response.take(0) {
-        Ok(retrieved) => retrieved,
-        Err(e) => {
-            log::error!("Failed to get user data: fn ws_index, error: {:?}", e);
-            session.purge();
-            return Ok(HttpResponse::Found()
-                .append_header(("LOCATION", "/login"))
-                .finish());
-        }
+    let ws_actor = WsActor {
+        user_id: user.user_id,
+        session_id: current_session_id(&session).unwrap_or_default(),
+        ws_id: Uuid::new_v4().to_string().replace('-', ""),
+        username: user.username,
+        current_room: main_room_id.clone(),
+        rooms: user.rooms,
+        state: state.into_inner().clone(),
+        typing_timeout: None,
+        resume_token: String::new(),
+        resume_request: query.request(),
     };
-    match user_query {
-        Some(user) => {
-            let ws_actor = WsActor {
-                user_id,
-                ws_id: Uuid::new_v4().to_string().replace('-', ""),
-                username: user.username,
-                current_room: main_room_id.clone(),
-                rooms: user.rooms,
-                state: state.into_inner().clone(),
-                request_token_count: MESSAGE_TOKENS,
-                start_time: Instant::now(),
-            };
-            ws::start(ws_actor, &req, stream)
-        }
-        None => {
-            session.purge();
-            Ok(HttpResponse::Found()
-                .append_header(("LOCATION", "/login"))
-                .finish())
-        }
-    }
+    ws::start(ws_actor, &req, stream)
 }
\ No newline at end of file
@@ -8,13 +8,30 @@ var __awaiter = (this && this.__awaiter) || function (thisArg, _arguments, P, ge
         step((generator = generator.apply(thisArg, _arguments || [])).next());
     });
 };
-var _a, _b;
+var _a, _b, _c;
 let sender_id = "";
 let user_map = {};
 let ws_id = "";
 let username = "";
 let current_room = "";
 let room_map = {};
+// Where the older messages of current_room continue from, null once all are shown
+let older_history_cursor = null;
+// How the next History page fits in: 'latest' is the page sent on connecting or changing
+// room, 'before' and 'after' pages were asked for with that cursor. Pages nobody asked
+// for after the first, e.g. replayed after a reconnect, continue forward.
+let history_direction = 'latest';
+// client_msg_id of the history request in flight
+let pending_history_request = null;
+// client_msg_id and room of the ChangeRoom request in flight
+let pending_room_change = null;
+let client_msg_counter = 0;
+// Given on connecting, lets a dropped connection pick up where it left off
+let resume_token = null;
+// Highest message seq seen in each room
+let last_seqs = {};
+let reconnect_attempts = 0;
+const MAX_RECONNECT_ATTEMPTS = 5;
 let socket;
 var MessageType;
 (function (MessageType) {
@@ -30,13 +47,20 @@ var MessageType;
     MessageType["CreateRoomChange"] = "CreateRoomChange";
     MessageType["Initialization"] = "Initialization";
     MessageType["Deletion"] = "Deletion";
+    MessageType["History"] = "History";
+    MessageType["Error"] = "Error";
+    MessageType["Ack"] = "Ack";
+    MessageType["RateLimited"] = "RateLimited";
+    MessageType["Resumed"] = "Resumed";
 })(MessageType || (MessageType = {}));
-initializeWebSocket("127.0.0.1")
-    function initializeWebSocket(server_ip) {
-    socket = new WebSocket(`ws://${server_ip}:8080/ws/`);
+initializeWebSocket();
+// Connects back to the host that served the page, over TLS when the page was
+function initializeWebSocket(query = '') {
+    const protocol = location.protocol === 'https:' ? 'wss:' : 'ws:';
+    socket = new WebSocket(`${protocol}//${location.host}/ws/${query}`);
     socket.onclose = (event) => {
         if (!event.wasClean) {
-            window.location.href = '/login';
+            reconnect();
         }
     };
     socket.onmessage = (event) => {
@@ -78,19 +102,66 @@ initializeWebSocket("127.0.0.1")
             case 'CreateRoomChange' in data:
                 handle_create_room_message(data.CreateRoomChange);
                 break;
+            case 'History' in data:
+                handle_history_message(data.History);
+                break;
+            case 'Error' in data:
+                handle_error_message(data.Error);
+                break;
+            case 'Ack' in data:
+                handle_ack_message(data.Ack);
+                break;
+            case 'RateLimited' in data:
+                handle_rate_limited_message(data.RateLimited);
+                break;
+            case 'Resumed' in data:
+                handle_resumed_message(data.Resumed);
+                break;
             default:
                 console.error("Unknown message type received");
         }
     };
 }
+// Resumes with the last token, backing off a little more each attempt, and goes
+// to the login page once resuming keeps failing
+function reconnect() {
+    if (resume_token === null || reconnect_attempts >= MAX_RECONNECT_ATTEMPTS) {
+        window.location.href = '/login';
+        return;
+    }
+    reconnect_attempts += 1;
+    pending_history_request = null;
+    pending_room_change = null;
+    history_direction = 'latest';
+    const seqs = Object.entries(last_seqs).map(([room_id, seq]) => `${room_id}:${seq}`).join(',');
+    const query = `?resume_token=${encodeURIComponent(resume_token)}&last_seqs=${encodeURIComponent(seqs)}`;
+    setTimeout(() => initializeWebSocket(query), reconnect_attempts * 1000);
+}
 function handle_initialization(init_message) {
     sender_id = init_message.user_id;
     ws_id = init_message.ws_id;
     username = init_message.username;
     user_map = init_message.user_map;
+    resume_token = init_message.resume_token;
+    reconnect_attempts = 0;
+}
+// The missed messages follow; if the server put us back in another room, load it instead
+function handle_resumed_message(message) {
+    ws_id = message.ws_id;
+    resume_token = message.resume_token;
+    reconnect_attempts = 0;
+    history_direction = 'after';
+    if (message.room_id !== current_room) {
+        change_room(message.room_id);
+    }
+}
+function note_seq(message) {
+    last_seqs[message.room_id] = Math.max(last_seqs[message.room_id] || 0, message.seq);
 }
 function handle_basic_message(message) {
-    if (message.ws_id === ws_id) {
+    note_seq(message);
+    // Image messages are not drawn locally when sent, so let them through
+    if (message.ws_id === ws_id && !message.image) {
         const chatContainer = document.getElementById('chat-container');
         const sentContainers = chatContainer.getElementsByClassName('sent-container');
         if (sentContainers.length > 0) {
@@ -100,6 +171,10 @@ function handle_basic_message(message) {
         return;
     }
     const chatContainer = document.getElementById('chat-container');
+    chatContainer.appendChild(build_message_element(message));
+    chatContainer.scrollTop = chatContainer.scrollHeight;
+}
+function build_message_element(message) {
     const messageContainer = document.createElement('div');
     const messageWrapper = document.createElement('div');
     const usernameElement = document.createElement('div');
@@ -112,7 +187,24 @@ function handle_basic_message(message) {
     usernameElement.classList.add('username');
     messageElement.textContent = message.content;
     messageWrapper.appendChild(usernameElement);
+    if (message.image) {
+        const imageLink = document.createElement('a');
+        const imageElement = document.createElement('img');
+        imageLink.href = message.image.url;
+        imageLink.target = '_blank';
+        imageElement.src = message.image.thumbnail_url;
+        imageElement.classList.add('message-image');
+        imageLink.appendChild(imageElement);
+        messageWrapper.appendChild(imageLink);
+    }
     messageWrapper.appendChild(messageElement);
+    for (const attachment of message.attachments || []) {
+        const attachmentLink = document.createElement('a');
+        attachmentLink.href = attachment.url;
+        attachmentLink.textContent = attachment.filename;
+        attachmentLink.classList.add('message-attachment');
+        messageWrapper.appendChild(attachmentLink);
+    }
     if (message.sender_id === sender_id) {
         messageWrapper.classList.add('sent-message');
         messageContainer.classList.add('sent-container');
@@ -127,8 +219,127 @@ function handle_basic_message(message) {
         messageContainer.classList.add('received-container');
     }
     messageContainer.appendChild(messageWrapper);
-    chatContainer.appendChild(messageContainer);
+    return messageContainer;
+}
+function handle_history_message(message) {
+    const direction = history_direction;
+    message.messages.forEach(note_seq);
+    if (direction !== 'latest' && message.room_id !== current_room) {
+        return;
+    }
+    history_direction = 'after';
+    pending_history_request = null;
+    const chatContainer = document.getElementById('chat-container');
+    if (direction === 'before') {
+        const previousHeight = chatContainer.scrollHeight;
+        const firstChild = chatContainer.firstChild;
+        for (const basic_message of message.messages) {
+            chatContainer.insertBefore(build_message_element(basic_message), firstChild);
+        }
+        chatContainer.scrollTop += chatContainer.scrollHeight - previousHeight;
+        older_history_cursor = message.next_cursor;
+        return;
+    }
+    // Also replaces what was shown before a reconnect that could not be resumed
+    if (direction === 'latest') {
+        chatContainer.replaceChildren();
+    }
+    for (const basic_message of message.messages) {
+        chatContainer.appendChild(build_message_element(basic_message));
+    }
     chatContainer.scrollTop = chatContainer.scrollHeight;
+    if (direction === 'latest') {
+        current_room = message.room_id;
+        older_history_cursor = message.next_cursor;
+    }
+    else if (message.next_cursor !== null) {
+        request_history('after', message.next_cursor);
+    }
+}
+// Asks for the page of current_room before or after cursor, one request at a time
+function request_history(direction, cursor) {
+    if (pending_history_request !== null) {
+        return;
+    }
+    pending_history_request = next_client_msg_id();
+    history_direction = direction;
+    const request = {
+        room_id: current_room,
+        client_msg_id: pending_history_request,
+    };
+    request[direction] = cursor;
+    const wrapped_message = {
+        HistoryRequest: request
+    };
+    if (socket.readyState === WebSocket.OPEN) {
+        socket.send(JSON.stringify(wrapped_message));
+    }
+}
+(_c = document.getElementById('chat-container')) === null || _c === void 0 ? void 0 : _c.addEventListener('scroll', (event) => {
+    if (event.target.scrollTop === 0 && older_history_cursor !== null) {
+        request_history('before', older_history_cursor);
+    }
+});
+// The room is switched once the server acknowledges it; its latest page follows
+function change_room(room_id) {
+    const client_msg_id = next_client_msg_id();
+    pending_room_change = { client_msg_id: client_msg_id, room_id: room_id };
+    const wrapped_message = {
+        ChangeRoom: { room_id: room_id, sender_id: sender_id, client_msg_id: client_msg_id }
+    };
+    if (socket.readyState === WebSocket.OPEN) {
+        socket.send(JSON.stringify(wrapped_message));
+    }
+}
+function show_room(room_id) {
+    document.getElementById('chat-container').replaceChildren();
+    current_room = room_id;
+    older_history_cursor = null;
+    pending_history_request = null;
+    history_direction = 'latest';
+}
+function next_client_msg_id() {
+    client_msg_counter += 1;
+    return `${ws_id}-${client_msg_counter}`;
+}
+// A request that failed no longer waits, and its locally drawn message goes away
+function drop_request(client_msg_id) {
+    var _a;
+    if (client_msg_id === null) {
+        return;
+    }
+    if (client_msg_id === pending_history_request) {
+        pending_history_request = null;
+        history_direction = 'after';
+    }
+    if (pending_room_change !== null && client_msg_id === pending_room_change.client_msg_id) {
+        pending_room_change = null;
+    }
+    (_a = document.querySelector(`[data-client-msg-id="${client_msg_id}"]`)) === null || _a === void 0 ? void 0 : _a.remove();
+}
+function handle_error_message(message) {
+    drop_request(message.client_msg_id);
+    display_message(message.message, 'error');
+}
+function handle_rate_limited_message(message) {
+    drop_request(message.client_msg_id);
+    const seconds = Math.ceil(message.retry_after_ms / 1000);
+    display_message(`You are sending too fast, try again in ${seconds}s`, 'error');
+}
+function handle_ack_message(message) {
+    if (message.client_msg_id === null) {
+        return;
+    }
+    if (pending_room_change !== null && message.client_msg_id === pending_room_change.client_msg_id) {
+        show_room(pending_room_change.room_id);
+        pending_room_change = null;
+        return;
+    }
+    const messageContainer = document.querySelector(`[data-client-msg-id="${message.client_msg_id}"]`);
+    if (messageContainer) {
+        messageContainer.setAttribute('data-message-id', message.message_id);
+        messageContainer.removeAttribute('data-client-msg-id');
+    }
 }
 let is_delete_mode = false; // Keeps track of whether delete mode is active
 // Add this after the WebSocket initialization
@@ -215,8 +426,10 @@ function send_message() {
     const textarea = document.querySelector('textarea[name="message_form"]');
     const message_content = textarea.value.trim();
     if (message_content !== '') {
+        const client_msg_id = next_client_msg_id();
         const basic_message = {
             content: message_content,
+            client_msg_id: client_msg_id,
         };
         const wrappedMessage = {
             TSBasic: basic_message
@@ -230,6 +443,7 @@ function send_message() {
         const usernameElement = document.createElement('div');
         const messageElement = document.createElement('div');
         messageContainer.classList.add('message-container', 'sent-container');
+        messageContainer.setAttribute('data-client-msg-id', client_msg_id);
         messageWrapper.classList.add('chat-message', 'sent-message');
         usernameElement.textContent = username + ':';
         usernameElement.classList.add('username');
@@ -338,29 +552,26 @@ function delete_message(message_id) {
 document.querySelector('input[type="file"][name="image"]').addEventListener('change', function (event) {
     const input = event.target;
     if (input.files && input.files.length > 0) {
-        const file = input.files[0];
-        const reader = new FileReader();
-        reader.onload = function (e) {
-            const base64Image = e.target.result;
-            fetch('/upload', {
-                method: 'POST',
-                headers: {
-                    'Content-Type': 'application/json'
-                },
-                body: JSON.stringify({
-                    filename: file.name,
-                    data: base64Image.split(',')[1]
-                })
-            }).then(response => {
-                if (!response.ok) {
-                    throw new Error('Network response was not ok');
-                }
-                return response.json();
-            }).then(data => {
-            }).catch(error => {
-                console.error('There has been a problem with your fetch operation:', error);
-            });
-        };
-        reader.readAsDataURL(file);
+        const form = new FormData();
+        form.append('file', input.files[0]);
+        fetch('/upload?room_id=' + encodeURIComponent(current_room), {
+            method: 'POST',
+            body: form
+        }).then(response => {
+            if (!response.ok) {
+                throw new Error('Network response was not ok');
+            }
+            return response.json();
+        }).then(data => {
+            const wrapped_message = {
+                Image: { attachment_id: data.attachment_id }
+            };
+            if (socket.readyState === WebSocket.OPEN) {
+                socket.send(JSON.stringify(wrapped_message));
+            }
+            input.value = '';
+        }).catch(error => {
+            console.error('There has been a problem with your fetch operation:', error);
+        });
     }
 });
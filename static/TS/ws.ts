@@ -4,6 +4,23 @@ let ws_id = "";
 let username = "";
 let current_room = "";
 let room_map: { [key: string]: string} = {};
+// Where the older messages of current_room continue from, null once all are shown
+let older_history_cursor: string | null = null;
+// How the next History page fits in: 'latest' is the page sent on connecting or changing
+// room, 'before' and 'after' pages were asked for with that cursor. Pages nobody asked
+// for after the first, e.g. replayed after a reconnect, continue forward.
+let history_direction: 'latest' | 'before' | 'after' = 'latest';
+// client_msg_id of the history request in flight
+let pending_history_request: string | null = null;
+// client_msg_id and room of the ChangeRoom request in flight
+let pending_room_change: { client_msg_id: string, room_id: string } | null = null;
+let client_msg_counter = 0;
+// Given on connecting, lets a dropped connection pick up where it left off
+let resume_token: string | null = null;
+// Highest message seq seen in each room
+let last_seqs: { [key: string]: number } = {};
+let reconnect_attempts = 0;
+const MAX_RECONNECT_ATTEMPTS = 5;
 
 let socket: WebSocket;
 
@@ -20,6 +37,11 @@ enum MessageType {
     CreateRoomChange = 'CreateRoomChange',
     Initialization = 'Initialization',
     Deletion = 'Deletion',
+    History = 'History',
+    Error = 'Error',
+    Ack = 'Ack',
+    RateLimited = 'RateLimited',
+    Resumed = 'Resumed',
 }
 
 interface InitMessage {
@@ -27,6 +49,13 @@ interface InitMessage {
     ws_id: string;
     username: string;
     user_map: { [key: string]: string };
+    resume_token: string;
+}
+
+interface ResumedMessage {
+    ws_id: string;
+    room_id: string;
+    resume_token: string;
 }
 
 interface BasicMessage {
@@ -36,15 +65,37 @@ interface BasicMessage {
     room_id: string;
     ws_id: string;
     timestamp: number;
+    seq: number;
+    image?: ImageAttachment;
+    attachments?: AttachmentRef[];
 }
 
 interface TSBasicMessage {
     content: string;
+    attachments?: string[];
+    client_msg_id?: string;
 }
 
 interface ImageMessage {
-    image_url: string;
-    sender_id: string;
+    attachment_id: string;
+    caption?: string;
+}
+
+interface AttachmentRef {
+    attachment_id: string;
+    url: string;
+    filename: string;
+    content_type: string;
+    size: number;
+}
+
+interface ImageAttachment {
+    attachment_id: string;
+    url: string;
+    thumbnail_url: string;
+    content_type: string;
+    width: number;
+    height: number;
 }
 
 interface NotificationMessage {
@@ -90,6 +141,36 @@ interface DeletionMessage {
     message_id: string;
 }
 
+interface HistoryRequestMessage {
+    room_id: string;
+    before?: string;
+    after?: string;
+    client_msg_id?: string;
+}
+
+interface HistoryMessage {
+    room_id: string;
+    messages: BasicMessage[];
+    next_cursor: string | null;
+}
+
+interface ErrorMessage {
+    client_msg_id: string | null;
+    code: string;
+    message: string;
+}
+
+interface AckMessage {
+    client_msg_id: string | null;
+    message_id: string;
+    timestamp: number;
+}
+
+interface RateLimitedMessage {
+    client_msg_id: string | null;
+    retry_after_ms: number;
+}
+
 type UserMessage =
     | { Basic: BasicMessage }
     | { TSBasic: TSBasicMessage }
@@ -103,23 +184,24 @@ type UserMessage =
     | { UsernameChange: UsernameChangeMessage }
     | { CreateRoomChange: CreateRoomChangeMessage }
     | { Initialization: InitMessage }
-    | { Deletion: DeletionMessage };
+    | { Deletion: DeletionMessage }
+    | { HistoryRequest: HistoryRequestMessage }
+    | { History: HistoryMessage }
+    | { Error: ErrorMessage }
+    | { Ack: AckMessage }
+    | { RateLimited: RateLimitedMessage }
+    | { Resumed: ResumedMessage };
 
 
-fetch('/get-ip')
-    .then(response => response.json())
-    .then(data => {
-        const server_ip = data.ip;
-        console.log('Server IP:', server_ip);
-        initializeWebSocket(server_ip);
-    })
-    .catch(error => console.error('Error fetching server IP:', error));
+initializeWebSocket();
 
-function initializeWebSocket(server_ip: string): void {
-    socket = new WebSocket(`ws://${server_ip}:8080/ws/`);
+// Connects back to the host that served the page, over TLS when the page was
+function initializeWebSocket(query: string = ''): void {
+    const protocol = location.protocol === 'https:' ? 'wss:' : 'ws:';
+    socket = new WebSocket(`${protocol}//${location.host}/ws/${query}`);
     socket.onclose = (event: CloseEvent): void => {
         if (!event.wasClean) {
-            window.location.href = '/login';
+            reconnect();
         }
     };
 
@@ -162,21 +244,71 @@ function initializeWebSocket(server_ip: string): void {
             case 'CreateRoomChange' in data:
                 handle_create_room_message(data.CreateRoomChange);
                 break;
+            case 'History' in data:
+                handle_history_message(data.History);
+                break;
+            case 'Error' in data:
+                handle_error_message(data.Error);
+                break;
+            case 'Ack' in data:
+                handle_ack_message(data.Ack);
+                break;
+            case 'RateLimited' in data:
+                handle_rate_limited_message(data.RateLimited);
+                break;
+            case 'Resumed' in data:
+                handle_resumed_message(data.Resumed);
+                break;
             default:
                 console.error("Unknown message type received");
         }
     };
 }
 
+// Resumes with the last token, backing off a little more each attempt, and goes
+// to the login page once resuming keeps failing
+function reconnect(): void {
+    if (resume_token === null || reconnect_attempts >= MAX_RECONNECT_ATTEMPTS) {
+        window.location.href = '/login';
+        return;
+    }
+    reconnect_attempts += 1;
+    pending_history_request = null;
+    pending_room_change = null;
+    history_direction = 'latest';
+    const seqs = Object.entries(last_seqs).map(([room_id, seq]) => `${room_id}:${seq}`).join(',');
+    const query = `?resume_token=${encodeURIComponent(resume_token)}&last_seqs=${encodeURIComponent(seqs)}`;
+    setTimeout(() => initializeWebSocket(query), reconnect_attempts * 1000);
+}
+
 function handle_initialization(init_message: InitMessage) {
     sender_id = init_message.user_id;
     ws_id = init_message.ws_id;
     username = init_message.username;
     user_map = init_message.user_map;
+    resume_token = init_message.resume_token;
+    reconnect_attempts = 0;
+}
+
+// The missed messages follow; if the server put us back in another room, load it instead
+function handle_resumed_message(message: ResumedMessage) {
+    ws_id = message.ws_id;
+    resume_token = message.resume_token;
+    reconnect_attempts = 0;
+    history_direction = 'after';
+    if (message.room_id !== current_room) {
+        change_room(message.room_id);
+    }
+}
+
+function note_seq(message: BasicMessage) {
+    last_seqs[message.room_id] = Math.max(last_seqs[message.room_id] || 0, message.seq);
 }
 
 function handle_basic_message(message: BasicMessage) {
-    if (message.ws_id === ws_id) {
+    note_seq(message);
+    // Image messages are not drawn locally when sent, so let them through
+    if (message.ws_id === ws_id && !message.image) {
         const chatContainer = document.getElementById('chat-container')!;
         const sentContainers = chatContainer.getElementsByClassName('sent-container');
         if (sentContainers.length > 0) {
@@ -185,8 +317,13 @@ function handle_basic_message(message: BasicMessage) {
         }
         return;
     }
-    
+
     const chatContainer = document.getElementById('chat-container')!;
+    chatContainer.appendChild(build_message_element(message));
+    chatContainer.scrollTop = chatContainer.scrollHeight;
+}
+
+function build_message_element(message: BasicMessage): HTMLElement {
     const messageContainer = document.createElement('div');
     const messageWrapper = document.createElement('div');
     const usernameElement = document.createElement('div');
@@ -202,7 +339,24 @@ function handle_basic_message(message: BasicMessage) {
     messageElement.textContent = message.content;
     
     messageWrapper.appendChild(usernameElement);
+    if (message.image) {
+        const imageLink = document.createElement('a');
+        const imageElement = document.createElement('img');
+        imageLink.href = message.image.url;
+        imageLink.target = '_blank';
+        imageElement.src = message.image.thumbnail_url;
+        imageElement.classList.add('message-image');
+        imageLink.appendChild(imageElement);
+        messageWrapper.appendChild(imageLink);
+    }
     messageWrapper.appendChild(messageElement);
+    for (const attachment of message.attachments || []) {
+        const attachmentLink = document.createElement('a');
+        attachmentLink.href = attachment.url;
+        attachmentLink.textContent = attachment.filename;
+        attachmentLink.classList.add('message-attachment');
+        messageWrapper.appendChild(attachmentLink);
+    }
 
     if (message.sender_id === sender_id) {
         messageWrapper.classList.add('sent-message');
@@ -220,11 +374,136 @@ function handle_basic_message(message: BasicMessage) {
     }
 
     messageContainer.appendChild(messageWrapper);
-    chatContainer.appendChild(messageContainer);
+    return messageContainer;
+}
+
+function handle_history_message(message: HistoryMessage) {
+    const direction = history_direction;
+    message.messages.forEach(note_seq);
+    if (direction !== 'latest' && message.room_id !== current_room) {
+        return;
+    }
+    history_direction = 'after';
+    pending_history_request = null;
+    const chatContainer = document.getElementById('chat-container')!;
+    if (direction === 'before') {
+        const previousHeight = chatContainer.scrollHeight;
+        const firstChild = chatContainer.firstChild;
+        for (const basic_message of message.messages) {
+            chatContainer.insertBefore(build_message_element(basic_message), firstChild);
+        }
+        chatContainer.scrollTop += chatContainer.scrollHeight - previousHeight;
+        older_history_cursor = message.next_cursor;
+        return;
+    }
+    // Also replaces what was shown before a reconnect that could not be resumed
+    if (direction === 'latest') {
+        chatContainer.replaceChildren();
+    }
+    for (const basic_message of message.messages) {
+        chatContainer.appendChild(build_message_element(basic_message));
+    }
     chatContainer.scrollTop = chatContainer.scrollHeight;
+    if (direction === 'latest') {
+        current_room = message.room_id;
+        older_history_cursor = message.next_cursor;
+    } else if (message.next_cursor !== null) {
+        request_history('after', message.next_cursor);
+    }
+}
+
+// Asks for the page of current_room before or after cursor, one request at a time
+function request_history(direction: 'before' | 'after', cursor: string) {
+    if (pending_history_request !== null) {
+        return;
+    }
+    pending_history_request = next_client_msg_id();
+    history_direction = direction;
+    const request: HistoryRequestMessage = {
+        room_id: current_room,
+        client_msg_id: pending_history_request,
+    };
+    request[direction] = cursor;
+    const wrapped_message: UserMessage = {
+        HistoryRequest: request
+    };
+    if (socket.readyState === WebSocket.OPEN) {
+        socket.send(JSON.stringify(wrapped_message));
+    }
 }
 
+document.getElementById('chat-container')?.addEventListener('scroll', (event: Event) => {
+    if ((event.target as HTMLElement).scrollTop === 0 && older_history_cursor !== null) {
+        request_history('before', older_history_cursor);
+    }
+});
 
+// The room is switched once the server acknowledges it; its latest page follows
+function change_room(room_id: string): void {
+    const client_msg_id = next_client_msg_id();
+    pending_room_change = { client_msg_id: client_msg_id, room_id: room_id };
+    const wrapped_message = {
+        ChangeRoom: { room_id: room_id, sender_id: sender_id, client_msg_id: client_msg_id }
+    };
+    if (socket.readyState === WebSocket.OPEN) {
+        socket.send(JSON.stringify(wrapped_message));
+    }
+}
+
+function show_room(room_id: string): void {
+    document.getElementById('chat-container')!.replaceChildren();
+    current_room = room_id;
+    older_history_cursor = null;
+    pending_history_request = null;
+    history_direction = 'latest';
+}
+
+function next_client_msg_id(): string {
+    client_msg_counter += 1;
+    return `${ws_id}-${client_msg_counter}`;
+}
+
+// A request that failed no longer waits, and its locally drawn message goes away
+function drop_request(client_msg_id: string | null) {
+    if (client_msg_id === null) {
+        return;
+    }
+    if (client_msg_id === pending_history_request) {
+        pending_history_request = null;
+        history_direction = 'after';
+    }
+    if (pending_room_change !== null && client_msg_id === pending_room_change.client_msg_id) {
+        pending_room_change = null;
+    }
+    document.querySelector(`[data-client-msg-id="${client_msg_id}"]`)?.remove();
+}
+
+function handle_error_message(message: ErrorMessage) {
+    drop_request(message.client_msg_id);
+    display_message(message.message, 'error');
+}
+
+function handle_rate_limited_message(message: RateLimitedMessage) {
+    drop_request(message.client_msg_id);
+    const seconds = Math.ceil(message.retry_after_ms / 1000);
+    display_message(`You are sending too fast, try again in ${seconds}s`, 'error');
+}
+
+function handle_ack_message(message: AckMessage) {
+    if (message.client_msg_id === null) {
+        return;
+    }
+    if (pending_room_change !== null && message.client_msg_id === pending_room_change.client_msg_id) {
+        show_room(pending_room_change.room_id);
+        pending_room_change = null;
+        return;
+    }
+    const messageContainer = document.querySelector(`[data-client-msg-id="${message.client_msg_id}"]`);
+    if (messageContainer) {
+        messageContainer.setAttribute('data-message-id', message.message_id);
+        messageContainer.removeAttribute('data-client-msg-id');
+    }
+}
 
 let is_delete_mode = false; // Keeps track of whether delete mode is active
 
@@ -324,8 +603,10 @@ function send_message(): void {
     const textarea: HTMLTextAreaElement = document.querySelector('textarea[name="message_form"]')!;
     const message_content: string = textarea.value.trim();
     if (message_content !== '') {
+        const client_msg_id = next_client_msg_id();
         const basic_message: TSBasicMessage = {
             content: message_content,
+            client_msg_id: client_msg_id,
         };
     
         const wrappedMessage: UserMessage = {
@@ -342,6 +623,7 @@ function send_message(): void {
         const messageElement = document.createElement('div');
 
         messageContainer.classList.add('message-container', 'sent-container');
+        messageContainer.setAttribute('data-client-msg-id', client_msg_id);
         messageWrapper.classList.add('chat-message', 'sent-message');
         
         usernameElement.textContent = username + ':';
@@ -468,30 +750,26 @@ function delete_message(message_id: string): void {
 document.querySelector('input[type="file"][name="image"]')!.addEventListener('change', function(event: Event) {
     const input = event.target as HTMLInputElement;
     if (input.files && input.files.length > 0) {
-        const file = input.files[0];
-        const reader = new FileReader();
-        reader.onload = function(e) {
-            const base64Image = (e.target!.result as string);
-            fetch('/upload', {
-                method: 'POST',
-                headers: {
-                    'Content-Type': 'application/json'
-                },
-                body: JSON.stringify({
-                    filename: file.name,
-                    data: base64Image.split(',')[1]
-                })
-            }).then(response => {
-                if (!response.ok) {
-                    throw new Error('Network response was not ok');
-                }
-                return response.json();
-            }).then(data => {
-
-            }).catch(error => {
-                console.error('There has been a problem with your fetch operation:', error);
-            });
-        };
-        reader.readAsDataURL(file);
+        const form = new FormData();
+        form.append('file', input.files[0]);
+        fetch('/upload?room_id=' + encodeURIComponent(current_room), {
+            method: 'POST',
+            body: form
+        }).then(response => {
+            if (!response.ok) {
+                throw new Error('Network response was not ok');
+            }
+            return response.json();
+        }).then(data => {
+            const wrapped_message = {
+                Image: { attachment_id: data.attachment_id } as ImageMessage
+            };
+            if (socket.readyState === WebSocket.OPEN) {
+                socket.send(JSON.stringify(wrapped_message));
+            }
+            input.value = '';
+        }).catch(error => {
+            console.error('There has been a problem with your fetch operation:', error);
+        });
     }
-});
\ No newline at end of file
+});